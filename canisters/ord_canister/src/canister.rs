@@ -8,10 +8,12 @@ use serde::Deserialize;
 use std::ops::Deref;
 use std::str::FromStr;
 
-#[query]
-pub fn get_runes_by_utxo(txid: String, vout: u32) -> Result<Vec<RuneBalance>, OrdError> {
+// the most outpoints a single `get_runes_by_utxos` query looks up
+const MAX_OUTPOINTS_PER_QUERY: usize = 500;
+
+fn runes_of(txid: &str, vout: u32) -> Result<Vec<RuneBalance>, OrdError> {
   let k = OutPoint::store(OutPoint {
-    txid: Txid::from_str(&txid).map_err(|e| OrdError::Params(e.to_string()))?,
+    txid: Txid::from_str(txid).map_err(|e| OrdError::Params(e.to_string()))?,
     vout,
   });
   let v = crate::outpoint_to_rune_balances(|b| {
@@ -22,12 +24,42 @@ pub fn get_runes_by_utxo(txid: String, vout: u32) -> Result<Vec<RuneBalance>, Or
   Ok(v)
 }
 
+#[query]
+pub fn get_runes_by_utxo(txid: String, vout: u32) -> Result<Vec<RuneBalance>, OrdError> {
+  runes_of(&txid, vout)
+}
+
+// the runes of every outpoint, in the order they were given
+#[query]
+pub fn get_runes_by_utxos(
+  outpoints: Vec<(String, u32)>,
+) -> Result<Vec<Vec<RuneBalance>>, OrdError> {
+  if outpoints.len() > MAX_OUTPOINTS_PER_QUERY {
+    return Err(OrdError::Params(format!(
+      "at most {} outpoints per query",
+      MAX_OUTPOINTS_PER_QUERY
+    )));
+  }
+  outpoints
+    .iter()
+    .map(|(txid, vout)| runes_of(txid, *vout))
+    .collect()
+}
+
 #[query]
 pub fn get_height() -> Result<(u32, String), OrdError> {
   let (height, hash) = crate::highest_block();
   Ok((height, hash.to_string()))
 }
 
+// the serialized transaction of `txid`, fetched from the bitcoin node. callers
+// check it against the txid, the node isn't trusted for it.
+#[update]
+pub async fn get_raw_transaction(txid: String) -> Result<Vec<u8>, OrdError> {
+  let txid = Txid::from_str(&txid).map_err(|e| OrdError::Params(e.to_string()))?;
+  crate::rpc::get_raw_tx(&crate::get_url(), txid).await
+}
+
 #[query(hidden = true)]
 pub fn rpc_transform(args: TransformArgs) -> HttpResponse {
   let headers = args
@@ -290,3 +290,23 @@ pub(crate) async fn get_block(url: &str, hash: BlockHash) -> Result<Block> {
     ))
   })
 }
+
+// the serialized transaction, the node needs `txindex` for transactions
+// outside its mempool
+pub(crate) async fn get_raw_tx(url: &str, txid: Txid) -> Result<Vec<u8>> {
+  let hex: String = make_rpc(
+    url,
+    "getrawtransaction",
+    serde_json::json!([format!("{:x}", txid), false]),
+    MAX_RESPONSE_BYTES,
+  )
+  .await?;
+  use hex::FromHex;
+  <Vec<u8>>::from_hex(hex).map_err(|e| {
+    OrdError::Rpc(RpcError::Decode(
+      "getrawtransaction".to_string(),
+      url.to_string(),
+      e.to_string(),
+    ))
+  })
+}
@@ -2,12 +2,14 @@ pub mod btc;
 
 pub mod ic;
 
-use btc::account_to_p2pkh_address;
+use btc::{account_to_p2pkh_address, account_to_p2tr_address, account_to_p2wpkh_address};
 use candid::{CandidType, Principal};
 use ic_ledger_types::{AccountIdentifier, Subaccount};
 use icrc_ledger_types::icrc1::account::Account;
 use tiny_keccak::{Hasher, Sha3};
 
+use crate::state::read_user_manager;
+
 #[derive(CandidType)]
 pub struct Addresses {
     pub icrc1: Account,
@@ -15,6 +17,8 @@ pub struct Addresses {
     pub account_identifier: AccountIdentifier,
     pub account_identifier_string: String,
     pub bitcoin: String,
+    pub bitcoin_segwit: String,
+    pub bitcoin_taproot: String,
 }
 
 pub fn principal_to_subaccount(principal: &Principal) -> [u8; 32] {
@@ -35,6 +39,22 @@ pub fn generate_subaccount_for_pool(pool_id: u128, created_at: u64) -> [u8; 32]
     hash
 }
 
+// the account whose derived key controls `addr`, if it's one of the
+// canister's registered bitcoin addresses
+pub fn account_of_address(addr: &str) -> Option<Account> {
+    read_user_manager(|users| users.subaccount_of(addr)).map(|subaccount| Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(subaccount),
+    })
+}
+
+impl Addresses {
+    // every bitcoin address derived for the account, regardless of script type
+    pub fn bitcoin_addresses(&self) -> [&str; 3] {
+        [&self.bitcoin, &self.bitcoin_segwit, &self.bitcoin_taproot]
+    }
+}
+
 impl From<&Principal> for Addresses {
     fn from(principal: &Principal) -> Self {
         let subaccount = principal_to_subaccount(principal);
@@ -51,12 +71,16 @@ impl From<[u8; 32]> for Addresses {
             subaccount: Some(subaccount),
         };
         let bitcoin = account_to_p2pkh_address(&account);
+        let bitcoin_segwit = account_to_p2wpkh_address(&account);
+        let bitcoin_taproot = account_to_p2tr_address(&account);
         Self {
             icrc1_string: account.to_string(),
             icrc1: account,
             account_identifier_string: account_identifier.to_string(),
             account_identifier,
             bitcoin,
+            bitcoin_segwit,
+            bitcoin_taproot,
         }
     }
 }
@@ -1,32 +1,47 @@
 #![allow(clippy::type_complexity)]
 #![allow(clippy::too_many_arguments)]
 
+pub mod coin_selection;
+pub mod psbt;
 pub mod runestone;
 pub mod signer;
 pub mod transaction;
 pub mod utils;
 
-use bitcoin::{address::NetworkUnchecked, Address};
+use bitcoin::{
+    address::NetworkUnchecked, key::TweakedPublicKey, Address, AddressType, CompressedPublicKey,
+    XOnlyPublicKey,
+};
 use icrc_ledger_types::icrc1::account::Account;
 
 use bitcoin::Network;
-use ic_cdk::api::management_canister::bitcoin::{
-    bitcoin_get_current_fee_percentiles, BitcoinNetwork as IcBitcoinNetwork,
-    GetCurrentFeePercentilesRequest,
+use ic_cdk::api::management_canister::{
+    bitcoin::{
+        bitcoin_get_current_fee_percentiles, BitcoinNetwork as IcBitcoinNetwork,
+        GetCurrentFeePercentilesRequest,
+    },
+    ecdsa::EcdsaPublicKeyResponse,
 };
 use utils::{account_to_derivation_path, derive_public_key, ripemd160, sha256};
 
-use crate::state::read_config;
+use crate::{
+    state::{config::FeePurpose, read_config},
+    types::{FeePreference, ScriptType},
+};
 
 pub const DUST_THRESHOLD: u64 = 1_000;
 
+pub fn to_bitcoin_network(network: IcBitcoinNetwork) -> Network {
+    match network {
+        IcBitcoinNetwork::Mainnet => Network::Bitcoin,
+        IcBitcoinNetwork::Testnet => Network::Testnet,
+        IcBitcoinNetwork::Regtest => Network::Regtest,
+    }
+}
+
 pub fn address_validation(addr: &str) -> Result<Address, String> {
     read_config(|config| {
-        let bitcoin_network = match config.bitcoin_network() {
-            IcBitcoinNetwork::Mainnet => Network::Bitcoin,
-            IcBitcoinNetwork::Testnet => Network::Testnet,
-            IcBitcoinNetwork::Regtest => Network::Regtest,
-        };
+        let bitcoin_network = to_bitcoin_network(config.bitcoin_network());
         let parsed_addr: Address<NetworkUnchecked> = match addr.parse() {
             Err(_e) => return Err(String::from("failed to parse into bitcoin address")),
             Ok(addr) => addr,
@@ -63,8 +78,68 @@ pub fn account_to_p2pkh_address(account: &Account) -> String {
     })
 }
 
-pub async fn get_fee_per_vbyte() -> u64 {
-    let network = read_config(|config| config.bitcoin_network());
+// same key as the p2pkh address, committed to as a v0 witness program
+pub fn account_to_p2wpkh_address(account: &Account) -> String {
+    read_config(|config| {
+        let network = to_bitcoin_network(config.bitcoin_network());
+        let ecdsa_public_key = config.ecdsa_public_key();
+        let path = account_to_derivation_path(account);
+        let derived_public_key = derive_public_key(&ecdsa_public_key, &path).public_key;
+        let pubkey = CompressedPublicKey::from_slice(&derived_public_key)
+            .expect("derived key should be compressed");
+        Address::p2wpkh(&pubkey, network).to_string()
+    })
+}
+
+// key path only p2tr address of the account's schnorr key. `sign_with_schnorr`
+// signs with the derived key as is, so it's committed to without the bip-86
+// tweak, which leaves no script path to spend from.
+pub fn account_to_p2tr_address(account: &Account) -> String {
+    read_config(|config| {
+        let network = to_bitcoin_network(config.bitcoin_network());
+        let schnorr_public_key = config.schnorr_public_key();
+        // bip-340 keys derive the same way ecdsa keys do
+        let master_key = EcdsaPublicKeyResponse {
+            public_key: schnorr_public_key.public_key,
+            chain_code: schnorr_public_key.chain_code,
+        };
+        let path = account_to_derivation_path(account);
+        let derived_public_key = derive_public_key(&master_key, &path).public_key;
+        let xonly = XOnlyPublicKey::from_slice(&derived_public_key[1..])
+            .expect("derived key should be on the curve");
+        Address::p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(xonly), network)
+            .to_string()
+    })
+}
+
+// the script type of `address`, if it's one the canister can sign for
+pub fn script_type(address: &Address) -> Option<ScriptType> {
+    match address.address_type()? {
+        AddressType::P2pkh => Some(ScriptType::P2pkh),
+        AddressType::P2wpkh => Some(ScriptType::P2wpkh),
+        AddressType::P2tr => Some(ScriptType::P2tr),
+        _ => None,
+    }
+}
+
+const ECONOMY_PERCENTILE: u8 = 25;
+const PRIORITY_PERCENTILE: u8 = 90;
+
+// picks the fee rate for a transaction sent for `purpose`, following the
+// caller's preference if any. whatever comes out stays within the bounds of
+// the fee policy.
+pub async fn get_fee_per_vbyte(purpose: FeePurpose, preference: Option<FeePreference>) -> u64 {
+    let (network, policy) = read_config(|config| (config.bitcoin_network(), config.fee_policy()));
+    let percentile = match preference {
+        Some(FeePreference::Explicit { sats_per_vbyte }) => {
+            return sats_per_vbyte
+                .saturating_mul(1000)
+                .clamp(policy.min_fee_per_vbytes, policy.max_fee_per_vbytes)
+        }
+        Some(FeePreference::Economy) => ECONOMY_PERCENTILE,
+        Some(FeePreference::Priority) => PRIORITY_PERCENTILE,
+        Some(FeePreference::Normal) | None => policy.percentile(purpose),
+    };
     // Get fee percentiles from previous transactions to estimate our own fee.
     let fee_percentiles =
         bitcoin_get_current_fee_percentiles(GetCurrentFeePercentilesRequest { network })
@@ -72,13 +147,12 @@ pub async fn get_fee_per_vbyte() -> u64 {
             .unwrap()
             .0;
 
-    if fee_percentiles.is_empty() {
-        // There are no fee percentiles. This case can only happen on a regtest
-        // network where there are no non-coinbase transactions. In this case,
-        // we use a default of 2000 millisatoshis/byte (i.e. 2 satoshi/byte)
-        2000
-    } else {
-        // Choose the 50th percentile for sending fees.
-        fee_percentiles[50]
-    }
+    // There are no fee percentiles on a regtest network without non-coinbase
+    // transactions, the policy's minimum is used then.
+    let fee_per_vbyte = fee_percentiles
+        .get(percentile as usize)
+        .or(fee_percentiles.last())
+        .copied()
+        .unwrap_or(policy.min_fee_per_vbytes);
+    fee_per_vbyte.clamp(policy.min_fee_per_vbytes, policy.max_fee_per_vbytes)
 }
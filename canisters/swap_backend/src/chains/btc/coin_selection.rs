@@ -0,0 +1,263 @@
+// picks the utxos funding a transaction. strategies work on plain amounts,
+// sats for bitcoin utxos and rune balances for runic ones, and return the
+// indexes of the selected amounts.
+
+// millisats per vbyte, same unit as the fee percentiles
+const MODERATE_FEE_RATE: u64 = 5_000;
+const HIGH_FEE_RATE: u64 = 20_000;
+const MODERATE_FEE_MAX_INPUTS: usize = 4;
+
+// branch-and-bound gives up after exploring this many branches
+const BNB_MAX_TRIES: usize = 100_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    // looks for a subset landing within the tolerance above the target so
+    // that no change output is needed, otherwise spends the smallest utxos
+    // first while it's cheap to do so
+    BranchAndBound,
+    // fewest inputs possible
+    LargestFirst,
+    // covers the target with at most that many inputs, preferring the
+    // smaller utxos which still keep the target reachable
+    MaxInputs(usize),
+}
+
+impl Strategy {
+    pub fn for_fee_rate(fee_per_vbytes: u64) -> Self {
+        if fee_per_vbytes >= HIGH_FEE_RATE {
+            Strategy::LargestFirst
+        } else if fee_per_vbytes >= MODERATE_FEE_RATE {
+            Strategy::MaxInputs(MODERATE_FEE_MAX_INPUTS)
+        } else {
+            Strategy::BranchAndBound
+        }
+    }
+}
+
+// returns the indexes into `amounts` to spend for `target`, none if all of
+// them together don't cover it. `tolerance` is the excess which is fine to
+// give away instead of creating change.
+pub fn select(
+    amounts: &[u128],
+    target: u128,
+    tolerance: u128,
+    strategy: Strategy,
+) -> Option<Vec<usize>> {
+    if amounts.iter().sum::<u128>() < target {
+        return None;
+    }
+    // largest first, ties broken by position to stay deterministic
+    let mut order = (0..amounts.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&index| (std::cmp::Reverse(amounts[index]), index));
+    let sorted = order
+        .iter()
+        .map(|&index| amounts[index])
+        .collect::<Vec<_>>();
+
+    let selected = match strategy {
+        Strategy::BranchAndBound => branch_and_bound(&sorted, target, tolerance)
+            .unwrap_or_else(|| smallest_first(&sorted, target)),
+        Strategy::LargestFirst => largest_first(&sorted, target),
+        Strategy::MaxInputs(max_inputs) => max_inputs_first(&sorted, target, max_inputs)
+            .unwrap_or_else(|| largest_first(&sorted, target)),
+    };
+    Some(
+        selected
+            .into_iter()
+            .map(|position| order[position])
+            .collect(),
+    )
+}
+
+fn largest_first(sorted: &[u128], target: u128) -> Vec<usize> {
+    let (mut selected, mut total) = (vec![], 0);
+    for (position, amount) in sorted.iter().enumerate() {
+        if total >= target && !selected.is_empty() {
+            break;
+        }
+        selected.push(position);
+        total += amount;
+    }
+    selected
+}
+
+fn smallest_first(sorted: &[u128], target: u128) -> Vec<usize> {
+    let (mut selected, mut total) = (vec![], 0);
+    for (position, amount) in sorted.iter().enumerate().rev() {
+        if total >= target && !selected.is_empty() {
+            break;
+        }
+        selected.push(position);
+        total += amount;
+    }
+    selected
+}
+
+// fills every slot with the smallest amount that, together with the largest
+// ones left for the remaining slots, still covers what's left of the target
+fn max_inputs_first(sorted: &[u128], target: u128, max_inputs: usize) -> Option<Vec<usize>> {
+    if max_inputs == 0 || sorted.iter().take(max_inputs).sum::<u128>() < target {
+        return None;
+    }
+    let mut pool = (0..sorted.len()).collect::<Vec<_>>();
+    let (mut selected, mut left) = (vec![], target);
+    for slot in 0..max_inputs {
+        let others = max_inputs - slot - 1;
+        let top = pool
+            .iter()
+            .take(others + 1)
+            .map(|&p| sorted[p])
+            .sum::<u128>();
+        let top_but_last = top - pool.get(others).map_or(0, |&p| sorted[p]);
+        let picked = (0..pool.len()).rev().find(|&index| {
+            let amount = sorted[pool[index]];
+            let reach = if index < others {
+                top - amount
+            } else {
+                top_but_last
+            };
+            amount + reach >= left
+        })?;
+        let position = pool.remove(picked);
+        selected.push(position);
+        if sorted[position] >= left {
+            return Some(selected);
+        }
+        left -= sorted[position];
+    }
+    None
+}
+
+struct Search<'a> {
+    sorted: &'a [u128],
+    target: u128,
+    upper: u128,
+    tries: usize,
+    selection: Vec<usize>,
+    best: Option<(u128, Vec<usize>)>,
+}
+
+impl Search<'_> {
+    // depth first over include/exclude of every amount, largest first so
+    // that overshooting branches get cut early
+    fn run(&mut self, position: usize, current: u128, remaining: u128) {
+        if self.tries == 0 || matches!(self.best, Some((0, _))) {
+            return;
+        }
+        self.tries -= 1;
+        if current > self.upper || current + remaining < self.target {
+            return;
+        }
+        if current >= self.target {
+            let waste = current - self.target;
+            if !matches!(self.best, Some((best, _)) if best <= waste) {
+                self.best = Some((waste, self.selection.clone()));
+            }
+            return;
+        }
+        if position == self.sorted.len() {
+            return;
+        }
+        let amount = self.sorted[position];
+        self.selection.push(position);
+        self.run(position + 1, current + amount, remaining - amount);
+        self.selection.pop();
+        self.run(position + 1, current, remaining - amount);
+    }
+}
+
+fn branch_and_bound(sorted: &[u128], target: u128, tolerance: u128) -> Option<Vec<usize>> {
+    let mut search = Search {
+        sorted,
+        target,
+        upper: target + tolerance,
+        tries: BNB_MAX_TRIES,
+        selection: vec![],
+        best: None,
+    };
+    search.run(0, 0, sorted.iter().sum());
+    search
+        .best
+        .map(|(_, selection)| selection)
+        .filter(|selection| !selection.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(mut indexes: Vec<usize>) -> Vec<usize> {
+        indexes.sort();
+        indexes
+    }
+
+    #[test]
+    fn not_enough() {
+        assert_eq!(select(&[1, 2, 3], 7, 0, Strategy::LargestFirst), None);
+        assert_eq!(select(&[], 1, 0, Strategy::BranchAndBound), None);
+    }
+
+    #[test]
+    fn branch_and_bound_exact_match() {
+        let selected = select(&[5, 3, 7, 2], 10, 0, Strategy::BranchAndBound).unwrap();
+        assert_eq!(sorted(selected), vec![1, 2]);
+    }
+
+    #[test]
+    fn branch_and_bound_within_tolerance() {
+        // 9 + 12 overshoots by 1, spending the smallest first would take 3 too
+        let selected = select(&[3, 9, 12, 40], 20, 1, Strategy::BranchAndBound).unwrap();
+        assert_eq!(sorted(selected), vec![1, 2]);
+    }
+
+    #[test]
+    fn branch_and_bound_falls_back_to_smallest_first() {
+        let selected = select(&[10, 20, 40], 25, 0, Strategy::BranchAndBound).unwrap();
+        assert_eq!(sorted(selected), vec![0, 1]);
+    }
+
+    #[test]
+    fn largest_first() {
+        let selected = select(&[1, 8, 4, 6], 10, 0, Strategy::LargestFirst).unwrap();
+        assert_eq!(sorted(selected), vec![1, 3]);
+    }
+
+    #[test]
+    fn largest_first_always_spends_something() {
+        let selected = select(&[1, 8, 4, 6], 0, 0, Strategy::LargestFirst).unwrap();
+        assert_eq!(selected, vec![1]);
+    }
+
+    #[test]
+    fn max_inputs_prefers_smaller_amounts() {
+        // 8 alone doesn't cover 10, the smallest pair that does is 4 + 6
+        let selected = select(&[1, 8, 4, 6], 10, 0, Strategy::MaxInputs(2)).unwrap();
+        assert_eq!(sorted(selected), vec![2, 3]);
+    }
+
+    #[test]
+    fn max_inputs_stays_within_the_limit() {
+        let selected = select(&[1, 1, 1, 1, 1, 5], 6, 0, Strategy::MaxInputs(2)).unwrap();
+        assert_eq!(sorted(selected), vec![4, 5]);
+    }
+
+    #[test]
+    fn max_inputs_falls_back_to_largest_first() {
+        let selected = select(&[1, 8, 4, 6], 10, 0, Strategy::MaxInputs(1)).unwrap();
+        assert_eq!(sorted(selected), vec![1, 3]);
+    }
+
+    #[test]
+    fn strategy_for_fee_rate() {
+        assert_eq!(Strategy::for_fee_rate(1_000), Strategy::BranchAndBound);
+        assert_eq!(
+            Strategy::for_fee_rate(MODERATE_FEE_RATE),
+            Strategy::MaxInputs(MODERATE_FEE_MAX_INPUTS)
+        );
+        assert_eq!(
+            Strategy::for_fee_rate(HIGH_FEE_RATE),
+            Strategy::LargestFirst
+        );
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+
+use bitcoin::{
+    hashes::Hash,
+    psbt::{raw::ProprietaryKey, Input, Psbt},
+    Address, Amount, OutPoint, Transaction, TxOut, Txid,
+};
+use candid::CandidType;
+use ordinals::Runestone;
+use serde_bytes::ByteBuf;
+
+use crate::{
+    chains::account_of_address,
+    ord_canister,
+    state::{read_config, read_txn_manager, txn_manager::SpentInput},
+    txn_handler::TransactionType,
+    types::ScriptType,
+};
+
+use super::{
+    address_validation, script_type, signer::mock_signature, utils::account_to_derivation_path,
+};
+
+// proprietary psbt keys carrying what the canister signs each input with.
+// threshold keys aren't bip-32 keys, so the standard derivation fields
+// can't express them.
+const PROPRIETARY_PREFIX: &[u8] = b"swap_backend";
+const DERIVATION_PATH_SUBTYPE: u8 = 0;
+const KEY_NAME_SUBTYPE: u8 = 1;
+
+#[derive(CandidType)]
+pub struct PsbtExport {
+    // bip-174 serialized
+    pub psbt: ByteBuf,
+    pub fee: u64,
+    // of the transaction once signed
+    pub vsize: u64,
+    // json of the deciphered runestone, if the transaction carries one
+    pub runestone: Option<String>,
+}
+
+fn proprietary(subtype: u8) -> ProprietaryKey {
+    ProprietaryKey {
+        prefix: PROPRIETARY_PREFIX.to_vec(),
+        subtype,
+        key: vec![],
+    }
+}
+
+fn spent_txid(spent: &SpentInput) -> Txid {
+    Txid::from_raw_hash(Hash::from_slice(&spent.utxo.outpoint.txid).unwrap())
+}
+
+// the transactions the legacy inputs spend, which their psbt inputs carry
+// whole. the canister's own transactions are still in its submitted map,
+// others are fetched through the ord canister. either way they have to hash
+// to the txid spent.
+async fn previous_transactions(
+    inputs: &[SpentInput],
+) -> Result<HashMap<Txid, Transaction>, String> {
+    let mut previous = HashMap::new();
+    for spent in inputs {
+        let address = address_validation(&spent.address)?;
+        let txid = spent_txid(spent);
+        if script_type(&address) != Some(ScriptType::P2pkh) || previous.contains_key(&txid) {
+            continue;
+        }
+        let kept = read_txn_manager(|txns| txns.submitted.get(&txid.to_string()))
+            .map(|submitted| submitted.raw);
+        let raw = match kept {
+            Some(raw) => raw,
+            None => match ord_canister::get_raw_transaction(&txid.to_string()).await {
+                Ok((Ok(raw),)) => raw,
+                Ok((Err(err),)) => return Err(format!("failed to fetch {}: {:?}", txid, err)),
+                Err((_, err)) => return Err(format!("failed to fetch {}: {}", txid, err)),
+            },
+        };
+        let txn: Transaction = bitcoin::consensus::deserialize(&raw)
+            .map_err(|err| format!("failed to decode {}: {}", txid, err))?;
+        if txn.compute_txid() != txid {
+            return Err(format!("fetched transaction isn't {}", txid));
+        }
+        previous.insert(txid, txn);
+    }
+    Ok(previous)
+}
+
+// segwit inputs get their witness utxo, legacy inputs the whole previous
+// transaction as their non-witness utxo. inputs of the canister's addresses
+// also say which key signs them.
+fn annotate_input(
+    input: &mut Input,
+    spent: &SpentInput,
+    wallet: Option<&Address>,
+    previous: &HashMap<Txid, Transaction>,
+) -> Result<Address, String> {
+    let address = address_validation(&spent.address)?;
+    if script_type(&address) == Some(ScriptType::P2pkh) {
+        let txid = spent_txid(spent);
+        input.non_witness_utxo = Some(
+            previous
+                .get(&txid)
+                .cloned()
+                .ok_or_else(|| format!("previous transaction {} isn't available", txid))?,
+        );
+    } else {
+        input.witness_utxo = Some(TxOut {
+            value: Amount::from_sat(spent.utxo.value),
+            script_pubkey: address.script_pubkey(),
+        });
+    }
+    if wallet == Some(&address) {
+        return Ok(address);
+    }
+
+    let account = account_of_address(&spent.address)
+        .ok_or_else(|| format!("{} isn't a canister address", spent.address))?;
+    let path = account_to_derivation_path(&account)
+        .into_iter()
+        .map(|index| index.into_vec())
+        .collect::<Vec<_>>();
+    let keyname = read_config(|config| match script_type(&address) {
+        Some(ScriptType::P2tr) => config.schnorr_keyname(),
+        _ => config.keyname(),
+    });
+    input.proprietary.insert(
+        proprietary(DERIVATION_PATH_SUBTYPE),
+        bitcoin::consensus::serialize(&path),
+    );
+    input
+        .proprietary
+        .insert(proprietary(KEY_NAME_SUBTYPE), keyname.into_bytes());
+    Ok(address)
+}
+
+async fn to_psbt(
+    txn_type: &TransactionType,
+    wallet: Option<&Address>,
+) -> Result<(Psbt, Vec<Address>), String> {
+    let txn = txn_type
+        .bitcoin_txn()
+        .ok_or_else(|| String::from("not a bitcoin transaction"))?;
+    let spent_inputs = txn_type.spent_inputs();
+    let previous = previous_transactions(&spent_inputs).await?;
+    let mut psbt = Psbt::from_unsigned_tx(txn.clone()).map_err(|err| err.to_string())?;
+    let owners = psbt
+        .inputs
+        .iter_mut()
+        .zip(spent_inputs.iter())
+        .map(|(input, spent)| annotate_input(input, spent, wallet, &previous))
+        .collect::<Result<Vec<_>, String>>()?;
+    Ok((psbt, owners))
+}
+
+// the unsigned transaction built by a builder as a psbt
+pub async fn export(txn_type: &TransactionType) -> Result<PsbtExport, String> {
+    let (psbt, owners) = to_psbt(txn_type, None).await?;
+    let inputs = txn_type.spent_inputs();
+    let txn = &psbt.unsigned_tx;
+
+    let spent = inputs
+        .iter()
+        .fold(0, |total, input| total + input.utxo.value);
+    let sent = txn
+        .output
+        .iter()
+        .fold(0, |total, output| total + output.value.to_sat());
+    Ok(PsbtExport {
+        psbt: ByteBuf::from(psbt.serialize()),
+        fee: spent.saturating_sub(sent),
+        vsize: mock_signature(txn, &owners.iter().collect::<Vec<_>>()).vsize() as u64,
+        runestone: Runestone::decipher(txn)
+            .and_then(|artifact| serde_json::to_string(&artifact).ok()),
+    })
+}
+
+// a transaction spending `wallet` next to the canister's addresses, for the
+// wallet's owner to sign its inputs
+pub async fn for_wallet(txn_type: &TransactionType, wallet: &Address) -> Result<Psbt, String> {
+    to_psbt(txn_type, Some(wallet)).await.map(|(psbt, _)| psbt)
+}
+
+// copies the finalized wallet inputs of a psbt signed by the wallet's owner
+// into `txn`, the unsigned transaction it was made from
+pub fn wallet_signatures(
+    psbt: &Psbt,
+    txn: &mut Transaction,
+    wallet_outpoints: &[OutPoint],
+) -> Result<(), String> {
+    if psbt.unsigned_tx.compute_txid() != txn.compute_txid() {
+        return Err(String::from("psbt doesn't match the transaction"));
+    }
+    for (index, (input, signed)) in txn.input.iter_mut().zip(psbt.inputs.iter()).enumerate() {
+        if !wallet_outpoints.contains(&input.previous_output) {
+            continue;
+        }
+        let witness = signed
+            .final_script_witness
+            .clone()
+            .ok_or_else(|| format!("wallet input {} isn't finalized", index))?;
+        input.witness = witness;
+        input.script_sig = signed.final_script_sig.clone().unwrap_or_default();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        absolute::LockTime, transaction::Version, Network, PubkeyHash, ScriptBuf, Sequence, TxIn,
+        Witness,
+    };
+    use ic_cdk::api::management_canister::bitcoin::{BitcoinNetwork, Outpoint, Utxo};
+
+    use super::*;
+    use crate::state::write_config;
+
+    fn on_mainnet() {
+        write_config(|config| {
+            let mut temp = config.get().clone();
+            temp.bitcoin_network = Some(BitcoinNetwork::Mainnet);
+            let _ = config.set(temp);
+        });
+    }
+
+    fn segwit(seed: u8) -> Address {
+        Address::p2wsh(&ScriptBuf::from(vec![seed]), Network::Bitcoin)
+    }
+
+    fn legacy(seed: u8) -> Address {
+        Address::p2pkh(PubkeyHash::from_byte_array([seed; 20]), Network::Bitcoin)
+    }
+
+    fn unsigned(inputs: &[OutPoint], outputs: Vec<TxOut>) -> Transaction {
+        Transaction {
+            version: Version(2),
+            lock_time: LockTime::ZERO,
+            input: inputs
+                .iter()
+                .map(|previous_output| TxIn {
+                    previous_output: *previous_output,
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: outputs,
+        }
+    }
+
+    fn spent(address: &Address, txid: Txid, value: u64) -> SpentInput {
+        SpentInput {
+            address: address.to_string(),
+            utxo: Utxo {
+                outpoint: Outpoint {
+                    txid: txid.to_byte_array().to_vec(),
+                    vout: 0,
+                },
+                value,
+                height: 1,
+            },
+            rune: None,
+        }
+    }
+
+    #[test]
+    fn segwit_input_gets_its_witness_utxo() {
+        on_mainnet();
+        let wallet = segwit(1);
+        let input = spent(&wallet, Txid::from_byte_array([1; 32]), 5_000);
+        let mut annotated = Input::default();
+        let owner = annotate_input(&mut annotated, &input, Some(&wallet), &HashMap::new()).unwrap();
+        assert_eq!(owner, wallet);
+        assert_eq!(
+            annotated.witness_utxo,
+            Some(TxOut {
+                value: Amount::from_sat(5_000),
+                script_pubkey: wallet.script_pubkey(),
+            })
+        );
+        assert!(annotated.non_witness_utxo.is_none());
+        // the wallet's own inputs don't say how the canister signs
+        assert!(annotated.proprietary.is_empty());
+    }
+
+    #[test]
+    fn legacy_input_carries_its_previous_transaction() {
+        on_mainnet();
+        let wallet = legacy(2);
+        let previous = unsigned(
+            &[OutPoint::null()],
+            vec![TxOut {
+                value: Amount::from_sat(8_000),
+                script_pubkey: wallet.script_pubkey(),
+            }],
+        );
+        let input = spent(&wallet, previous.compute_txid(), 8_000);
+
+        let mut annotated = Input::default();
+        let missing = annotate_input(&mut annotated, &input, Some(&wallet), &HashMap::new());
+        assert!(missing.is_err());
+
+        let known = HashMap::from([(previous.compute_txid(), previous.clone())]);
+        let mut annotated = Input::default();
+        annotate_input(&mut annotated, &input, Some(&wallet), &known).unwrap();
+        assert_eq!(annotated.non_witness_utxo, Some(previous));
+        assert!(annotated.witness_utxo.is_none());
+    }
+
+    #[test]
+    fn wallet_signatures_are_copied_to_the_wallet_inputs_only() {
+        let wallet_outpoint = OutPoint::new(Txid::from_byte_array([1; 32]), 0);
+        let canister_outpoint = OutPoint::new(Txid::from_byte_array([2; 32]), 1);
+        let output = TxOut {
+            value: Amount::from_sat(1_000),
+            script_pubkey: segwit(3).script_pubkey(),
+        };
+        let mut txn = unsigned(&[wallet_outpoint, canister_outpoint], vec![output]);
+        let mut psbt = Psbt::from_unsigned_tx(txn.clone()).unwrap();
+
+        // not signed yet
+        assert!(wallet_signatures(&psbt, &mut txn.clone(), &[wallet_outpoint]).is_err());
+
+        let witness = Witness::from_slice(&[vec![1u8; 64]]);
+        psbt.inputs[0].final_script_witness = Some(witness.clone());
+        psbt.inputs[1].final_script_witness = Some(Witness::from_slice(&[vec![2u8; 64]]));
+        wallet_signatures(&psbt, &mut txn, &[wallet_outpoint]).unwrap();
+        assert_eq!(txn.input[0].witness, witness);
+        assert!(txn.input[1].witness.is_empty());
+    }
+
+    #[test]
+    fn psbt_of_another_transaction_is_refused() {
+        let outpoint = OutPoint::new(Txid::from_byte_array([1; 32]), 0);
+        let output = |value| TxOut {
+            value: Amount::from_sat(value),
+            script_pubkey: segwit(3).script_pubkey(),
+        };
+        let psbt = Psbt::from_unsigned_tx(unsigned(&[outpoint], vec![output(1_000)])).unwrap();
+        let mut other = unsigned(&[outpoint], vec![output(900)]);
+        assert!(wallet_signatures(&psbt, &mut other, &[outpoint]).is_err());
+    }
+}
@@ -1,4 +1,6 @@
+pub mod multi_transfer;
 pub mod transfer;
+pub mod validation;
 
 pub const DEFAULT_POSTAGE: u64 = 10_000;
 
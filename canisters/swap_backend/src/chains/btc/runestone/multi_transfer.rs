@@ -0,0 +1,525 @@
+use bitcoin::{
+    absolute::LockTime, hashes::Hash, transaction::Version, Address, Amount, OutPoint, ScriptBuf,
+    Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+};
+use icrc_ledger_types::icrc1::account::Account;
+use ordinals::{Edict, Runestone};
+
+use crate::{
+    chains::btc::{
+        coin_selection::Strategy,
+        runestone::{validation::Allocation, DEFAULT_POSTAGE},
+        signer::mock_signature,
+        DUST_THRESHOLD,
+    },
+    state::{txn_manager::SpentInput, write_utxo_manager},
+    txn_handler::{release_inputs, TransactionType},
+    types::RuneId,
+};
+
+// keeps the transaction well within the standard size
+pub const MAX_RECIPIENTS: usize = 250;
+
+pub struct RuneRecipient {
+    pub receiver: Address,
+    pub runeid: RuneId,
+    pub amount: u128,
+}
+
+// the address holding the runes of `runeid` sent out
+pub struct RuneSource {
+    pub runeid: RuneId,
+    pub sender: Address,
+    pub sender_account: Account,
+}
+
+pub struct MultiTransferArgs {
+    pub recipients: Vec<RuneRecipient>,
+    // one per rune sent, the first one gets the rune change
+    pub sources: Vec<RuneSource>,
+    pub fee_payer: Address,
+    pub fee_payer_account: Account,
+    pub postage: Option<u64>,
+    pub fee_per_vbytes: u64,
+    pub strategy: Strategy,
+}
+
+// what the recipients of `runeid` get together
+fn sent_total(recipients: &[RuneRecipient], runeid: &RuneId) -> Result<u128, String> {
+    recipients
+        .iter()
+        .filter(|recipient| &recipient.runeid == runeid)
+        .try_fold(0u128, |total, recipient| {
+            total.checked_add(recipient.amount)
+        })
+        .ok_or_else(|| format!("amounts of rune {}:{} overflow", runeid.block, runeid.tx))
+}
+
+fn to_input(input: &SpentInput) -> TxIn {
+    TxIn {
+        script_sig: ScriptBuf::new(),
+        witness: Witness::new(),
+        sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+        previous_output: OutPoint {
+            txid: Txid::from_raw_hash(Hash::from_slice(&input.utxo.outpoint.txid).unwrap()),
+            vout: input.utxo.outpoint.vout,
+        },
+    }
+}
+
+// sends runes to many recipients in a single transaction, one edict per
+// recipient. whatever the edicts leave of the inputs' runes goes to the
+// change output the runestone's pointer names.
+pub fn transfer_many(
+    MultiTransferArgs {
+        recipients,
+        sources,
+        fee_payer,
+        fee_payer_account,
+        postage,
+        fee_per_vbytes,
+        strategy,
+    }: MultiTransferArgs,
+) -> Result<TransactionType, String> {
+    if recipients.is_empty() || recipients.len() > MAX_RECIPIENTS {
+        return Err(format!(
+            "between 1 and {} recipients are supported",
+            MAX_RECIPIENTS
+        ));
+    }
+    // an edict of 0 would hand over everything left of the rune
+    for (index, recipient) in recipients.iter().enumerate() {
+        if recipient.amount == 0 {
+            return Err(format!("recipient {} gets no runes", recipient.receiver));
+        }
+        let is_duplicate = recipients[..index].iter().any(|other| {
+            other.runeid == recipient.runeid
+                && other.receiver.script_pubkey() == recipient.receiver.script_pubkey()
+        });
+        if is_duplicate {
+            return Err(format!(
+                "recipient {} is listed twice for rune {}:{}",
+                recipient.receiver, recipient.runeid.block, recipient.runeid.tx
+            ));
+        }
+    }
+    for source in sources.iter() {
+        sent_total(&recipients, &source.runeid)?;
+    }
+    let postage = Amount::from_sat(postage.unwrap_or(DEFAULT_POSTAGE));
+    let mut total_fee = 0;
+    loop {
+        let (txn, inputs, signers) = build_transaction_with_fee(
+            &recipients,
+            &sources,
+            &fee_payer,
+            &fee_payer_account,
+            postage,
+            total_fee,
+            strategy,
+        )?;
+
+        let spent = signers
+            .iter()
+            .map(|(address, _)| address)
+            .collect::<Vec<_>>();
+        let signed_txn = mock_signature(&txn, &spent);
+        let required_fee = (signed_txn.vsize() as u64 * fee_per_vbytes) / 1000;
+        if required_fee <= total_fee {
+            let allocations = match allocations(&recipients, &sources, &inputs) {
+                Ok(allocations) => allocations,
+                Err(err) => {
+                    release_inputs(inputs);
+                    return Err(err);
+                }
+            };
+            return Ok(TransactionType::Batch {
+                txn,
+                inputs,
+                signers,
+                change: vec![],
+                allocations,
+            });
+        }
+        release_inputs(inputs);
+        total_fee = required_fee;
+    }
+}
+
+// every recipient gets its amount at its postage output, the rest of each
+// rune selected goes to the change output
+fn allocations(
+    recipients: &[RuneRecipient],
+    sources: &[RuneSource],
+    inputs: &[SpentInput],
+) -> Result<Vec<Allocation>, String> {
+    let mut allocations = recipients
+        .iter()
+        .enumerate()
+        .map(|(index, recipient)| Allocation {
+            vout: index as u32 + 2,
+            runeid: recipient.runeid.clone(),
+            amount: recipient.amount,
+        })
+        .collect::<Vec<_>>();
+    for source in sources {
+        let selected = inputs
+            .iter()
+            .filter_map(|input| input.rune.as_ref())
+            .filter(|(runeid, _)| runeid == &source.runeid)
+            .try_fold(0u128, |total, (_, balance)| total.checked_add(*balance))
+            .ok_or_else(|| String::from("selected rune balances overflow"))?;
+        let sent = sent_total(recipients, &source.runeid)?;
+        let change = selected
+            .checked_sub(sent)
+            .ok_or_else(|| String::from("selected fewer runes than sent"))?;
+        allocations.push(Allocation {
+            vout: 1,
+            runeid: source.runeid.clone(),
+            amount: change,
+        });
+    }
+    Ok(allocations)
+}
+
+fn build_transaction_with_fee(
+    recipients: &[RuneRecipient],
+    sources: &[RuneSource],
+    fee_payer: &Address,
+    fee_payer_account: &Account,
+    postage: Amount,
+    fee: u64,
+    strategy: Strategy,
+) -> Result<(Transaction, Vec<SpentInput>, Vec<(Address, Account)>), String> {
+    let (mut inputs, mut signers) = (vec![], vec![]);
+
+    for source in sources {
+        // checked by transfer_many already
+        let total = sent_total(recipients, &source.runeid)?;
+        let Some(runic_utxos) = write_utxo_manager(|manager| {
+            manager.select_runic_utxos(&source.sender.to_string(), &source.runeid, total, strategy)
+        }) else {
+            release_inputs(inputs);
+            return Err(format!(
+                "{} of rune {}:{} required",
+                total, source.runeid.block, source.runeid.tx
+            ));
+        };
+        for utxo in runic_utxos {
+            inputs.push(SpentInput {
+                address: source.sender.to_string(),
+                utxo: utxo.utxo,
+                rune: Some((source.runeid.clone(), utxo.balance)),
+            });
+            signers.push((source.sender.clone(), source.sender_account));
+        }
+    }
+    let Some(change) = sources.first() else {
+        return Err(String::from("no rune source"));
+    };
+
+    // the fee payer covers the fee and postage beyond the sats which came
+    // along with the runes, anything above that stays with the rune change
+    let runic_sats = inputs
+        .iter()
+        .fold(0, |total, input| total + input.utxo.value);
+    let postage_btc = postage.to_sat() * (recipients.len() as u64 + 1);
+    let required = (postage_btc + fee).saturating_sub(runic_sats);
+    let fee_utxos = if required == 0 {
+        vec![]
+    } else {
+        let Some(fee_utxos) = write_utxo_manager(|manager| {
+            manager.select_bitcoin_utxos(&fee_payer.to_string(), required, strategy)
+        }) else {
+            release_inputs(inputs);
+            return Err(format!("{} sats required including fee", required));
+        };
+        fee_utxos
+    };
+    for utxo in fee_utxos {
+        inputs.push(SpentInput {
+            address: fee_payer.to_string(),
+            utxo,
+            rune: None,
+        });
+        signers.push((fee_payer.clone(), *fee_payer_account));
+    }
+
+    let txn = transaction(recipients, &change.sender, fee_payer, &inputs, postage, fee);
+    Ok((txn, inputs, signers))
+}
+
+// lays out the transfer spending `inputs`, the runic ones first
+//
+// 0: runestone
+// 1: rune change, named by the pointer
+// 2..: a postage output per recipient
+// last: bitcoin change (if above dust)
+fn transaction(
+    recipients: &[RuneRecipient],
+    change: &Address,
+    fee_payer: &Address,
+    inputs: &[SpentInput],
+    postage: Amount,
+    fee: u64,
+) -> Transaction {
+    let (runic_sats, fee_sats) = inputs.iter().fold((0, 0), |(runic, plain), input| {
+        if input.rune.is_some() {
+            (runic + input.utxo.value, plain)
+        } else {
+            (runic, plain + input.utxo.value)
+        }
+    });
+    let postage_btc = postage.to_sat() * (recipients.len() as u64 + 1);
+    let required = (postage_btc + fee).saturating_sub(runic_sats);
+
+    let runestone = Runestone {
+        edicts: recipients
+            .iter()
+            .enumerate()
+            .map(|(index, recipient)| Edict {
+                id: ordinals::RuneId {
+                    block: recipient.runeid.block,
+                    tx: recipient.runeid.tx,
+                },
+                amount: recipient.amount,
+                output: index as u32 + 2,
+            })
+            .collect(),
+        pointer: Some(1),
+        ..Default::default()
+    };
+    let mut output = vec![
+        TxOut {
+            script_pubkey: runestone.encipher(),
+            value: Amount::from_sat(0),
+        },
+        TxOut {
+            script_pubkey: change.script_pubkey(),
+            value: postage + Amount::from_sat(runic_sats.saturating_sub(postage_btc + fee)),
+        },
+    ];
+    output.extend(recipients.iter().map(|recipient| TxOut {
+        script_pubkey: recipient.receiver.script_pubkey(),
+        value: postage,
+    }));
+    let remaining = fee_sats.saturating_sub(required);
+    if remaining > DUST_THRESHOLD {
+        output.push(TxOut {
+            script_pubkey: fee_payer.script_pubkey(),
+            value: Amount::from_sat(remaining),
+        });
+    }
+
+    Transaction {
+        input: inputs.iter().map(to_input).collect(),
+        output,
+        version: Version(2),
+        lock_time: LockTime::ZERO,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::Network;
+    use candid::Principal;
+    use ic_cdk::api::management_canister::bitcoin::{Outpoint, Utxo};
+
+    use super::*;
+    use crate::chains::btc::runestone::validation::validate_runestone;
+
+    const POSTAGE: u64 = 546;
+
+    fn address(seed: u8) -> Address {
+        Address::p2wsh(&ScriptBuf::from(vec![seed]), Network::Bitcoin)
+    }
+
+    fn rune(tx: u32) -> RuneId {
+        RuneId { block: 840_000, tx }
+    }
+
+    fn recipient(seed: u8, runeid: RuneId, amount: u128) -> RuneRecipient {
+        RuneRecipient {
+            receiver: address(seed),
+            runeid,
+            amount,
+        }
+    }
+
+    fn source(seed: u8, runeid: RuneId) -> RuneSource {
+        RuneSource {
+            runeid,
+            sender: address(seed),
+            sender_account: Account {
+                owner: Principal::anonymous(),
+                subaccount: None,
+            },
+        }
+    }
+
+    fn input(seed: u8, vout: u32, value: u64, rune: Option<(RuneId, u128)>) -> SpentInput {
+        SpentInput {
+            address: address(seed).to_string(),
+            utxo: Utxo {
+                outpoint: Outpoint {
+                    txid: vec![seed; 32],
+                    vout,
+                },
+                value,
+                height: 1,
+            },
+            rune,
+        }
+    }
+
+    fn transfer_args(recipients: Vec<RuneRecipient>) -> MultiTransferArgs {
+        MultiTransferArgs {
+            recipients,
+            sources: vec![source(10, rune(1))],
+            fee_payer: address(20),
+            fee_payer_account: Account {
+                owner: Principal::anonymous(),
+                subaccount: None,
+            },
+            postage: None,
+            fee_per_vbytes: 1_000,
+            strategy: Strategy::LargestFirst,
+        }
+    }
+
+    #[test]
+    fn refuses_what_the_edicts_cant_express() {
+        assert!(transfer_many(transfer_args(vec![])).is_err());
+        assert!(transfer_many(transfer_args(vec![recipient(1, rune(1), 0)])).is_err());
+        let twice = vec![recipient(1, rune(1), 10), recipient(1, rune(1), 20)];
+        assert!(transfer_many(transfer_args(twice)).is_err());
+        let overflowing = vec![recipient(1, rune(1), u128::MAX), recipient(2, rune(1), 1)];
+        assert!(transfer_many(transfer_args(overflowing)).is_err());
+    }
+
+    #[test]
+    fn sent_total_adds_up_a_single_rune() {
+        let recipients = vec![
+            recipient(1, rune(1), 30),
+            recipient(2, rune(2), 5),
+            recipient(3, rune(1), 20),
+        ];
+        assert_eq!(sent_total(&recipients, &rune(1)), Ok(50));
+        assert_eq!(sent_total(&recipients, &rune(3)), Ok(0));
+    }
+
+    #[test]
+    fn lays_out_runestone_change_recipients_and_bitcoin_change() {
+        let recipients = vec![
+            recipient(1, rune(1), 30),
+            recipient(2, rune(1), 20),
+            recipient(3, rune(2), 10),
+        ];
+        let inputs = vec![
+            input(10, 0, POSTAGE, Some((rune(1), 100))),
+            input(11, 0, POSTAGE, Some((rune(2), 50))),
+            input(20, 0, 10_000, None),
+        ];
+        let fee = 1_000;
+        let txn = transaction(
+            &recipients,
+            &address(10),
+            &address(20),
+            &inputs,
+            Amount::from_sat(POSTAGE),
+            fee,
+        );
+
+        assert_eq!(txn.input.len(), 3);
+        assert_eq!(txn.output.len(), 6);
+        assert_eq!(txn.output[1].script_pubkey, address(10).script_pubkey());
+        for (index, recipient) in recipients.iter().enumerate() {
+            let output = &txn.output[index + 2];
+            assert_eq!(output.script_pubkey, recipient.receiver.script_pubkey());
+            assert_eq!(output.value.to_sat(), POSTAGE);
+        }
+        assert_eq!(txn.output[5].script_pubkey, address(20).script_pubkey());
+
+        let Some(ordinals::Artifact::Runestone(runestone)) = Runestone::decipher(&txn) else {
+            panic!("no runestone");
+        };
+        assert_eq!(runestone.pointer, Some(1));
+        let edicts = runestone
+            .edicts
+            .iter()
+            .map(|edict| (edict.id.tx, edict.amount, edict.output))
+            .collect::<Vec<_>>();
+        assert_eq!(edicts, vec![(1, 30, 2), (1, 20, 3), (2, 10, 4)]);
+
+        // what goes in leaves as outputs and the fee
+        let spent = inputs.iter().map(|input| input.utxo.value).sum::<u64>();
+        let sent = txn
+            .output
+            .iter()
+            .map(|output| output.value.to_sat())
+            .sum::<u64>();
+        assert_eq!(spent - sent, fee);
+    }
+
+    #[test]
+    fn dust_bitcoin_change_is_left_to_the_fee() {
+        let recipients = vec![recipient(1, rune(1), 30)];
+        let inputs = vec![
+            input(10, 0, POSTAGE, Some((rune(1), 100))),
+            input(20, 0, POSTAGE + 1_000 + DUST_THRESHOLD, None),
+        ];
+        let txn = transaction(
+            &recipients,
+            &address(10),
+            &address(20),
+            &inputs,
+            Amount::from_sat(POSTAGE),
+            1_000,
+        );
+        assert_eq!(txn.output.len(), 3);
+    }
+
+    #[test]
+    fn change_gets_what_was_selected_but_not_sent() {
+        let recipients = vec![
+            recipient(1, rune(1), 30),
+            recipient(2, rune(1), 20),
+            recipient(3, rune(2), 10),
+        ];
+        let sources = vec![source(10, rune(1)), source(11, rune(2))];
+        let inputs = vec![
+            input(10, 0, POSTAGE, Some((rune(1), 60))),
+            input(10, 1, POSTAGE, Some((rune(1), 40))),
+            input(11, 0, POSTAGE, Some((rune(2), 50))),
+            input(20, 0, 10_000, None),
+        ];
+        let allocations = allocations(&recipients, &sources, &inputs).unwrap();
+        let laid_out = allocations
+            .iter()
+            .map(|allocation| (allocation.vout, allocation.runeid.tx, allocation.amount))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            laid_out,
+            vec![(2, 1, 30), (3, 1, 20), (4, 2, 10), (1, 1, 50), (1, 2, 40)]
+        );
+
+        // the runestone moves the runes exactly as allocated
+        let txn = transaction(
+            &recipients,
+            &address(10),
+            &address(20),
+            &inputs,
+            Amount::from_sat(POSTAGE),
+            1_000,
+        );
+        assert!(validate_runestone(&txn, &inputs, &allocations).is_ok());
+    }
+
+    #[test]
+    fn selecting_less_than_sent_is_an_error() {
+        let recipients = vec![recipient(1, rune(1), 30)];
+        let sources = vec![source(10, rune(1))];
+        let inputs = vec![input(10, 0, POSTAGE, Some((rune(1), 20)))];
+        assert!(allocations(&recipients, &sources, &inputs).is_err());
+    }
+}
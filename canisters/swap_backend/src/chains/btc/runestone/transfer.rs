@@ -7,7 +7,12 @@ use icrc_ledger_types::icrc1::account::Account;
 use ordinals::{Edict, Runestone};
 
 use crate::{
-    chains::btc::{runestone::DEFAULT_POSTAGE, signer::mock_signature, DUST_THRESHOLD},
+    chains::btc::{
+        coin_selection::Strategy,
+        runestone::{validation::Allocation, DEFAULT_POSTAGE},
+        signer::mock_signature,
+        DUST_THRESHOLD,
+    },
     state::write_utxo_manager,
     txn_handler::TransactionType,
     types::{RuneId, RunicUtxo},
@@ -23,6 +28,7 @@ pub struct RuneTransferArgs {
     pub fee_payer_account: Account,
     pub postage: Option<u64>,
     pub fee_per_vbytes: u64,
+    pub strategy: Strategy,
 }
 
 pub fn transfer(
@@ -36,42 +42,47 @@ pub fn transfer(
         fee_payer_account,
         postage,
         fee_per_vbytes,
+        strategy,
     }: RuneTransferArgs,
 ) -> Result<TransactionType, (u128, u64)> {
     let mut total_fee = 0;
     let postage = Amount::from_sat(postage.unwrap_or(DEFAULT_POSTAGE));
     loop {
-        let (txn, runic_utxos, fee_utxos) = build_transaction_with_fee(
-            &runeid, amount, &sender, &receiver, &fee_payer, postage, total_fee,
+        let (txn, runic_utxos, fee_utxos, allocations) = build_transaction_with_fee(
+            &runeid, amount, &sender, &receiver, &fee_payer, postage, total_fee, strategy,
         )?;
 
-        let signed_txn = mock_signature(&txn);
+        let spent = std::iter::repeat_n(&sender, runic_utxos.len())
+            .chain(std::iter::repeat_n(&fee_payer, fee_utxos.len()))
+            .collect::<Vec<_>>();
+        let signed_txn = mock_signature(&txn, &spent);
         let txn_vsize = signed_txn.vsize() as u64;
 
-        if (txn_vsize * fee_per_vbytes) / 1000 == total_fee {
+        let required_fee = (txn_vsize * fee_per_vbytes) / 1000;
+        if required_fee <= total_fee {
             return Ok(TransactionType::Rune {
+                txn,
                 rune: runeid,
-                rune_amount: amount,
                 runic_utxos,
                 sender: Box::new(sender),
-                receiver: Box::new(receiver),
                 sender_account,
                 fee: total_fee,
                 fee_payer: Box::new(fee_payer),
                 fee_payer_account,
                 fee_utxos,
                 postage,
+                allocations,
             });
         } else {
             write_utxo_manager(|manager| {
-                manager.record_runic_utxos(
+                manager.release_runic_utxos(
                     sender.to_string().as_str(),
                     runeid.clone(),
                     runic_utxos,
                 );
-                manager.record_btc_utxos(fee_payer.to_string().as_str(), fee_utxos);
+                manager.release_btc_utxos(fee_payer.to_string().as_str(), fee_utxos);
             });
-            total_fee = (txn_vsize * fee_per_vbytes) / 1000;
+            total_fee = required_fee;
         }
     }
 }
@@ -84,55 +95,45 @@ fn build_transaction_with_fee(
     fee_payer: &Address,
     postage: Amount,
     fee: u64,
-) -> Result<(Transaction, Vec<RunicUtxo>, Vec<Utxo>), (u128, u64)> {
+    strategy: Strategy,
+) -> Result<(Transaction, Vec<RunicUtxo>, Vec<Utxo>, Vec<Allocation>), (u128, u64)> {
     let (mut input, mut output) = (vec![], vec![]);
 
-    let (runic_utxos, runic_total_spent, btc_in_runic_spent) = write_utxo_manager(|manager| {
-        let (mut utxos, mut runic_total_spent, mut btc_in_runic_spent) = (vec![], 0, 0);
-        let addr = sender.to_string();
-        while let Some(utxo) = manager.get_runic_utxo(&addr, runeid.clone()) {
-            runic_total_spent += utxo.balance;
-            btc_in_runic_spent += utxo.utxo.value;
-            utxos.push(utxo);
-            if runic_total_spent >= amount {
-                break;
-            }
-        }
-
-        if runic_total_spent < amount {
-            manager.record_runic_utxos(&addr, runeid.clone(), utxos);
-            return Err((amount, fee));
-        }
-        Ok((utxos, runic_total_spent, btc_in_runic_spent))
-    })?;
-
-    let need_change_rune_output = runic_utxos.len() > 1 || runic_total_spent > amount;
+    let runic_utxos = write_utxo_manager(|manager| {
+        manager.select_runic_utxos(&sender.to_string(), runeid, amount, strategy)
+    })
+    .ok_or((amount, fee))?;
+    let (runic_total_spent, btc_in_runic_spent) =
+        runic_utxos.iter().fold((0, 0), |(runes, sats), utxo| {
+            (runes + utxo.balance, sats + utxo.utxo.value)
+        });
 
-    let required_postage_btc = if need_change_rune_output {
+    // sats of the runic utxos beyond the postage go back to the sender along
+    // with its rune change
+    let need_change_rune_output = runic_utxos.len() > 1
+        || runic_total_spent > amount
+        || btc_in_runic_spent > postage.to_sat();
+    let postage_btc = if need_change_rune_output {
         postage.to_sat() * 2
     } else {
         postage.to_sat()
-    } - btc_in_runic_spent;
-
-    let (fee_utxos, fee_total_spent) = write_utxo_manager(|manager| {
-        let (mut utxos, mut total_spent) = (vec![], 0);
-
-        let addr = fee_payer.to_string();
-        while let Some(utxo) = manager.get_bitcoin_utxo(&addr) {
-            total_spent += utxo.value;
-            utxos.push(utxo);
-            if total_spent >= fee + required_postage_btc {
-                break;
-            }
-        }
-
-        if total_spent < fee + required_postage_btc {
-            manager.record_btc_utxos(&addr, utxos);
-            return Err((amount, fee + required_postage_btc));
+    };
+    let required_postage_btc = postage_btc.saturating_sub(btc_in_runic_spent);
+    let rune_change_value = postage.to_sat() + btc_in_runic_spent.saturating_sub(postage_btc);
+
+    let fee_utxos = write_utxo_manager(|manager| {
+        let fee_utxos = manager.select_bitcoin_utxos(
+            &fee_payer.to_string(),
+            fee + required_postage_btc,
+            strategy,
+        );
+        if fee_utxos.is_none() {
+            manager.release_runic_utxos(&sender.to_string(), runeid.clone(), runic_utxos.clone());
         }
-
-        Ok((utxos, total_spent))
-    })?;
+        fee_utxos
+    })
+    .ok_or((amount, fee + required_postage_btc))?;
+    let fee_total_spent = fee_utxos.iter().fold(0, |sum, utxo| sum + utxo.value);
 
     runic_utxos
         .iter()
@@ -140,7 +141,7 @@ fn build_transaction_with_fee(
             input.push(TxIn {
                 script_sig: ScriptBuf::new(),
                 witness: Witness::new(),
-                sequence: Sequence::MAX,
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
                 previous_output: OutPoint {
                     txid: Txid::from_raw_hash(Hash::from_slice(&utxo.outpoint.txid).unwrap()),
                     vout: utxo.outpoint.vout,
@@ -152,7 +153,7 @@ fn build_transaction_with_fee(
         input.push(TxIn {
             script_sig: ScriptBuf::new(),
             witness: Witness::new(),
-            sequence: Sequence::MAX,
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
             previous_output: OutPoint {
                 txid: Txid::from_raw_hash(Hash::from_slice(&utxo.outpoint.txid).unwrap()),
                 vout: utxo.outpoint.vout,
@@ -160,7 +161,18 @@ fn build_transaction_with_fee(
         });
     });
 
+    let mut allocations = vec![];
     if need_change_rune_output {
+        allocations.push(Allocation {
+            vout: 1,
+            runeid: runeid.clone(),
+            amount: runic_total_spent - amount,
+        });
+        allocations.push(Allocation {
+            vout: 2,
+            runeid: runeid.clone(),
+            amount,
+        });
         let runestone = Runestone {
             edicts: vec![Edict {
                 id: ordinals::RuneId {
@@ -180,7 +192,7 @@ fn build_transaction_with_fee(
 
         output.push(TxOut {
             script_pubkey: sender.script_pubkey(),
-            value: postage,
+            value: Amount::from_sat(rune_change_value),
         });
 
         output.push(TxOut {
@@ -188,6 +200,11 @@ fn build_transaction_with_fee(
             value: postage,
         });
     } else {
+        allocations.push(Allocation {
+            vout: 0,
+            runeid: runeid.clone(),
+            amount,
+        });
         output.push(TxOut {
             script_pubkey: receiver.script_pubkey(),
             value: postage,
@@ -210,5 +227,5 @@ fn build_transaction_with_fee(
         lock_time: LockTime::ZERO,
     };
 
-    Ok((txn, runic_utxos, fee_utxos))
+    Ok((txn, runic_utxos, fee_utxos, allocations))
 }
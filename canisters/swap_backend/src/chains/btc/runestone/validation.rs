@@ -0,0 +1,319 @@
+use std::collections::BTreeMap;
+
+use bitcoin::Transaction;
+use ordinals::{Artifact, Edict, Runestone};
+
+use crate::{state::txn_manager::SpentInput, types::RuneId};
+
+// runes the builder of a transaction means output `vout` to receive
+#[derive(Clone, Debug)]
+pub struct Allocation {
+    pub vout: u32,
+    pub runeid: RuneId,
+    pub amount: u128,
+}
+
+// runes ending up at every output, keyed by vout and rune
+pub type OutputRunes = BTreeMap<(u32, RuneId), u128>;
+
+fn rune_id(id: ordinals::RuneId) -> RuneId {
+    RuneId {
+        block: id.block,
+        tx: id.tx,
+    }
+}
+
+// replays the transaction's runestone against the runes of `inputs` the way
+// the indexer would, returning what every output ends up with and what gets
+// burned. `inputs` has to follow the order of the transaction's inputs.
+pub fn replay(
+    txn: &Transaction,
+    inputs: &[SpentInput],
+) -> Result<(OutputRunes, BTreeMap<RuneId, u128>), String> {
+    let mut unallocated: BTreeMap<RuneId, u128> = BTreeMap::new();
+    for (runeid, balance) in inputs.iter().filter_map(|input| input.rune.as_ref()) {
+        *unallocated.entry(runeid.clone()).or_default() += balance;
+    }
+    let mut allocated = OutputRunes::new();
+
+    let pointer = match Runestone::decipher(txn) {
+        Some(Artifact::Cenotaph(cenotaph)) => {
+            return Err(match cenotaph.flaw {
+                Some(flaw) => format!("runestone is a cenotaph: {}", flaw),
+                None => String::from("runestone is a cenotaph"),
+            })
+        }
+        Some(Artifact::Runestone(runestone)) => {
+            if runestone.etching.is_some() || runestone.mint.is_some() {
+                return Err(String::from("runestone etches or mints a rune"));
+            }
+            for Edict { id, amount, output } in runestone.edicts {
+                let runeid = rune_id(id);
+                let Some(balance) = unallocated.get_mut(&runeid) else {
+                    return Err(format!(
+                        "edict of rune {}:{} which no input carries",
+                        runeid.block, runeid.tx
+                    ));
+                };
+                let mut allocate = |balance: &mut u128, amount: u128, vout: u32| {
+                    if amount > 0 {
+                        *balance -= amount;
+                        *allocated.entry((vout, runeid.clone())).or_default() += amount;
+                    }
+                };
+                if output as usize == txn.output.len() {
+                    // split across every non op_return output
+                    let destinations = txn
+                        .output
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, output)| !output.script_pubkey.is_op_return())
+                        .map(|(vout, _)| vout as u32)
+                        .collect::<Vec<_>>();
+                    if destinations.is_empty() {
+                        continue;
+                    }
+                    if amount == 0 {
+                        let share = *balance / destinations.len() as u128;
+                        let remainder = (*balance % destinations.len() as u128) as usize;
+                        for (index, vout) in destinations.into_iter().enumerate() {
+                            let amount = if index < remainder { share + 1 } else { share };
+                            allocate(balance, amount, vout);
+                        }
+                    } else {
+                        for vout in destinations {
+                            allocate(balance, amount.min(*balance), vout);
+                        }
+                    }
+                } else {
+                    let amount = if amount == 0 {
+                        *balance
+                    } else {
+                        amount.min(*balance)
+                    };
+                    allocate(balance, amount, output);
+                }
+            }
+            runestone.pointer
+        }
+        None => None,
+    };
+
+    // the rest goes to the pointer, or the first non op_return output
+    let mut burned: BTreeMap<RuneId, u128> = BTreeMap::new();
+    let default = pointer.or_else(|| {
+        txn.output
+            .iter()
+            .position(|output| !output.script_pubkey.is_op_return())
+            .map(|vout| vout as u32)
+    });
+    for (runeid, balance) in unallocated {
+        if balance == 0 {
+            continue;
+        }
+        match default {
+            Some(vout) => *allocated.entry((vout, runeid)).or_default() += balance,
+            None => *burned.entry(runeid).or_default() += balance,
+        }
+    }
+    allocated.retain(|(vout, runeid), amount| {
+        if txn.output[*vout as usize].script_pubkey.is_op_return() {
+            *burned.entry(runeid.clone()).or_default() += *amount;
+            return false;
+        }
+        true
+    });
+    Ok((allocated, burned))
+}
+
+// checks the runestone of `txn` moves the runes of `inputs` exactly as
+// `expected` says, refusing anything that would burn them
+pub fn validate_runestone(
+    txn: &Transaction,
+    inputs: &[SpentInput],
+    expected: &[Allocation],
+) -> Result<(), String> {
+    let (allocated, burned) = replay(txn, inputs)?;
+    if let Some((runeid, amount)) = burned.into_iter().next() {
+        return Err(format!(
+            "transaction would burn {} of rune {}:{}",
+            amount, runeid.block, runeid.tx
+        ));
+    }
+    let mut wanted = OutputRunes::new();
+    for Allocation {
+        vout,
+        runeid,
+        amount,
+    } in expected.iter().filter(|allocation| allocation.amount > 0)
+    {
+        *wanted.entry((*vout, runeid.clone())).or_default() += amount;
+    }
+    for key in allocated.keys().chain(wanted.keys()) {
+        let (vout, runeid) = key;
+        let actual = allocated.get(key).copied().unwrap_or_default();
+        let expected = wanted.get(key).copied().unwrap_or_default();
+        if actual != expected {
+            return Err(format!(
+                "output {} would receive {} of rune {}:{} instead of {}",
+                vout, actual, runeid.block, runeid.tx, expected
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{absolute::LockTime, transaction::Version, Amount, ScriptBuf, TxOut};
+    use ic_cdk::api::management_canister::bitcoin::{Outpoint, Utxo};
+
+    use super::*;
+
+    const RUNE: ordinals::RuneId = ordinals::RuneId {
+        block: 840_000,
+        tx: 1,
+    };
+
+    fn input(vout: u32, balance: u128) -> SpentInput {
+        SpentInput {
+            address: String::new(),
+            utxo: Utxo {
+                outpoint: Outpoint {
+                    txid: vec![0; 32],
+                    vout,
+                },
+                value: 10_000,
+                height: 1,
+            },
+            rune: Some((rune_id(RUNE), balance)),
+        }
+    }
+
+    // the runestone at vout 0 followed by `outputs` plain outputs
+    fn transaction(runestone: Runestone, outputs: usize) -> Transaction {
+        let mut output = vec![TxOut {
+            script_pubkey: runestone.encipher(),
+            value: Amount::from_sat(0),
+        }];
+        output.extend((0..outputs).map(|_| TxOut {
+            script_pubkey: ScriptBuf::new(),
+            value: Amount::from_sat(546),
+        }));
+        Transaction {
+            version: Version(2),
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output,
+        }
+    }
+
+    fn edict(amount: u128, output: u32) -> Runestone {
+        Runestone {
+            edicts: vec![Edict {
+                id: RUNE,
+                amount,
+                output,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn zero_amount_moves_the_whole_balance() {
+        let txn = transaction(edict(0, 2), 2);
+        let (allocated, burned) = replay(&txn, &[input(0, 60), input(1, 40)]).unwrap();
+        assert_eq!(allocated, OutputRunes::from([((2, rune_id(RUNE)), 100)]));
+        assert!(burned.is_empty());
+    }
+
+    #[test]
+    fn leftover_goes_to_the_first_output() {
+        let txn = transaction(edict(30, 2), 2);
+        let (allocated, burned) = replay(&txn, &[input(0, 100)]).unwrap();
+        assert_eq!(
+            allocated,
+            OutputRunes::from([((1, rune_id(RUNE)), 70), ((2, rune_id(RUNE)), 30)])
+        );
+        assert!(burned.is_empty());
+    }
+
+    #[test]
+    fn split_to_all_hands_out_the_remainder_in_order() {
+        // an output equal to the number of outputs splits across every one
+        // which isn't an op_return
+        let txn = transaction(edict(0, 4), 3);
+        let (allocated, burned) = replay(&txn, &[input(0, 10)]).unwrap();
+        assert_eq!(
+            allocated,
+            OutputRunes::from([
+                ((1, rune_id(RUNE)), 4),
+                ((2, rune_id(RUNE)), 3),
+                ((3, rune_id(RUNE)), 3),
+            ])
+        );
+        assert!(burned.is_empty());
+    }
+
+    #[test]
+    fn split_to_all_with_an_amount_runs_out() {
+        let txn = transaction(edict(4, 4), 3);
+        let (allocated, _) = replay(&txn, &[input(0, 10)]).unwrap();
+        assert_eq!(
+            allocated,
+            OutputRunes::from([
+                ((1, rune_id(RUNE)), 4),
+                ((2, rune_id(RUNE)), 4),
+                ((3, rune_id(RUNE)), 2),
+            ])
+        );
+    }
+
+    #[test]
+    fn pointer_to_op_return_burns() {
+        let runestone = Runestone {
+            pointer: Some(0),
+            ..Default::default()
+        };
+        let txn = transaction(runestone, 1);
+        let (allocated, burned) = replay(&txn, &[input(0, 50)]).unwrap();
+        assert!(allocated.is_empty());
+        assert_eq!(burned, BTreeMap::from([(rune_id(RUNE), 50)]));
+        assert!(validate_runestone(&txn, &[input(0, 50)], &[]).is_err());
+    }
+
+    #[test]
+    fn edict_to_op_return_burns() {
+        let txn = transaction(edict(0, 0), 1);
+        let (allocated, burned) = replay(&txn, &[input(0, 50)]).unwrap();
+        assert!(allocated.is_empty());
+        assert_eq!(burned, BTreeMap::from([(rune_id(RUNE), 50)]));
+    }
+
+    #[test]
+    fn cenotaph_is_refused() {
+        // an edict to an output past the last one makes the runestone a cenotaph
+        let txn = transaction(edict(10, 5), 1);
+        let err = replay(&txn, &[input(0, 50)]).unwrap_err();
+        assert!(err.contains("cenotaph"));
+    }
+
+    #[test]
+    fn edict_of_a_rune_no_input_carries() {
+        let txn = transaction(edict(10, 1), 1);
+        assert!(replay(&txn, &[]).is_err());
+    }
+
+    #[test]
+    fn validates_expected_allocations() {
+        let txn = transaction(edict(30, 2), 2);
+        let inputs = [input(0, 100)];
+        let allocation = |vout, amount| Allocation {
+            vout,
+            runeid: rune_id(RUNE),
+            amount,
+        };
+        assert!(validate_runestone(&txn, &inputs, &[allocation(1, 70), allocation(2, 30)]).is_ok());
+        assert!(validate_runestone(&txn, &inputs, &[allocation(2, 100)]).is_err());
+    }
+}
@@ -1,17 +1,20 @@
 use bitcoin::{
     script::{Builder, PushBytesBuf},
     sighash::EcdsaSighashType,
-    Sequence, Transaction, TxIn, Witness,
+    Address, ScriptBuf, Sequence, Transaction, TxIn, Witness,
 };
-use ic_cdk::api::management_canister::ecdsa::{
-    sign_with_ecdsa, SignWithEcdsaArgument, SignWithEcdsaResponse,
+use ic_cdk::api::management_canister::{
+    ecdsa::{sign_with_ecdsa, SignWithEcdsaArgument, SignWithEcdsaResponse},
+    schnorr::{sign_with_schnorr, SignWithSchnorrArgument, SignWithSchnorrResponse},
 };
 
-use crate::state::read_config;
+use crate::{state::read_config, types::ScriptType};
 
-use super::utils::*;
+use super::{script_type, utils::*};
 
-pub fn mock_signature(txn: &Transaction) -> Transaction {
+// fills every input with a dummy signature matching the script type it
+// spends, `spent[index]` being the address holding the utxo of input `index`.
+pub fn mock_signature(txn: &Transaction, spent: &[&Address]) -> Transaction {
     let pubkey = read_config(|config| {
         let ecdsa_key = config.ecdsa_public_key();
         let path = vec![];
@@ -20,20 +23,35 @@ pub fn mock_signature(txn: &Transaction) -> Transaction {
     let input = txn
         .input
         .iter()
-        .map(|input| {
+        .enumerate()
+        .map(|(index, input)| {
             let signature = vec![255; 64];
             let mut der_signature = sec1_to_der(signature);
             der_signature.push(EcdsaSighashType::All.to_u32() as u8);
-            let signature_as_pushbytes = PushBytesBuf::try_from(der_signature).unwrap();
-            let publickey_as_pushbytes = PushBytesBuf::try_from(pubkey.clone()).unwrap();
+            let (script_sig, witness) = match script_type(spent[index]) {
+                Some(ScriptType::P2wpkh) => (
+                    ScriptBuf::new(),
+                    Witness::from_slice(&[der_signature, pubkey.clone()]),
+                ),
+                // key path spend with the default sighash type, a bare 64 byte signature
+                Some(ScriptType::P2tr) => (ScriptBuf::new(), Witness::from_slice(&[vec![255; 64]])),
+                _ => {
+                    let signature_as_pushbytes = PushBytesBuf::try_from(der_signature).unwrap();
+                    let publickey_as_pushbytes = PushBytesBuf::try_from(pubkey.clone()).unwrap();
+                    (
+                        Builder::new()
+                            .push_slice(signature_as_pushbytes)
+                            .push_slice(publickey_as_pushbytes)
+                            .into_script(),
+                        Witness::new(),
+                    )
+                }
+            };
             TxIn {
                 previous_output: input.previous_output,
-                witness: Witness::new(),
+                witness,
                 sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
-                script_sig: Builder::new()
-                    .push_slice(signature_as_pushbytes)
-                    .push_slice(publickey_as_pushbytes)
-                    .into_script(),
+                script_sig,
             }
         })
         .collect::<Vec<TxIn>>();
@@ -60,3 +78,19 @@ pub async fn ecdsa_sign(
     .unwrap()
     .0
 }
+
+pub async fn schnorr_sign(
+    message: Vec<u8>,
+    derivation_path: Vec<Vec<u8>>,
+) -> SignWithSchnorrResponse {
+    let key_id = read_config(|config| config.schnorrkeyid());
+
+    sign_with_schnorr(SignWithSchnorrArgument {
+        message,
+        derivation_path,
+        key_id,
+    })
+    .await
+    .unwrap()
+    .0
+}
@@ -7,9 +7,15 @@ use icrc_ledger_types::icrc1::account::Account;
 
 use crate::{state::write_utxo_manager, txn_handler::TransactionType};
 
-use super::{signer::mock_signature, DUST_THRESHOLD};
+use super::{coin_selection::Strategy, signer::mock_signature, DUST_THRESHOLD};
 
+pub mod batch;
+pub mod consolidate;
+
+// builds the user-signed swaps
 pub mod combined;
+// both runes of a rune pair in one transaction
+pub mod rune_pair;
 
 pub struct BtcTransferArgs {
     pub sender: Address,
@@ -18,6 +24,7 @@ pub struct BtcTransferArgs {
     pub sender_account: Account,
     pub paid_by_sender: bool,
     pub fee_per_vbytes: u64,
+    pub strategy: Strategy,
 }
 
 pub fn transfer(
@@ -28,15 +35,25 @@ pub fn transfer(
         sender_account,
         paid_by_sender,
         fee_per_vbytes,
+        strategy,
     }: BtcTransferArgs,
 ) -> Result<TransactionType, u64> {
     let mut total_fee = 0;
     loop {
-        let (txn, utxos) =
-            build_transaction_with_fee(&sender, &receiver, amount, total_fee, paid_by_sender)?;
-        let signed_txn = mock_signature(&txn);
+        let (txn, utxos) = build_transaction_with_fee(
+            &sender,
+            &receiver,
+            amount,
+            total_fee,
+            paid_by_sender,
+            strategy,
+        )?;
+        let signed_txn = mock_signature(&txn, &vec![&sender; utxos.len()]);
         let txn_vsize = signed_txn.vsize() as u64;
-        if (txn_vsize * fee_per_vbytes) / 1000 == total_fee {
+        // a different selection may need a smaller fee, it only has to be
+        // covered so that the loop can't go back and forth
+        let required_fee = (txn_vsize * fee_per_vbytes) / 1000;
+        if required_fee <= total_fee {
             return Ok(TransactionType::Bitcoin {
                 txn,
                 utxos,
@@ -44,8 +61,8 @@ pub fn transfer(
                 sender_account,
             });
         } else {
-            write_utxo_manager(|state| state.record_btc_utxos(sender.to_string().as_str(), utxos));
-            total_fee = (txn_vsize * fee_per_vbytes) / 1000;
+            write_utxo_manager(|state| state.release_btc_utxos(sender.to_string().as_str(), utxos));
+            total_fee = required_fee;
         }
     }
 }
@@ -56,32 +73,20 @@ fn build_transaction_with_fee(
     amount: u64,
     fee: u64,
     paid_by_sender: bool,
+    strategy: Strategy,
 ) -> Result<(Transaction, Vec<Utxo>), u64> {
     let total_amount = if paid_by_sender { amount + fee } else { amount };
 
-    let (utxos_to_spend, total_spent) = write_utxo_manager(|manager| {
-        let addr = sender.to_string();
-        let mut utxos = vec![];
-        let mut sum = 0;
-
-        while let Some(utxo) = manager.get_bitcoin_utxo(&addr) {
-            sum += utxo.value;
-            utxos.push(utxo);
-            if sum > total_amount {
-                break;
-            }
-        }
-        if sum < total_amount {
-            manager.record_btc_utxos(&addr, utxos);
-            return Err(total_amount);
-        }
-        Ok((utxos, sum))
-    })?;
+    let utxos_to_spend = write_utxo_manager(|manager| {
+        manager.select_bitcoin_utxos(&sender.to_string(), total_amount, strategy)
+    })
+    .ok_or(total_amount)?;
+    let total_spent = utxos_to_spend.iter().fold(0, |sum, utxo| sum + utxo.value);
 
     let input: Vec<TxIn> = utxos_to_spend
         .iter()
         .map(|utxo| TxIn {
-            sequence: Sequence::MAX,
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
             script_sig: ScriptBuf::new(),
             witness: Witness::new(),
             previous_output: OutPoint {
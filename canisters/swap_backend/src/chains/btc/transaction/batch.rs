@@ -0,0 +1,474 @@
+use bitcoin::{
+    absolute::LockTime, hashes::Hash, transaction::Version, Address, Amount, OutPoint, ScriptBuf,
+    Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+};
+use icrc_ledger_types::icrc1::account::Account;
+use ordinals::{Edict, Runestone};
+
+use crate::{
+    chains::{
+        account_of_address,
+        btc::{
+            address_validation,
+            coin_selection::Strategy,
+            runestone::{validation::Allocation, DEFAULT_POSTAGE},
+            signer::mock_signature,
+            DUST_THRESHOLD,
+        },
+    },
+    state::{read_utxo_manager, txn_manager::SpentInput, write_utxo_manager},
+    txn_handler::{release_inputs, TransactionType},
+    types::RuneId,
+};
+
+pub struct BatchPayout {
+    pub id: u64,
+    pub to: Address,
+    // none for bitcoin
+    pub rune: Option<RuneId>,
+    pub amount: u128,
+}
+
+// the payouts of a pool. they're funded by whichever of the canister's
+// addresses hold enough, the pool's address first, and every change output
+// of the group goes back to the pool's address.
+pub struct PayoutGroup {
+    pub change: Address,
+    pub payouts: Vec<BatchPayout>,
+}
+
+#[derive(Debug)]
+pub enum BatchError {
+    // the bitcoin payout can't carry its share of the fee
+    TooSmall(u64),
+    // the canister's addresses can't fund the group at that index
+    Unfunded(usize),
+}
+
+struct Funding {
+    inputs: Vec<SpentInput>,
+    signers: Vec<(Address, Account)>,
+    // the runes every edict hands out, which is all of the inputs'
+    allocations: Vec<Allocation>,
+}
+
+impl Funding {
+    fn push(&mut self, address: &Address, account: Account, input: SpentInput) {
+        self.inputs.push(input);
+        self.signers.push((address.clone(), account));
+    }
+
+    fn value(&self) -> u64 {
+        self.inputs
+            .iter()
+            .fold(0, |total, input| total + input.utxo.value)
+    }
+}
+
+fn to_input(input: &SpentInput) -> TxIn {
+    TxIn {
+        script_sig: ScriptBuf::new(),
+        witness: Witness::new(),
+        sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+        previous_output: OutPoint {
+            txid: Txid::from_raw_hash(Hash::from_slice(&input.utxo.outpoint.txid).unwrap()),
+            vout: input.utxo.outpoint.vout,
+        },
+    }
+}
+
+fn source(addr: &str) -> Option<(Address, Account)> {
+    Some((address_validation(addr).ok()?, account_of_address(addr)?))
+}
+
+// adds the inputs and outputs of a group, every payout carrying `share` of
+// the fee. bitcoin payouts have it taken out of what they send, the
+// postage and share of rune payouts are paid by the group's sources.
+fn fund_group(
+    index: usize,
+    group: &PayoutGroup,
+    share: u64,
+    strategy: Strategy,
+    output: &mut Vec<TxOut>,
+    edicts: &mut Vec<Edict>,
+) -> Result<Funding, BatchError> {
+    let change = group.change.to_string();
+    let postage = Amount::from_sat(DEFAULT_POSTAGE);
+    let mut funding = Funding {
+        inputs: vec![],
+        signers: vec![],
+        allocations: vec![],
+    };
+
+    let mut runes: Vec<(RuneId, u128)> = vec![];
+    for payout in group.payouts.iter() {
+        if let Some(rune) = &payout.rune {
+            match runes.iter_mut().find(|(id, _)| id == rune) {
+                Some((_, total)) => *total += payout.amount,
+                None => runes.push((rune.clone(), payout.amount)),
+            }
+        }
+    }
+    let mut leftovers = vec![];
+    for (rune, total) in runes {
+        let selected = read_utxo_manager(|manager| {
+            manager.address_with_runestone_balance(&rune, total, &change)
+        })
+        .and_then(|addr| source(&addr))
+        .and_then(|(address, account)| {
+            write_utxo_manager(|manager| {
+                manager.select_runic_utxos(&address.to_string(), &rune, total, strategy)
+            })
+            .map(|utxos| (address, account, utxos))
+        });
+        let Some((address, account, utxos)) = selected else {
+            release_inputs(funding.inputs);
+            return Err(BatchError::Unfunded(index));
+        };
+        let selected_total = utxos.iter().fold(0, |total, utxo| total + utxo.balance);
+        for utxo in utxos {
+            let input = SpentInput {
+                address: address.to_string(),
+                utxo: utxo.utxo,
+                rune: Some((rune.clone(), utxo.balance)),
+            };
+            funding.push(&address, account, input);
+        }
+        if selected_total > total {
+            leftovers.push((rune, selected_total - total));
+        }
+    }
+
+    let rune_payouts = group
+        .payouts
+        .iter()
+        .filter(|payout| payout.rune.is_some())
+        .count() as u64;
+    let btc_payouts = group
+        .payouts
+        .iter()
+        .filter(|payout| payout.rune.is_none())
+        .fold(0, |total, payout| total + payout.amount as u64);
+    let needed = btc_payouts
+        + share * rune_payouts
+        + postage.to_sat() * (rune_payouts + leftovers.len() as u64);
+    let runic_sats = funding.value();
+    let required = needed.saturating_sub(runic_sats);
+    if required > 0 {
+        let selected =
+            read_utxo_manager(|manager| manager.address_with_bitcoin_balance(required, &change))
+                .and_then(|addr| source(&addr))
+                .and_then(|(address, account)| {
+                    write_utxo_manager(|manager| {
+                        manager.select_bitcoin_utxos(&address.to_string(), required, strategy)
+                    })
+                    .map(|utxos| (address, account, utxos))
+                });
+        let Some((address, account, utxos)) = selected else {
+            release_inputs(funding.inputs);
+            return Err(BatchError::Unfunded(index));
+        };
+        for utxo in utxos {
+            let input = SpentInput {
+                address: address.to_string(),
+                utxo,
+                rune: None,
+            };
+            funding.push(&address, account, input);
+        }
+    }
+
+    let remaining = funding.value() - needed;
+    if let Err(err) = lay_out_group(group, share, leftovers, remaining, output, edicts) {
+        release_inputs(funding.inputs);
+        return Err(err);
+    }
+    Ok(funding)
+}
+
+// appends the outputs and edicts of a funded group: runes to the payouts,
+// then the rune change, then bitcoin and what's `remaining` of the funding
+fn lay_out_group(
+    group: &PayoutGroup,
+    share: u64,
+    leftovers: Vec<(RuneId, u128)>,
+    remaining: u64,
+    output: &mut Vec<TxOut>,
+    edicts: &mut Vec<Edict>,
+) -> Result<(), BatchError> {
+    let postage = Amount::from_sat(DEFAULT_POSTAGE);
+    for payout in group.payouts.iter() {
+        if let Some(rune) = &payout.rune {
+            edicts.push(Edict {
+                id: ordinals::RuneId {
+                    block: rune.block,
+                    tx: rune.tx,
+                },
+                amount: payout.amount,
+                output: output.len() as u32,
+            });
+            output.push(TxOut {
+                value: postage,
+                script_pubkey: payout.to.script_pubkey(),
+            });
+        }
+    }
+    for (rune, leftover) in leftovers {
+        edicts.push(Edict {
+            id: ordinals::RuneId {
+                block: rune.block,
+                tx: rune.tx,
+            },
+            amount: leftover,
+            output: output.len() as u32,
+        });
+        output.push(TxOut {
+            value: postage,
+            script_pubkey: group.change.script_pubkey(),
+        });
+    }
+    for payout in group.payouts.iter() {
+        if payout.rune.is_none() {
+            let amount = payout.amount as u64;
+            if amount < share + DUST_THRESHOLD {
+                return Err(BatchError::TooSmall(payout.id));
+            }
+            output.push(TxOut {
+                value: Amount::from_sat(amount - share),
+                script_pubkey: payout.to.script_pubkey(),
+            });
+        }
+    }
+    if remaining > DUST_THRESHOLD {
+        output.push(TxOut {
+            value: Amount::from_sat(remaining),
+            script_pubkey: group.change.script_pubkey(),
+        });
+    }
+    Ok(())
+}
+
+fn build_with_share(
+    groups: &[PayoutGroup],
+    share: u64,
+    strategy: Strategy,
+) -> Result<(Transaction, Funding), BatchError> {
+    let has_runes = groups
+        .iter()
+        .flat_map(|group| group.payouts.iter())
+        .any(|payout| payout.rune.is_some());
+    // 0: runestone, once every edict is known
+    let mut output = vec![];
+    if has_runes {
+        output.push(TxOut {
+            value: Amount::from_sat(0),
+            script_pubkey: ScriptBuf::new(),
+        });
+    }
+    let mut edicts = vec![];
+    let mut funding = Funding {
+        inputs: vec![],
+        signers: vec![],
+        allocations: vec![],
+    };
+    for (index, group) in groups.iter().enumerate() {
+        match fund_group(index, group, share, strategy, &mut output, &mut edicts) {
+            Ok(group_funding) => {
+                funding.inputs.extend(group_funding.inputs);
+                funding.signers.extend(group_funding.signers);
+            }
+            Err(err) => {
+                release_inputs(funding.inputs);
+                return Err(err);
+            }
+        }
+    }
+    if has_runes {
+        funding.allocations = edicts
+            .iter()
+            .map(|edict| Allocation {
+                vout: edict.output,
+                runeid: RuneId {
+                    block: edict.id.block,
+                    tx: edict.id.tx,
+                },
+                amount: edict.amount,
+            })
+            .collect();
+        output[0].script_pubkey = Runestone {
+            edicts,
+            ..Default::default()
+        }
+        .encipher();
+    }
+
+    let txn = Transaction {
+        input: funding.inputs.iter().map(to_input).collect(),
+        output,
+        version: Version(2),
+        lock_time: LockTime::ZERO,
+    };
+    Ok((txn, funding))
+}
+
+// a single transaction settling every payout of `groups`, along with the
+// share of the fee each payout carries
+pub fn build(
+    groups: &[PayoutGroup],
+    fee_per_vbytes: u64,
+    strategy: Strategy,
+) -> Result<(TransactionType, u64), BatchError> {
+    let count = groups
+        .iter()
+        .map(|group| group.payouts.len() as u64)
+        .sum::<u64>()
+        .max(1);
+    let mut share = 0;
+    loop {
+        let (txn, funding) = build_with_share(groups, share, strategy)?;
+        let owners = funding
+            .signers
+            .iter()
+            .map(|(address, _)| address)
+            .collect::<Vec<_>>();
+        let required_fee = mock_signature(&txn, &owners).vsize() as u64 * fee_per_vbytes / 1000;
+        if required_fee <= share * count {
+            return Ok((
+                TransactionType::Batch {
+                    txn,
+                    inputs: funding.inputs,
+                    signers: funding.signers,
+                    change: groups.iter().map(|group| group.change.clone()).collect(),
+                    allocations: funding.allocations,
+                },
+                share,
+            ));
+        }
+        release_inputs(funding.inputs);
+        share = required_fee.div_ceil(count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::Network;
+
+    use super::*;
+
+    fn address(seed: u8) -> Address {
+        Address::p2wsh(&ScriptBuf::from(vec![seed]), Network::Bitcoin)
+    }
+
+    fn rune(tx: u32) -> RuneId {
+        RuneId { block: 840_000, tx }
+    }
+
+    fn payout(id: u64, seed: u8, rune: Option<RuneId>, amount: u128) -> BatchPayout {
+        BatchPayout {
+            id,
+            to: address(seed),
+            rune,
+            amount,
+        }
+    }
+
+    fn edicts_of(edicts: &[Edict]) -> Vec<(u32, u128, u32)> {
+        edicts
+            .iter()
+            .map(|edict| (edict.id.tx, edict.amount, edict.output))
+            .collect()
+    }
+
+    #[test]
+    fn runes_then_rune_change_then_bitcoin() {
+        let group = PayoutGroup {
+            change: address(100),
+            payouts: vec![
+                payout(0, 1, Some(rune(1)), 30),
+                payout(1, 2, None, 5_000),
+                payout(2, 3, Some(rune(2)), 10),
+                payout(3, 4, Some(rune(1)), 20),
+            ],
+        };
+        // the runestone's placeholder
+        let mut output = vec![TxOut {
+            value: Amount::from_sat(0),
+            script_pubkey: ScriptBuf::new(),
+        }];
+        let mut edicts = vec![];
+        lay_out_group(
+            &group,
+            200,
+            vec![(rune(1), 50)],
+            7_000,
+            &mut output,
+            &mut edicts,
+        )
+        .unwrap();
+
+        let laid_out = output
+            .iter()
+            .skip(1)
+            .map(|output| (output.script_pubkey.clone(), output.value.to_sat()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            laid_out,
+            vec![
+                (address(1).script_pubkey(), DEFAULT_POSTAGE),
+                (address(3).script_pubkey(), DEFAULT_POSTAGE),
+                (address(4).script_pubkey(), DEFAULT_POSTAGE),
+                (address(100).script_pubkey(), DEFAULT_POSTAGE),
+                (address(2).script_pubkey(), 4_800),
+                (address(100).script_pubkey(), 7_000),
+            ]
+        );
+        assert_eq!(
+            edicts_of(&edicts),
+            vec![(1, 30, 1), (2, 10, 2), (1, 20, 3), (1, 50, 4)]
+        );
+    }
+
+    #[test]
+    fn groups_follow_one_another() {
+        let first = PayoutGroup {
+            change: address(100),
+            payouts: vec![payout(0, 1, Some(rune(1)), 30)],
+        };
+        let second = PayoutGroup {
+            change: address(101),
+            payouts: vec![payout(1, 2, Some(rune(2)), 10)],
+        };
+        let (mut output, mut edicts) = (vec![], vec![]);
+        lay_out_group(&first, 0, vec![], 0, &mut output, &mut edicts).unwrap();
+        lay_out_group(&second, 0, vec![(rune(2), 5)], 0, &mut output, &mut edicts).unwrap();
+        assert_eq!(output.len(), 3);
+        assert_eq!(edicts_of(&edicts), vec![(1, 30, 0), (2, 10, 1), (2, 5, 2)]);
+        assert_eq!(output[2].script_pubkey, address(101).script_pubkey());
+    }
+
+    #[test]
+    fn dust_change_stays_with_the_fee() {
+        let group = PayoutGroup {
+            change: address(100),
+            payouts: vec![payout(0, 1, None, 5_000)],
+        };
+        let (mut output, mut edicts) = (vec![], vec![]);
+        lay_out_group(&group, 0, vec![], DUST_THRESHOLD, &mut output, &mut edicts).unwrap();
+        assert_eq!(output.len(), 1);
+        assert!(edicts.is_empty());
+    }
+
+    #[test]
+    fn bitcoin_payout_must_carry_its_share() {
+        let group = PayoutGroup {
+            change: address(100),
+            payouts: vec![
+                payout(0, 1, None, 5_000),
+                payout(7, 2, None, 200 + DUST_THRESHOLD as u128 - 1),
+            ],
+        };
+        let (mut output, mut edicts) = (vec![], vec![]);
+        let err = lay_out_group(&group, 200, vec![], 0, &mut output, &mut edicts).unwrap_err();
+        assert!(matches!(err, BatchError::TooSmall(7)));
+    }
+}
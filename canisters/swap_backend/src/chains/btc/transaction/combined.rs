@@ -7,12 +7,33 @@ use icrc_ledger_types::icrc1::account::Account;
 use ordinals::{Edict, Runestone};
 
 use crate::{
-    chains::btc::{runestone::DEFAULT_POSTAGE, signer::mock_signature, DUST_THRESHOLD},
+    chains::btc::{
+        coin_selection::{self, Strategy},
+        runestone::{validation::Allocation, DEFAULT_POSTAGE},
+        signer::mock_signature,
+        DUST_THRESHOLD,
+    },
     state::write_utxo_manager,
     txn_handler::TransactionType,
     types::{RuneId, RunicUtxo},
 };
 
+// utxos of an address the canister doesn't hold, spent through a psbt its
+// owner signs. they never go through the utxo manager.
+#[derive(Clone)]
+pub struct Wallet {
+    pub address: Address,
+    pub utxos: Vec<Utxo>,
+    // only the ones carrying nothing but the transferred rune
+    pub runic_utxos: Vec<RunicUtxo>,
+}
+
+impl Wallet {
+    fn owns(&self, addr: &Address) -> bool {
+        &self.address == addr
+    }
+}
+
 pub struct CombinedTransactionArgs {
     pub runeid: RuneId,
     pub rune_amount: u128,
@@ -27,6 +48,84 @@ pub struct CombinedTransactionArgs {
     pub fee_payer_account: Account,
     pub postage: Option<u64>,
     pub fee_per_vbytes: u64,
+    pub strategy: Strategy,
+    // spends the wallet's utxos for whichever party has its address
+    pub wallet: Option<Wallet>,
+}
+
+fn take_runic_utxos(
+    wallet: Option<&Wallet>,
+    addr: &Address,
+    runeid: &RuneId,
+    target: u128,
+    strategy: Strategy,
+) -> Option<Vec<RunicUtxo>> {
+    match wallet.filter(|wallet| wallet.owns(addr)) {
+        Some(wallet) => {
+            let amounts = wallet
+                .runic_utxos
+                .iter()
+                .map(|utxo| utxo.balance)
+                .collect::<Vec<_>>();
+            let selected = coin_selection::select(&amounts, target, 0, strategy)?;
+            Some(
+                selected
+                    .into_iter()
+                    .map(|index| wallet.runic_utxos[index].clone())
+                    .collect(),
+            )
+        }
+        None => write_utxo_manager(|manager| {
+            manager.select_runic_utxos(&addr.to_string(), runeid, target, strategy)
+        }),
+    }
+}
+
+fn take_bitcoin_utxos(
+    wallet: Option<&Wallet>,
+    addr: &Address,
+    target: u64,
+    strategy: Strategy,
+) -> Option<Vec<Utxo>> {
+    match wallet.filter(|wallet| wallet.owns(addr)) {
+        Some(wallet) => {
+            let amounts = wallet
+                .utxos
+                .iter()
+                .map(|utxo| utxo.value as u128)
+                .collect::<Vec<_>>();
+            let selected =
+                coin_selection::select(&amounts, target as u128, DUST_THRESHOLD as u128, strategy)?;
+            Some(
+                selected
+                    .into_iter()
+                    .map(|index| wallet.utxos[index].clone())
+                    .collect(),
+            )
+        }
+        None => write_utxo_manager(|manager| {
+            manager.select_bitcoin_utxos(&addr.to_string(), target, strategy)
+        }),
+    }
+}
+
+fn return_runic_utxos(
+    wallet: Option<&Wallet>,
+    addr: &Address,
+    runeid: &RuneId,
+    utxos: Vec<RunicUtxo>,
+) {
+    if !wallet.is_some_and(|wallet| wallet.owns(addr)) {
+        write_utxo_manager(|manager| {
+            manager.release_runic_utxos(&addr.to_string(), runeid.clone(), utxos)
+        });
+    }
+}
+
+fn return_bitcoin_utxos(wallet: Option<&Wallet>, addr: &Address, utxos: Vec<Utxo>) {
+    if !wallet.is_some_and(|wallet| wallet.owns(addr)) {
+        write_utxo_manager(|manager| manager.release_btc_utxos(&addr.to_string(), utxos));
+    }
 }
 
 pub fn transfer(
@@ -44,6 +143,8 @@ pub fn transfer(
         fee_payer_account,
         postage,
         fee_per_vbytes,
+        strategy,
+        wallet,
     }: CombinedTransactionArgs,
 ) -> Result<TransactionType, (u128, u64, u64)> {
     let mut total_fee = 0;
@@ -60,21 +161,52 @@ pub fn transfer(
             total_fee,
             &fee_payer,
             postage,
+            strategy,
+            wallet.as_ref(),
         )?;
 
-        let signed_txn = mock_signature(&txn);
+        let spent = std::iter::repeat_n(&rune_sender, runic_utxos.len())
+            .chain(std::iter::repeat_n(&bitcoin_sender, btc_utxos.len()))
+            .chain(std::iter::repeat_n(&fee_payer, fee_utxos.len()))
+            .collect::<Vec<_>>();
+        let signed_txn = mock_signature(&txn, &spent);
         let txn_vsize = signed_txn.vsize() as u64;
-        if (txn_vsize * fee_per_vbytes) / 1000 == total_fee {
+        let required_fee = (txn_vsize * fee_per_vbytes) / 1000;
+        if required_fee <= total_fee {
+            // with rune change the runestone comes first, the change second
+            // and the receiver third. without, the receiver is the first
+            // output and gets every rune of the inputs.
+            let allocations = if txn.output[0].script_pubkey.is_op_return() {
+                let runic_total = runic_utxos
+                    .iter()
+                    .fold(0, |total, utxo| total + utxo.balance);
+                vec![
+                    Allocation {
+                        vout: 1,
+                        runeid: runeid.clone(),
+                        amount: runic_total - rune_amount,
+                    },
+                    Allocation {
+                        vout: 2,
+                        runeid: runeid.clone(),
+                        amount: rune_amount,
+                    },
+                ]
+            } else {
+                vec![Allocation {
+                    vout: 0,
+                    runeid: runeid.clone(),
+                    amount: rune_amount,
+                }]
+            };
             return Ok(TransactionType::Combined {
+                txn,
                 runeid,
-                rune_amount,
                 rune_sender: Box::new(rune_sender),
-                rune_receiver: Box::new(rune_receiver),
                 rune_sender_account,
                 runic_utxos,
                 btc_amount,
                 btc_sender: Box::new(bitcoin_sender),
-                btc_receiver: Box::new(bitcoin_receiver),
                 btc_sender_account: bitcoin_sender_account,
                 btc_utxos,
                 fee: total_fee,
@@ -82,18 +214,14 @@ pub fn transfer(
                 fee_utxos,
                 fee_payer: Box::new(fee_payer),
                 postage,
+                allocations,
             });
         } else {
-            write_utxo_manager(|manager| {
-                manager.record_runic_utxos(
-                    rune_sender.to_string().as_str(),
-                    runeid.clone(),
-                    runic_utxos,
-                );
-                manager.record_btc_utxos(bitcoin_sender.to_string().as_str(), btc_utxos);
-                manager.record_btc_utxos(fee_payer.to_string().as_str(), fee_utxos);
-            });
-            total_fee = (txn_vsize * fee_per_vbytes) / 1000;
+            let wallet = wallet.as_ref();
+            return_runic_utxos(wallet, &rune_sender, &runeid, runic_utxos);
+            return_bitcoin_utxos(wallet, &bitcoin_sender, btc_utxos);
+            return_bitcoin_utxos(wallet, &fee_payer, fee_utxos);
+            total_fee = required_fee;
         }
     }
 }
@@ -109,33 +237,26 @@ fn build_transaction_with_fee(
     fee: u64,
     fee_payer: &Address,
     postage: Amount,
+    strategy: Strategy,
+    wallet: Option<&Wallet>,
 ) -> Result<(Transaction, Vec<RunicUtxo>, Vec<Utxo>, Vec<Utxo>), (u128, u64, u64)> {
     let (mut input, mut output) = (vec![], vec![]);
 
-    let (runic_utxos, runic_total_spent, btc_in_runic_spent) = write_utxo_manager(|manager| {
-        let addr = rune_sender.to_string();
-        let (mut utxos, mut r_total_spent, mut b_total_spent) = (vec![], 0, 0);
-
-        while let Some(utxo) = manager.get_runic_utxo(&addr, runeid.clone()) {
-            r_total_spent += utxo.balance;
-            b_total_spent += utxo.utxo.value;
-            utxos.push(utxo);
-            if r_total_spent > rune_amount {
-                break;
-            }
-        }
+    let runic_utxos = take_runic_utxos(wallet, rune_sender, runeid, rune_amount, strategy)
+        .ok_or((rune_amount, btc_amount, fee))?;
+    let (runic_total_spent, btc_in_runic_spent) =
+        runic_utxos.iter().fold((0, 0), |(runes, sats), utxo| {
+            (runes + utxo.balance, sats + utxo.utxo.value)
+        });
 
-        if r_total_spent < rune_amount {
-            manager.record_runic_utxos(&addr, runeid.clone(), utxos);
-            return Err((rune_amount, btc_amount, fee));
+    // sats of the runic utxos beyond the postage go back to the rune sender
+    // along with its rune change
+    let (need_rune_change_output, required_postage_btc, rune_change_value) = {
+        let mut need_rune_change_output = runic_total_spent > rune_amount || runic_utxos.len() > 1;
+        if btc_in_runic_spent > postage.to_sat() {
+            need_rune_change_output = true;
         }
-
-        Ok((utxos, r_total_spent, b_total_spent))
-    })?;
-
-    let (need_rune_change_output, required_postage_btc) = {
-        let need_rune_change_output = runic_total_spent > rune_amount || runic_utxos.len() > 1;
-        let required_postage_btc = if need_rune_change_output {
+        let postage_btc = if need_rune_change_output {
             postage.to_sat() * 2
         } else {
             postage.to_sat()
@@ -143,71 +264,36 @@ fn build_transaction_with_fee(
 
         (
             need_rune_change_output,
-            required_postage_btc - btc_in_runic_spent,
+            postage_btc.saturating_sub(btc_in_runic_spent),
+            postage.to_sat() + btc_in_runic_spent.saturating_sub(postage_btc),
         )
     };
 
-    let (mut btc_utxos, mut btc_total_spent) = write_utxo_manager(|manager| {
-        let addr = btc_sender.to_string();
-        let (mut utxos, mut total_spent) = (vec![], 0);
-
-        while let Some(utxo) = manager.get_bitcoin_utxo(&addr) {
-            total_spent += utxo.value;
-            utxos.push(utxo);
-            if total_spent > btc_amount {
-                break;
-            }
-        }
-        if total_spent < btc_amount {
-            manager.record_runic_utxos(
-                &rune_sender.to_string(),
-                runeid.clone(),
-                runic_utxos.clone(),
-            );
-            manager.record_btc_utxos(&addr, utxos);
-            return Err((rune_amount, btc_amount, fee));
-        }
-        Ok((utxos, total_spent))
-    })?;
-
-    let (fee_utxos, fee_total_spent) = write_utxo_manager(|manager| {
-        let addr = fee_payer.to_string();
-        let (mut utxos, mut total_spent) = (vec![], 0);
-
-        if fee_payer != btc_sender {
-            while let Some(utxo) = manager.get_bitcoin_utxo(&addr) {
-                total_spent += utxo.value;
-                utxos.push(utxo);
-                if total_spent > fee + required_postage_btc {
-                    break;
-                }
-            }
-
-            if total_spent < fee + required_postage_btc {
-                manager.record_runic_utxos(
-                    &rune_sender.to_string(),
-                    runeid.clone(),
-                    runic_utxos.clone(),
-                );
-                manager.record_btc_utxos(&btc_sender.to_string(), btc_utxos.clone());
-                manager.record_btc_utxos(&addr, utxos);
-                return Err((rune_amount, btc_amount, fee + required_postage_btc));
-            }
-        } else {
-            while let Some(utxo) = manager.get_bitcoin_utxo(&addr) {
-                btc_total_spent += utxo.value;
-                btc_utxos.push(utxo);
-                if btc_total_spent > btc_amount + fee + required_postage_btc {
-                    break;
-                }
-            }
-            if btc_total_spent < btc_amount + fee + required_postage_btc {
-                return Err((rune_amount, btc_amount + fee + required_postage_btc, 0));
-            }
-        }
-
-        Ok((utxos, total_spent))
-    })?;
+    // when the bitcoin sender pays the fee as well, a single selection
+    // covers both
+    let btc_target = if fee_payer == btc_sender {
+        btc_amount + fee + required_postage_btc
+    } else {
+        btc_amount
+    };
+    let Some(btc_utxos) = take_bitcoin_utxos(wallet, btc_sender, btc_target, strategy) else {
+        return_runic_utxos(wallet, rune_sender, runeid, runic_utxos);
+        return Err((rune_amount, btc_target, 0));
+    };
+    let fee_utxos = if fee_payer == btc_sender {
+        vec![]
+    } else {
+        let Some(fee_utxos) =
+            take_bitcoin_utxos(wallet, fee_payer, fee + required_postage_btc, strategy)
+        else {
+            return_runic_utxos(wallet, rune_sender, runeid, runic_utxos);
+            return_bitcoin_utxos(wallet, btc_sender, btc_utxos);
+            return Err((rune_amount, btc_amount, fee + required_postage_btc));
+        };
+        fee_utxos
+    };
+    let btc_total_spent = btc_utxos.iter().fold(0, |sum, utxo| sum + utxo.value);
+    let fee_total_spent = fee_utxos.iter().fold(0, |sum, utxo| sum + utxo.value);
 
     // transaction's input
 
@@ -217,7 +303,7 @@ fn build_transaction_with_fee(
             input.push(TxIn {
                 script_sig: ScriptBuf::new(),
                 witness: Witness::new(),
-                sequence: Sequence::MAX,
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
                 previous_output: OutPoint {
                     txid: Txid::from_raw_hash(Hash::from_slice(&utxo.outpoint.txid).unwrap()),
                     vout: utxo.outpoint.vout,
@@ -229,7 +315,7 @@ fn build_transaction_with_fee(
         input.push(TxIn {
             script_sig: ScriptBuf::new(),
             witness: Witness::new(),
-            sequence: Sequence::MAX,
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
             previous_output: OutPoint {
                 txid: Txid::from_raw_hash(Hash::from_slice(&utxo.outpoint.txid).unwrap()),
                 vout: utxo.outpoint.vout,
@@ -241,7 +327,7 @@ fn build_transaction_with_fee(
         input.push(TxIn {
             script_sig: ScriptBuf::new(),
             witness: Witness::new(),
-            sequence: Sequence::MAX,
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
             previous_output: OutPoint {
                 txid: Txid::from_raw_hash(Hash::from_slice(&utxo.outpoint.txid).unwrap()),
                 vout: utxo.outpoint.vout,
@@ -271,7 +357,7 @@ fn build_transaction_with_fee(
             script_pubkey: runestone.encipher(),
         });
         output.push(TxOut {
-            value: postage,
+            value: Amount::from_sat(rune_change_value),
             script_pubkey: rune_sender.script_pubkey(),
         });
         output.push(TxOut {
@@ -287,34 +373,28 @@ fn build_transaction_with_fee(
 
     // btc
 
-    if fee_payer == btc_sender {
-        let remaining = btc_total_spent - btc_amount - fee - required_postage_btc;
+    output.push(TxOut {
+        value: Amount::from_sat(btc_amount),
+        script_pubkey: btc_receiver.script_pubkey(),
+    });
+    let remaining = btc_total_spent - btc_target;
+    if remaining > DUST_THRESHOLD {
         output.push(TxOut {
-            value: Amount::from_sat(btc_amount),
-            script_pubkey: btc_receiver.script_pubkey(),
+            value: Amount::from_sat(remaining),
+            script_pubkey: btc_sender.script_pubkey(),
         });
+    }
+
+    // fee
 
+    if fee_payer != btc_sender {
+        let remaining = fee_total_spent - fee - required_postage_btc;
         if remaining > DUST_THRESHOLD {
             output.push(TxOut {
                 value: Amount::from_sat(remaining),
-                script_pubkey: btc_sender.script_pubkey(),
+                script_pubkey: fee_payer.script_pubkey(),
             });
         }
-    } else {
-        output.push(TxOut {
-            value: Amount::from_sat(btc_amount),
-            script_pubkey: btc_receiver.script_pubkey(),
-        });
-    }
-
-    // fee
-
-    let remaining = fee_total_spent - fee - required_postage_btc;
-    if remaining > DUST_THRESHOLD {
-        output.push(TxOut {
-            value: Amount::from_sat(remaining),
-            script_pubkey: fee_payer.script_pubkey(),
-        });
     }
 
     let txn = Transaction {
@@ -0,0 +1,194 @@
+use bitcoin::{
+    absolute::LockTime, hashes::Hash, transaction::Version, Address, Amount, OutPoint, ScriptBuf,
+    Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+};
+use ic_cdk::api::management_canister::bitcoin::Utxo;
+use icrc_ledger_types::icrc1::account::Account;
+use ordinals::Runestone;
+
+use crate::{
+    chains::btc::{
+        coin_selection::Strategy,
+        runestone::{validation::Allocation, DEFAULT_POSTAGE},
+        signer::mock_signature,
+        DUST_THRESHOLD,
+    },
+    state::write_utxo_manager,
+    txn_handler::TransactionType,
+    types::{RuneId, RunicUtxo},
+};
+
+// fewer utxos than this aren't worth a transaction
+pub const MIN_UTXOS_TO_CONSOLIDATE: usize = 10;
+// keeps a consolidation well within the standard transaction size
+pub const MAX_UTXOS_TO_CONSOLIDATE: usize = 100;
+
+pub struct ConsolidationArgs {
+    pub addr: Address,
+    pub account: Account,
+    pub fee_per_vbytes: u64,
+}
+
+fn to_input(utxo: &Utxo) -> TxIn {
+    TxIn {
+        script_sig: ScriptBuf::new(),
+        witness: Witness::new(),
+        sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+        previous_output: OutPoint {
+            txid: Txid::from_raw_hash(Hash::from_slice(&utxo.outpoint.txid).unwrap()),
+            vout: utxo.outpoint.vout,
+        },
+    }
+}
+
+// merges the smallest bitcoin utxos of `addr` into a single output paying
+// back to it. returns none if there's too few of them or they'd mostly go
+// to fees.
+pub fn consolidate_bitcoin(
+    ConsolidationArgs {
+        addr,
+        account,
+        fee_per_vbytes,
+    }: ConsolidationArgs,
+) -> Option<TransactionType> {
+    let utxos = write_utxo_manager(|manager| {
+        manager.take_smallest_bitcoin_utxos(&addr.to_string(), MAX_UTXOS_TO_CONSOLIDATE)
+    });
+    let total = utxos.iter().fold(0, |total, utxo| total + utxo.value);
+
+    let mut txn = Transaction {
+        input: utxos.iter().map(to_input).collect(),
+        output: vec![TxOut {
+            script_pubkey: addr.script_pubkey(),
+            value: Amount::from_sat(total),
+        }],
+        version: Version(2),
+        lock_time: LockTime::ZERO,
+    };
+    let signed_txn = mock_signature(&txn, &vec![&addr; utxos.len()]);
+    let fee = (signed_txn.vsize() as u64 * fee_per_vbytes) / 1000;
+
+    if utxos.len() < MIN_UTXOS_TO_CONSOLIDATE || total < fee + DUST_THRESHOLD {
+        write_utxo_manager(|manager| manager.release_btc_utxos(&addr.to_string(), utxos));
+        return None;
+    }
+    txn.output[0].value = Amount::from_sat(total - fee);
+
+    Some(TransactionType::Bitcoin {
+        txn,
+        utxos,
+        sender: addr,
+        sender_account: account,
+    })
+}
+
+// merges the runic utxos of `addr` holding only `runeid` into one postage
+// output. the runestone carries no edicts, its pointer hands every rune of
+// the inputs to that output. sats beyond the postage pay the fee and come
+// back as change, topped up from the bitcoin utxos of `addr` if short.
+pub fn consolidate_runes(
+    ConsolidationArgs {
+        addr,
+        account,
+        fee_per_vbytes,
+    }: ConsolidationArgs,
+    runeid: RuneId,
+) -> Option<TransactionType> {
+    let runic_utxos = write_utxo_manager(|manager| {
+        manager.take_smallest_runic_utxos(&addr.to_string(), &runeid, MAX_UTXOS_TO_CONSOLIDATE)
+    });
+    if runic_utxos.len() < MIN_UTXOS_TO_CONSOLIDATE {
+        write_utxo_manager(|manager| {
+            manager.release_runic_utxos(&addr.to_string(), runeid, runic_utxos)
+        });
+        return None;
+    }
+    let postage = Amount::from_sat(DEFAULT_POSTAGE);
+    let runic_sats = runic_utxos
+        .iter()
+        .fold(0, |total, RunicUtxo { utxo, .. }| total + utxo.value);
+
+    let mut total_fee = 0;
+    loop {
+        let required = (postage.to_sat() + total_fee).saturating_sub(runic_sats);
+        let fee_utxos = if required == 0 {
+            Some(vec![])
+        } else {
+            write_utxo_manager(|manager| {
+                manager.select_bitcoin_utxos(&addr.to_string(), required, Strategy::LargestFirst)
+            })
+        };
+        let Some(fee_utxos) = fee_utxos else {
+            write_utxo_manager(|manager| {
+                manager.release_runic_utxos(&addr.to_string(), runeid, runic_utxos)
+            });
+            return None;
+        };
+        let total = fee_utxos
+            .iter()
+            .fold(runic_sats, |total, utxo| total + utxo.value);
+
+        // 0: runestone
+        // 1: runes of every input
+        // 2: change (if above dust)
+        let runestone = Runestone {
+            pointer: Some(1),
+            ..Default::default()
+        };
+        let mut output = vec![
+            TxOut {
+                script_pubkey: runestone.encipher(),
+                value: Amount::from_sat(0),
+            },
+            TxOut {
+                script_pubkey: addr.script_pubkey(),
+                value: postage,
+            },
+        ];
+        let remaining = total - postage.to_sat() - total_fee;
+        if remaining > DUST_THRESHOLD {
+            output.push(TxOut {
+                script_pubkey: addr.script_pubkey(),
+                value: Amount::from_sat(remaining),
+            });
+        }
+
+        let txn = Transaction {
+            input: runic_utxos
+                .iter()
+                .map(|RunicUtxo { utxo, .. }| utxo)
+                .chain(fee_utxos.iter())
+                .map(to_input)
+                .collect(),
+            output,
+            version: Version(2),
+            lock_time: LockTime::ZERO,
+        };
+        let signed_txn = mock_signature(&txn, &vec![&addr; txn.input.len()]);
+        let required_fee = (signed_txn.vsize() as u64 * fee_per_vbytes) / 1000;
+        if required_fee <= total_fee {
+            let allocations = vec![Allocation {
+                vout: 1,
+                runeid: runeid.clone(),
+                amount: runic_utxos
+                    .iter()
+                    .fold(0, |total, utxo| total + utxo.balance),
+            }];
+            return Some(TransactionType::Rune {
+                txn,
+                rune: runeid,
+                runic_utxos,
+                sender: Box::new(addr.clone()),
+                sender_account: account,
+                fee: total_fee,
+                fee_payer: Box::new(addr),
+                fee_payer_account: account,
+                fee_utxos,
+                postage,
+                allocations,
+            });
+        }
+        write_utxo_manager(|manager| manager.release_btc_utxos(&addr.to_string(), fee_utxos));
+        total_fee = required_fee;
+    }
+}
@@ -0,0 +1,340 @@
+use bitcoin::{
+    absolute::LockTime, hashes::Hash, transaction::Version, Address, Amount, OutPoint, ScriptBuf,
+    Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+};
+use ic_cdk::api::management_canister::bitcoin::Utxo;
+use icrc_ledger_types::icrc1::account::Account;
+use ordinals::{Edict, Runestone};
+
+use crate::{
+    chains::btc::{
+        coin_selection::Strategy,
+        runestone::{validation::Allocation, DEFAULT_POSTAGE},
+        signer::mock_signature,
+        DUST_THRESHOLD,
+    },
+    state::write_utxo_manager,
+    txn_handler::TransactionType,
+    types::{RuneId, RunicUtxo},
+};
+
+pub struct RunePairTransactionArgs {
+    pub rune0: RuneId,
+    pub rune0_amount: u128,
+    pub rune0_sender: Address,
+    pub rune0_receiver: Address,
+    pub rune0_sender_account: Account,
+    pub rune1: RuneId,
+    pub rune1_amount: u128,
+    pub rune1_sender: Address,
+    pub rune1_receiver: Address,
+    pub rune1_sender_account: Account,
+    pub fee_payer: Address,
+    pub fee_payer_account: Account,
+    pub postage: Option<u64>,
+    pub fee_per_vbytes: u64,
+    pub strategy: Strategy,
+}
+
+pub fn transfer(
+    RunePairTransactionArgs {
+        rune0,
+        rune0_amount,
+        rune0_sender,
+        rune0_receiver,
+        rune0_sender_account,
+        rune1,
+        rune1_amount,
+        rune1_sender,
+        rune1_receiver,
+        rune1_sender_account,
+        fee_payer,
+        fee_payer_account,
+        postage,
+        fee_per_vbytes,
+        strategy,
+    }: RunePairTransactionArgs,
+) -> Result<TransactionType, (u128, u128, u64)> {
+    if rune0 == rune1 {
+        return Err((rune0_amount, rune1_amount, 0));
+    }
+    let mut total_fee = 0;
+    let postage = Amount::from_sat(postage.unwrap_or(DEFAULT_POSTAGE));
+    loop {
+        let (txn, rune0_utxos, rune1_utxos, fee_utxos) = build_transaction_with_fee(
+            (&rune0, rune0_amount, &rune0_sender, &rune0_receiver),
+            (&rune1, rune1_amount, &rune1_sender, &rune1_receiver),
+            &fee_payer,
+            postage,
+            total_fee,
+            strategy,
+        )?;
+
+        let spent = std::iter::repeat_n(&rune0_sender, rune0_utxos.len())
+            .chain(std::iter::repeat_n(&rune1_sender, rune1_utxos.len()))
+            .chain(std::iter::repeat_n(&fee_payer, fee_utxos.len()))
+            .collect::<Vec<_>>();
+        let signed_txn = mock_signature(&txn, &spent);
+        let txn_vsize = signed_txn.vsize() as u64;
+        let required_fee = (txn_vsize * fee_per_vbytes) / 1000;
+        if required_fee <= total_fee {
+            // receivers at 1 and 2, then the change of each sender that has some
+            let balance =
+                |utxos: &[RunicUtxo]| utxos.iter().fold(0, |total, utxo| total + utxo.balance);
+            let rune0_change = balance(&rune0_utxos) - rune0_amount;
+            let rune1_change = balance(&rune1_utxos) - rune1_amount;
+            let allocations = vec![
+                Allocation {
+                    vout: 1,
+                    runeid: rune0.clone(),
+                    amount: rune0_amount,
+                },
+                Allocation {
+                    vout: 2,
+                    runeid: rune1.clone(),
+                    amount: rune1_amount,
+                },
+                Allocation {
+                    vout: 3,
+                    runeid: rune0.clone(),
+                    amount: rune0_change,
+                },
+                Allocation {
+                    vout: 3 + (rune0_change > 0) as u32,
+                    runeid: rune1.clone(),
+                    amount: rune1_change,
+                },
+            ];
+            return Ok(TransactionType::RunePair {
+                txn,
+                rune0,
+                rune0_utxos,
+                rune0_sender: Box::new(rune0_sender),
+                rune0_sender_account,
+                rune1,
+                rune1_utxos,
+                rune1_sender: Box::new(rune1_sender),
+                rune1_sender_account,
+                fee_utxos,
+                fee_payer: Box::new(fee_payer),
+                fee_payer_account,
+                allocations,
+            });
+        } else {
+            write_utxo_manager(|manager| {
+                manager.release_runic_utxos(
+                    rune0_sender.to_string().as_str(),
+                    rune0.clone(),
+                    rune0_utxos,
+                );
+                manager.release_runic_utxos(
+                    rune1_sender.to_string().as_str(),
+                    rune1.clone(),
+                    rune1_utxos,
+                );
+                manager.release_btc_utxos(fee_payer.to_string().as_str(), fee_utxos);
+            });
+            total_fee = required_fee;
+        }
+    }
+}
+
+// selects runic utxos of `runeid` held by `addr` covering `amount`. returns
+// the utxos, total runes and total sats locked in them.
+fn collect_runic_utxos(
+    addr: &Address,
+    runeid: &RuneId,
+    amount: u128,
+    strategy: Strategy,
+) -> Option<(Vec<RunicUtxo>, u128, u64)> {
+    let utxos = write_utxo_manager(|manager| {
+        manager.select_runic_utxos(&addr.to_string(), runeid, amount, strategy)
+    })?;
+    let (r_total_spent, b_total_spent) = utxos.iter().fold((0, 0), |(runes, sats), utxo| {
+        (runes + utxo.balance, sats + utxo.utxo.value)
+    });
+    Some((utxos, r_total_spent, b_total_spent))
+}
+
+fn build_transaction_with_fee(
+    (rune0, rune0_amount, rune0_sender, rune0_receiver): (&RuneId, u128, &Address, &Address),
+    (rune1, rune1_amount, rune1_sender, rune1_receiver): (&RuneId, u128, &Address, &Address),
+    fee_payer: &Address,
+    postage: Amount,
+    fee: u64,
+    strategy: Strategy,
+) -> Result<(Transaction, Vec<RunicUtxo>, Vec<RunicUtxo>, Vec<Utxo>), (u128, u128, u64)> {
+    let (mut input, mut output) = (vec![], vec![]);
+
+    let (rune0_utxos, rune0_total_spent, btc_in_rune0_spent) =
+        collect_runic_utxos(rune0_sender, rune0, rune0_amount, strategy).ok_or((
+            rune0_amount,
+            rune1_amount,
+            fee,
+        ))?;
+
+    let (rune1_utxos, rune1_total_spent, btc_in_rune1_spent) =
+        match collect_runic_utxos(rune1_sender, rune1, rune1_amount, strategy) {
+            Some(collected) => collected,
+            None => {
+                write_utxo_manager(|manager| {
+                    manager.release_runic_utxos(
+                        &rune0_sender.to_string(),
+                        rune0.clone(),
+                        rune0_utxos,
+                    )
+                });
+                return Err((rune0_amount, rune1_amount, fee));
+            }
+        };
+
+    let rune0_change = rune0_total_spent - rune0_amount;
+    let rune1_change = rune1_total_spent - rune1_amount;
+
+    // one postage output for each receiver, plus one for every sender with change
+    let postage_outputs = 2 + (rune0_change > 0) as u64 + (rune1_change > 0) as u64;
+    let required_postage_btc = (postage.to_sat() * postage_outputs)
+        .saturating_sub(btc_in_rune0_spent + btc_in_rune1_spent);
+
+    let fee_utxos = write_utxo_manager(|manager| {
+        let fee_utxos = manager.select_bitcoin_utxos(
+            &fee_payer.to_string(),
+            fee + required_postage_btc,
+            strategy,
+        );
+        if fee_utxos.is_none() {
+            manager.release_runic_utxos(
+                &rune0_sender.to_string(),
+                rune0.clone(),
+                rune0_utxos.clone(),
+            );
+            manager.release_runic_utxos(
+                &rune1_sender.to_string(),
+                rune1.clone(),
+                rune1_utxos.clone(),
+            );
+        }
+        fee_utxos
+    })
+    .ok_or((rune0_amount, rune1_amount, fee + required_postage_btc))?;
+    let fee_total_spent = fee_utxos.iter().fold(0, |sum, utxo| sum + utxo.value);
+
+    // transaction's input
+
+    rune0_utxos
+        .iter()
+        .chain(rune1_utxos.iter())
+        .map(|RunicUtxo { utxo, balance: _ }| utxo)
+        .chain(fee_utxos.iter())
+        .for_each(|utxo| {
+            input.push(TxIn {
+                script_sig: ScriptBuf::new(),
+                witness: Witness::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                previous_output: OutPoint {
+                    txid: Txid::from_raw_hash(Hash::from_slice(&utxo.outpoint.txid).unwrap()),
+                    vout: utxo.outpoint.vout,
+                },
+            });
+        });
+
+    // transaction's output
+    //
+    // 0: runestone
+    // 1: rune0 receiver
+    // 2: rune1 receiver
+    // 3: rune0 sender's change (if any)
+    // 3|4: rune1 sender's change (if any)
+    // fee payer's change (if above dust)
+
+    let mut edicts = vec![
+        Edict {
+            id: ordinals::RuneId {
+                block: rune0.block,
+                tx: rune0.tx,
+            },
+            amount: rune0_amount,
+            output: 1,
+        },
+        Edict {
+            id: ordinals::RuneId {
+                block: rune1.block,
+                tx: rune1.tx,
+            },
+            amount: rune1_amount,
+            output: 2,
+        },
+    ];
+
+    output.push(TxOut {
+        value: postage,
+        script_pubkey: rune0_receiver.script_pubkey(),
+    });
+    output.push(TxOut {
+        value: postage,
+        script_pubkey: rune1_receiver.script_pubkey(),
+    });
+
+    // unallocated runes (rune0's change and anything else riding on the
+    // spent utxos) go to the pointer, so it has to be rune0's sender.
+    let mut pointer = None;
+    if rune0_change > 0 {
+        pointer = Some(output.len() as u32 + 1);
+        output.push(TxOut {
+            value: postage,
+            script_pubkey: rune0_sender.script_pubkey(),
+        });
+    }
+
+    if rune1_change > 0 {
+        let change_output = output.len() as u32 + 1;
+        edicts.push(Edict {
+            id: ordinals::RuneId {
+                block: rune1.block,
+                tx: rune1.tx,
+            },
+            amount: rune1_change,
+            output: change_output,
+        });
+        if pointer.is_none() {
+            pointer = Some(change_output);
+        }
+        output.push(TxOut {
+            value: postage,
+            script_pubkey: rune1_sender.script_pubkey(),
+        });
+    }
+
+    let runestone = Runestone {
+        edicts,
+        pointer,
+        ..Default::default()
+    };
+    output.insert(
+        0,
+        TxOut {
+            value: Amount::from_sat(0),
+            script_pubkey: runestone.encipher(),
+        },
+    );
+
+    // fee
+
+    let remaining = fee_total_spent + btc_in_rune0_spent + btc_in_rune1_spent
+        - fee
+        - postage.to_sat() * postage_outputs;
+    if remaining > DUST_THRESHOLD {
+        output.push(TxOut {
+            value: Amount::from_sat(remaining),
+            script_pubkey: fee_payer.script_pubkey(),
+        });
+    }
+
+    let txn = Transaction {
+        input,
+        output,
+        version: Version(2),
+        lock_time: LockTime::ZERO,
+    };
+    Ok((txn, rune0_utxos, rune1_utxos, fee_utxos))
+}
@@ -1,5 +1,5 @@
 use crate::EcdsaPublicKey;
-use ic_crypto_secp256k1::{DerivationIndex, DerivationPath, PublicKey};
+use ic_secp256k1::{DerivationIndex, DerivationPath, PublicKey};
 use icrc_ledger_types::icrc1::account::Account;
 use serde_bytes::ByteBuf;
 
@@ -1 +1,2 @@
 pub mod icp;
+pub mod icrc1;
@@ -1,29 +1,173 @@
-use ic_ledger_types::{AccountIdentifier, Memo, Subaccount, Tokens, TransferArgs, DEFAULT_FEE};
+use std::fmt;
 
-use crate::txn_handler::TransactionType;
+use candid::{CandidType, Principal};
+use ic_ledger_types::{
+    AccountIdentifier, Memo, Subaccount, Tokens, TransferArgs, TransferError, DEFAULT_SUBACCOUNT,
+};
+use serde::Deserialize;
 
-pub fn transfer(
+use crate::{state::read_config, txn_handler::TransactionType, types::SubmittedTxidType};
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum IcpTransferError {
+    BadFee { expected_fee: u64 },
+    InsufficientFunds { balance: u64 },
+    TxTooOld { allowed_window_nanos: u64 },
+    TxCreatedInFuture,
+    TxDuplicate { duplicate_of: u64 },
+    AmountTooSmall { fee: u64 },
+    LedgerUnavailable(String),
+}
+
+impl fmt::Display for IcpTransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadFee { expected_fee } => {
+                write!(
+                    f,
+                    "ICP_TRANSFER_ERROR: Bad fee, expected {} e8s",
+                    expected_fee
+                )
+            }
+            Self::InsufficientFunds { balance } => {
+                write!(
+                    f,
+                    "ICP_TRANSFER_ERROR: Insufficient funds, balance {} e8s",
+                    balance
+                )
+            }
+            Self::TxTooOld {
+                allowed_window_nanos,
+            } => write!(
+                f,
+                "ICP_TRANSFER_ERROR: Transaction too old, allowed window {} ns",
+                allowed_window_nanos
+            ),
+            Self::TxCreatedInFuture => {
+                write!(f, "ICP_TRANSFER_ERROR: Transaction created in future")
+            }
+            Self::TxDuplicate { duplicate_of } => {
+                write!(f, "ICP_TRANSFER_ERROR: Duplicate of block {}", duplicate_of)
+            }
+            Self::AmountTooSmall { fee } => write!(
+                f,
+                "ICP_TRANSFER_ERROR: Amount doesn't cover the fee of {} e8s",
+                fee
+            ),
+            Self::LedgerUnavailable(msg) => write!(f, "ICP_TRANSFER_ERROR: {}", msg),
+        }
+    }
+}
+
+impl From<TransferError> for IcpTransferError {
+    fn from(err: TransferError) -> Self {
+        match err {
+            TransferError::BadFee { expected_fee } => Self::BadFee {
+                expected_fee: expected_fee.e8s(),
+            },
+            TransferError::InsufficientFunds { balance } => Self::InsufficientFunds {
+                balance: balance.e8s(),
+            },
+            TransferError::TxTooOld {
+                allowed_window_nanos,
+            } => Self::TxTooOld {
+                allowed_window_nanos,
+            },
+            TransferError::TxCreatedInFuture => Self::TxCreatedInFuture,
+            TransferError::TxDuplicate { duplicate_of } => Self::TxDuplicate { duplicate_of },
+        }
+    }
+}
+
+// ic-ledger-types doesn't wrap the ledger's transfer_fee endpoint
+#[derive(CandidType)]
+struct TransferFeeArgs {}
+
+#[derive(Deserialize, CandidType)]
+struct TransferFee {
+    transfer_fee: Tokens,
+}
+
+pub async fn get_transfer_fee(ledger: Principal) -> Result<Tokens, IcpTransferError> {
+    ic_cdk::call::<_, (TransferFee,)>(ledger, "transfer_fee", (TransferFeeArgs {},))
+        .await
+        .map(|(fee,)| fee.transfer_fee)
+        .map_err(|(code, msg)| IcpTransferError::LedgerUnavailable(format!("{:?}: {}", code, msg)))
+}
+
+pub async fn get_balance(account: AccountIdentifier) -> Result<u64, IcpTransferError> {
+    let ledger = read_config(|config| config.icp_ledger());
+    ic_ledger_types::account_balance(ledger, ic_ledger_types::AccountBalanceArgs { account })
+        .await
+        .map(|tokens| tokens.e8s())
+        .map_err(|(code, msg)| IcpTransferError::LedgerUnavailable(format!("{:?}: {}", code, msg)))
+}
+
+// with `paid_by_sender` the receiver gets the full `amount` and the ledger
+// fee is charged on top of it, otherwise the fee is deducted from `amount`.
+pub async fn transfer(
     from_subaccount: [u8; 32],
     to: AccountIdentifier,
     amount: u64,
     paid_by_sender: bool,
-) -> TransactionType {
-    let amount = Tokens::from_e8s(if paid_by_sender {
+) -> Result<TransactionType, IcpTransferError> {
+    let ledger = read_config(|config| config.icp_ledger());
+    let fee = get_transfer_fee(ledger).await?;
+
+    let amount = if paid_by_sender {
         amount
     } else {
-        amount - DEFAULT_FEE.e8s()
-    });
-
-    let from_subaccount = Subaccount(from_subaccount);
+        amount
+            .checked_sub(fee.e8s())
+            .filter(|amount| *amount > 0)
+            .ok_or(IcpTransferError::AmountTooSmall { fee: fee.e8s() })?
+    };
 
     let arg = TransferArgs {
         memo: Memo(0),
-        amount,
-        fee: DEFAULT_FEE,
-        from_subaccount: Some(from_subaccount),
+        amount: Tokens::from_e8s(amount),
+        fee,
+        from_subaccount: Some(Subaccount(from_subaccount)),
         to,
         created_at_time: None,
     };
 
-    todo!()
+    Ok(TransactionType::Icp { ledger, txn: arg })
+}
+
+// moves whatever `from_subaccount` holds to the canister's default account,
+// returns the amount that arrived there.
+pub async fn sweep(
+    from_subaccount: [u8; 32],
+    account: AccountIdentifier,
+) -> Result<u64, IcpTransferError> {
+    let ledger = read_config(|config| config.icp_ledger());
+    let balance = get_balance(account).await?;
+    let fee = get_transfer_fee(ledger).await?;
+    if balance <= fee.e8s() {
+        return Ok(0);
+    }
+    let amount = balance - fee.e8s();
+    let arg = TransferArgs {
+        memo: Memo(0),
+        amount: Tokens::from_e8s(amount),
+        fee,
+        from_subaccount: Some(Subaccount(from_subaccount)),
+        to: AccountIdentifier::new(&ic_cdk::id(), &DEFAULT_SUBACCOUNT),
+        created_at_time: None,
+    };
+    submit(ledger, arg).await?;
+    Ok(amount)
+}
+
+pub async fn submit(
+    ledger: Principal,
+    txn: TransferArgs,
+) -> Result<SubmittedTxidType, IcpTransferError> {
+    let txid = ic_ledger_types::transfer(ledger, txn)
+        .await
+        .map_err(|(code, msg)| {
+            IcpTransferError::LedgerUnavailable(format!("{:?}: {}", code, msg))
+        })??;
+    Ok(SubmittedTxidType::Ic { txid })
 }
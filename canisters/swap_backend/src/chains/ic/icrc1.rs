@@ -0,0 +1,42 @@
+use candid::{Nat, Principal};
+use icrc_ledger_types::icrc1::{account::Account, transfer::TransferArg};
+
+use crate::txn_handler::TransactionType;
+
+fn to_u128(value: Nat) -> u128 {
+    value.0.try_into().unwrap_or(u128::MAX)
+}
+
+pub async fn get_transfer_fee(ledger: Principal) -> Result<u128, String> {
+    let (fee,): (Nat,) = ic_cdk::call(ledger, "icrc1_fee", ())
+        .await
+        .map_err(|(code, msg)| format!("ICRC1_ERROR: {:?}: {}", code, msg))?;
+    Ok(to_u128(fee))
+}
+
+// the receiver gets the full `amount`, the ledger's `fee` is charged on top
+pub fn transfer(
+    ledger: Principal,
+    from_subaccount: [u8; 32],
+    to: Account,
+    amount: u128,
+    fee: u128,
+) -> TransactionType {
+    let arg = TransferArg {
+        from_subaccount: Some(from_subaccount),
+        to,
+        fee: Some(Nat::from(fee)),
+        created_at_time: None,
+        memo: None,
+        amount: Nat::from(amount),
+    };
+
+    TransactionType::Icrc1 { ledger, txn: arg }
+}
+
+pub async fn balance_of(ledger: Principal, account: Account) -> Result<u128, String> {
+    let (balance,): (Nat,) = ic_cdk::call(ledger, "icrc1_balance_of", (account,))
+        .await
+        .map_err(|(code, msg)| format!("ICRC1_ERROR: {:?}: {}", code, msg))?;
+    Ok(to_u128(balance))
+}
@@ -6,34 +6,50 @@ mod txn_handler;
 mod types;
 mod updater;
 
-use std::{collections::HashMap, time::Duration};
+use std::{collections::HashMap, str::FromStr, time::Duration};
 
 use candid::{CandidType, Principal};
 use chains::{
+    account_of_address,
     btc::{
-        runestone::transfer::RuneTransferArgs,
-        transaction::{combined::CombinedTransactionArgs, BtcTransferArgs},
+        coin_selection::Strategy,
+        psbt::PsbtExport,
+        runestone::{
+            multi_transfer::{transfer_many, MultiTransferArgs, RuneRecipient, RuneSource},
+            transfer::RuneTransferArgs,
+            DEFAULT_POSTAGE,
+        },
+        transaction::{
+            combined::{CombinedTransactionArgs, Wallet},
+            rune_pair::RunePairTransactionArgs,
+            BtcTransferArgs,
+        },
     },
     generate_subaccount_for_pool, principal_to_subaccount, Addresses,
 };
 use ic_cdk::{
     api::management_canister::{
-        bitcoin::BitcoinNetwork,
         ecdsa::{
             ecdsa_public_key, EcdsaKeyId, EcdsaPublicKeyArgument,
             EcdsaPublicKeyResponse as EcdsaPublicKey,
         },
+        schnorr::{schnorr_public_key, SchnorrPublicKeyArgument},
     },
     init, post_upgrade, pre_upgrade, query, update,
 };
-use ic_ledger_types::account_balance;
+use ic_ledger_types::{AccountIdentifier, DEFAULT_SUBACCOUNT};
+use icrc_ledger_types::icrc1::account::Account;
 use serde::Deserialize;
+use serde_bytes::ByteBuf;
 use state::{
-    pool_manager::PoolInfo, read_config, read_pool_manager, read_utxo_manager, write_config,
-    write_pool_manager,
+    config::{FeePolicy, FeePurpose, SwapBackendArgs},
+    pool_manager::PoolInfo,
+    read_config, read_pool_manager, read_txn_manager, read_user_manager, read_utxo_manager,
+    txn_manager::{Operation, Payout, PayoutStatus, PsbtSwap, TxnStatus},
+    write_config, write_pool_manager, write_txn_manager, write_user_manager, write_utxo_manager,
 };
-use types::{RuneId, SubmittedTxidType, TokenType};
-use updater::TargetType;
+use txn_handler::{release_inputs, TransactionType};
+use types::{FeePreference, RuneId, ScriptType, SubmittedTxidType, TokenType};
 
 async fn lazy_ecdsa_setup() {
     let ecdsa_keyid: EcdsaKeyId = read_config(|config| config.ecdsakeyid());
@@ -53,82 +69,245 @@ async fn lazy_ecdsa_setup() {
     });
 }
 
+async fn lazy_schnorr_setup() {
+    let key_id = read_config(|config| config.schnorrkeyid());
+    let schnorr_response = schnorr_public_key(SchnorrPublicKeyArgument {
+        canister_id: None,
+        derivation_path: vec![],
+        key_id,
+    })
+    .await
+    .expect("Failed to get schnorr key")
+    .0;
+
+    write_config(|config| {
+        let mut temp = config.get().clone();
+        temp.schnorr_public_key = Some(schnorr_response);
+        let _ = config.set(temp);
+    });
+}
+
+// how often submitted bitcoin transactions are checked for confirmations
+const TXN_POLL_INTERVAL: Duration = Duration::from_secs(10 * 60);
+// how often pool addresses get a chance to merge their utxos
+const CONSOLIDATION_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+// how often queued swap payouts go out in a batch
+const PAYOUT_BATCH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(TXN_POLL_INTERVAL, || {
+        ic_cdk::spawn(async {
+            updater::release_expired_psbt_swaps();
+            updater::release_expired_reservations();
+            updater::poll_submitted_transactions().await;
+            updater::bump_stuck_transactions().await;
+        })
+    });
+    ic_cdk_timers::set_timer_interval(PAYOUT_BATCH_INTERVAL, || {
+        ic_cdk::spawn(async {
+            match updater::flush_payouts().await {
+                Ok(Some(txid)) => ic_cdk::println!("sent payouts in {:?}", txid),
+                Ok(None) => {}
+                Err(err) => ic_cdk::println!("failed to send payouts: {}", err),
+            }
+        })
+    });
+    ic_cdk_timers::set_timer_interval(CONSOLIDATION_INTERVAL, || {
+        ic_cdk::spawn(async {
+            if let Err(err) =
+                updater::consolidate_held_utxos(updater::CONSOLIDATION_MAX_FEE_RATE).await
+            {
+                ic_cdk::println!("skipped consolidation: {}", err);
+            }
+        })
+    });
+}
+
 #[init]
-pub fn init(bitcoin_network: BitcoinNetwork) {
-    let keyname = match bitcoin_network {
-        BitcoinNetwork::Mainnet => "key_1".to_string(),
-        BitcoinNetwork::Testnet => "test_key_1".to_string(),
-        BitcoinNetwork::Regtest => "dfx_test_key".to_string(),
+pub fn init(args: SwapBackendArgs) {
+    let SwapBackendArgs::Init(args) = args else {
+        ic_cdk::trap("INVALID_ARGS: expected init args")
     };
     write_config(|config| {
         let mut temp = config.get().clone();
-        temp.keyname.replace(keyname);
-        temp.bitcoin_network.replace(bitcoin_network);
+        temp.apply_init(args)
+            .unwrap_or_else(|err| ic_cdk::trap(&format!("INVALID_ARGS: {}", err)));
         let _ = config.set(temp);
     });
+    state::migration::init_schema();
     ic_cdk_timers::set_timer(Duration::from_secs(0), || ic_cdk::spawn(lazy_ecdsa_setup()));
+    ic_cdk_timers::set_timer(Duration::from_secs(0), || {
+        ic_cdk::spawn(lazy_schnorr_setup())
+    });
+    start_timers();
 }
 
 #[pre_upgrade]
 pub fn pre_upgrade() {}
 
+// pools created before the address book, or before an address kind existed
+fn register_pool_addresses() {
+    let pool_subaccounts = read_pool_manager(|pools| {
+        pools
+            .pool_mapping
+            .iter()
+            .map(|(_, pool)| pool.allocated_raw_subaccount)
+            .collect::<Vec<_>>()
+    });
+    write_user_manager(|users| {
+        for subaccount in pool_subaccounts {
+            let addresses = Addresses::from(subaccount);
+            for addr in addresses.bitcoin_addresses() {
+                users.register_address(addr, subaccount);
+            }
+        }
+    });
+}
+
 #[post_upgrade]
-pub fn post_upgrade() {}
+pub fn post_upgrade(args: Option<SwapBackendArgs>) {
+    match args {
+        None | Some(SwapBackendArgs::Upgrade(None)) => {}
+        Some(SwapBackendArgs::Upgrade(Some(args))) => write_config(|config| {
+            let mut temp = config.get().clone();
+            temp.apply_upgrade(args)
+                .unwrap_or_else(|err| ic_cdk::trap(&format!("INVALID_ARGS: {}", err)));
+            let _ = config.set(temp);
+        }),
+        Some(SwapBackendArgs::Init(_)) => ic_cdk::trap("INVALID_ARGS: expected upgrade args"),
+    }
+    state::migration::start_migrations();
+    start_timers();
+    // taproot addresses can't be derived before the schnorr key is fetched
+    if read_config(|config| config.schnorr_public_key.is_none()) {
+        ic_cdk_timers::set_timer(Duration::from_secs(0), || {
+            ic_cdk::spawn(async {
+                lazy_schnorr_setup().await;
+                register_pool_addresses();
+            })
+        });
+    } else {
+        register_pool_addresses();
+    }
+}
+
+#[update]
+pub fn set_icp_ledger(ledger: Principal) {
+    if !read_config(|config| config.is_admin(&ic_cdk::caller())) {
+        ic_cdk::trap("Unauthorized")
+    }
+    write_config(|config| {
+        let mut temp = config.get().clone();
+        temp.icp_ledger.replace(ledger);
+        let _ = config.set(temp);
+    });
+}
+
+#[update]
+pub fn set_fee_policy(policy: FeePolicy) {
+    if !read_config(|config| config.is_admin(&ic_cdk::caller())) {
+        ic_cdk::trap("Unauthorized")
+    }
+    policy
+        .validate()
+        .unwrap_or_else(|err| ic_cdk::trap(&format!("INVALID_FEE_POLICY: {}", err)));
+    write_config(|config| {
+        let mut temp = config.get().clone();
+        temp.fee_policy.replace(policy);
+        let _ = config.set(temp);
+    });
+}
 
 #[query]
-pub fn get_deposit_addresses() -> Addresses {
-    let caller = ic_cdk::caller();
-    Addresses::from(&caller)
+pub fn get_fee_policy() -> FeePolicy {
+    read_config(|config| config.fee_policy())
 }
 
 #[update]
-pub async fn test_combined_withdrawal(
-    runeid: RuneId,
-    rune_amount: u128,
-    btc_amount: u64,
-    to: String,
-) -> SubmittedTxidType {
-    let caller = ic_cdk::caller();
-    let caller_addresses = Addresses::from(&caller);
+pub fn set_ord_canister(ord_canister: Principal) {
+    if !read_config(|config| config.is_admin(&ic_cdk::caller())) {
+        ic_cdk::trap("Unauthorized")
+    }
+    write_config(|config| {
+        let mut temp = config.get().clone();
+        temp.ord_canister.replace(ord_canister);
+        let _ = config.set(temp);
+    });
+}
 
-    let sender = chains::btc::address_validation(&caller_addresses.bitcoin).unwrap();
-    let receiver = chains::btc::address_validation(to.as_str()).unwrap();
+#[derive(CandidType)]
+pub struct IndexerHealth {
+    pub ord_canister: Principal,
+    pub indexed_height: u32,
+    pub indexed_hash: String,
+    pub bitcoin_height: u32,
+    pub blocks_behind: u32,
+}
 
-    updater::fetch_utxos_and_update_balances(
-        &caller_addresses.bitcoin,
-        updater::TargetType::Bitcoin { target: u64::MAX },
-    )
-    .await;
-
-    let fee_per_vbytes = chains::btc::get_fee_per_vbyte().await;
-
-    chains::btc::transaction::combined::transfer(CombinedTransactionArgs {
-        runeid,
-        rune_amount,
-        rune_sender: sender.clone(),
-        rune_receiver: receiver.clone(),
-        rune_sender_account: caller_addresses.icrc1,
-        btc_amount,
-        bitcoin_sender: sender.clone(),
-        bitcoin_receiver: receiver.clone(),
-        bitcoin_sender_account: caller_addresses.icrc1,
-        fee_payer: sender.clone(),
-        fee_payer_account: caller_addresses.icrc1,
-        postage: None,
-        fee_per_vbytes,
+// how far the rune indexer trails the bitcoin canister's tip. utxos mined
+// past the indexed height wait to be classified.
+#[update]
+pub async fn get_indexer_health() -> Result<IndexerHealth, String> {
+    if !read_config(|config| config.is_admin(&ic_cdk::caller())) {
+        ic_cdk::trap("Unauthorized")
+    }
+    let ord_canister = read_config(|config| config.ord_canister());
+    let (indexed_height, indexed_hash) = ord_canister::get_height()
+        .await
+        .map_err(|(code, msg)| format!("{:?}: {}", code, msg))?
+        .0
+        .map_err(|err| format!("{:?}", err))?;
+    let bitcoin_height = updater::bitcoin_tip_height().await?;
+    Ok(IndexerHealth {
+        ord_canister,
+        indexed_height,
+        indexed_hash,
+        bitcoin_height,
+        blocks_behind: bitcoin_height.saturating_sub(indexed_height),
     })
-    .unwrap()
-    .build_and_submit()
-    .await
 }
 
+// merges the utxos held by pool and deposit addresses right away, at most paying
+// `max_fee_per_vbytes` instead of the default threshold
 #[update]
-pub async fn get_combined_balance(addr: String, runeid: RuneId) -> HashMap<TokenType, u128> {
-    updater::fetch_utxos_and_update_balances(
-        &addr,
-        updater::TargetType::Bitcoin { target: u64::MAX },
+pub async fn consolidate_utxos(
+    max_fee_per_vbytes: Option<u64>,
+) -> Result<Vec<SubmittedTxidType>, String> {
+    if !read_config(|config| config.is_admin(&ic_cdk::caller())) {
+        ic_cdk::trap("Unauthorized")
+    }
+    updater::consolidate_held_utxos(
+        max_fee_per_vbytes.unwrap_or(updater::CONSOLIDATION_MAX_FEE_RATE),
     )
-    .await;
+    .await
+}
+
+#[update]
+pub fn set_min_confirmations(btc: u32, rune: u32) {
+    if !read_config(|config| config.is_admin(&ic_cdk::caller())) {
+        ic_cdk::trap("Unauthorized")
+    }
+    if btc == 0 || rune == 0 {
+        ic_cdk::trap("confirmations must be at least 1")
+    }
+    write_config(|config| {
+        let mut temp = config.get().clone();
+        temp.btc_min_confirmations.replace(btc);
+        temp.rune_min_confirmations.replace(rune);
+        let _ = config.set(temp);
+    });
+}
+
+#[query]
+pub fn get_deposit_addresses() -> Addresses {
+    let caller = ic_cdk::caller();
+    Addresses::from(&caller)
+}
+
+// what the utxo manager has recorded for `addr`, syncing happens when its
+// owner's deposits are credited
+#[query]
+pub fn get_combined_balance(addr: String, runeid: RuneId) -> HashMap<TokenType, u128> {
     let mut balances = HashMap::new();
     read_utxo_manager(|manager| {
         let bitcoin_balance = manager.get_bitcoin_balance(&addr);
@@ -139,24 +318,21 @@ pub async fn get_combined_balance(addr: String, runeid: RuneId) -> HashMap<Token
     })
 }
 
+#[derive(CandidType)]
+pub struct UserBalanceQuery {
+    pub available: HashMap<TokenType, u128>,
+    // deposits still waiting for their confirmation depth
+    pub pending: HashMap<TokenType, u128>,
+}
+
 #[update]
-pub async fn get_user_balance() -> HashMap<TokenType, u128> {
+pub async fn get_user_balance() -> UserBalanceQuery {
     let caller = ic_cdk::caller();
-    let caller_addresses = Addresses::from(&caller);
-    updater::fetch_utxos_and_update_balances(
-        &caller_addresses.bitcoin,
-        updater::TargetType::Bitcoin { target: u64::MAX },
-    )
-    .await;
-    read_utxo_manager(|manager| {
-        let mut balances = HashMap::new();
-        let bitcoin_balance = manager.get_bitcoin_balance(&caller_addresses.bitcoin);
-        let runes = manager.all_rune_with_balances(&caller_addresses.bitcoin);
-        for (rune, balance) in runes {
-            balances.insert(TokenType::Runestone(rune), balance);
-        }
-        balances.insert(TokenType::Bitcoin, bitcoin_balance as u128);
-        balances
+    updater::credit_deposits(&caller).await;
+    updater::update_pending_deposits(&caller).await;
+    read_user_manager(|users| UserBalanceQuery {
+        available: users.all_balances(&caller),
+        pending: users.pending_balances(&caller),
     })
 }
 
@@ -165,11 +341,27 @@ pub enum WithdrawalType {
     Bitcoin {
         to: String,
         amount: u64,
+        fee: Option<FeePreference>,
     },
     Rune {
         to: String,
         runeid: RuneId,
         amount: u128,
+        fee: Option<FeePreference>,
+    },
+    // several runes to many addresses in a single transaction
+    Runes {
+        recipients: Vec<Recipient>,
+        fee: Option<FeePreference>,
+    },
+    // both runes of a rune/rune pool, with an edict each
+    RunePair {
+        to: String,
+        rune0: RuneId,
+        amount0: u128,
+        rune1: RuneId,
+        amount1: u128,
+        fee: Option<FeePreference>,
     },
     Icp {
         to: String,
@@ -182,10 +374,364 @@ pub enum WithdrawalType {
     },
 }
 
-pub async fn withdraw(withdrawal_type: WithdrawalType) -> SubmittedTxidType {
+#[derive(CandidType, Deserialize)]
+pub struct Recipient {
+    pub to: String,
+    pub runeid: RuneId,
+    pub amount: u128,
+}
+
+// the spendable bitcoin address for `addr`, which must be one of the canister's
+fn canister_address(addr: &str) -> Result<(bitcoin::Address, Account), String> {
+    let address = chains::btc::address_validation(addr).unwrap();
+    let account = account_of_address(addr)
+        .ok_or_else(|| String::from("WITHDRAWAL_ERROR: Unknown source address"))?;
+    Ok((address, account))
+}
+
+// selects the utxos and builds the transaction for a withdrawal by
+// `caller`, along with what it costs the caller. nothing is awaited once
+// utxos are taken out of the utxo manager.
+async fn build_withdrawal(
+    caller: &Principal,
+    withdrawal_type: WithdrawalType,
+) -> Result<(TransactionType, Vec<(TokenType, u128)>), String> {
+    let caller_addresses = Addresses::from(caller);
+    match withdrawal_type {
+        WithdrawalType::Bitcoin { to, amount, fee } => {
+            let receiver = chains::btc::address_validation(&to)
+                .map_err(|err| format!("WITHDRAWAL_ERROR: {}", err))?;
+            let fee_per_vbytes = chains::btc::get_fee_per_vbyte(FeePurpose::Withdrawal, fee).await;
+
+            let source = read_utxo_manager(|manager| {
+                manager.address_with_bitcoin_balance(amount, &caller_addresses.bitcoin)
+            })
+            .ok_or_else(|| String::from("WITHDRAWAL_ERROR: Not enough spendable bitcoin"))?;
+            let (sender, sender_account) = canister_address(&source)?;
+
+            let txn = chains::btc::transaction::transfer(BtcTransferArgs {
+                sender,
+                receiver,
+                amount,
+                sender_account,
+                paid_by_sender: true,
+                fee_per_vbytes,
+                strategy: Strategy::for_fee_rate(fee_per_vbytes),
+            })
+            .map_err(|required| {
+                format!("WITHDRAWAL_ERROR: {} sats required including fee", required)
+            })?;
+            let debits = vec![(TokenType::Bitcoin, txn.bitcoin_cost() as u128)];
+            Ok((txn, debits))
+        }
+        WithdrawalType::Rune {
+            to,
+            runeid,
+            amount,
+            fee,
+        } => {
+            let receiver = chains::btc::address_validation(&to)
+                .map_err(|err| format!("WITHDRAWAL_ERROR: {}", err))?;
+            let fee_per_vbytes = chains::btc::get_fee_per_vbyte(FeePurpose::Withdrawal, fee).await;
+
+            let (source, fee_source) = read_utxo_manager(|manager| {
+                let source = manager
+                    .address_with_runestone_balance(&runeid, amount, &caller_addresses.bitcoin)
+                    .ok_or_else(|| String::from("WITHDRAWAL_ERROR: Not enough spendable runes"))?;
+                let fee_source = manager
+                    .address_with_bitcoin_balance(DEFAULT_POSTAGE * 2, &source)
+                    .ok_or_else(|| String::from("WITHDRAWAL_ERROR: Not enough bitcoin for fee"))?;
+                Ok::<_, String>((source, fee_source))
+            })?;
+            let (sender, sender_account) = canister_address(&source)?;
+            let (fee_payer, fee_payer_account) = canister_address(&fee_source)?;
+
+            let txn = chains::btc::runestone::transfer(RuneTransferArgs {
+                runeid: runeid.clone(),
+                amount,
+                sender,
+                sender_account,
+                receiver,
+                fee_payer,
+                fee_payer_account,
+                postage: None,
+                fee_per_vbytes,
+                strategy: Strategy::for_fee_rate(fee_per_vbytes),
+            })
+            .map_err(|(runes, sats)| {
+                format!(
+                    "WITHDRAWAL_ERROR: {} runes and {} sats required including fee",
+                    runes, sats
+                )
+            })?;
+            let debits = vec![
+                (TokenType::Runestone(runeid), amount),
+                (TokenType::Bitcoin, txn.bitcoin_cost() as u128),
+            ];
+            Ok((txn, debits))
+        }
+        WithdrawalType::Runes { recipients, fee } => {
+            if recipients.is_empty() {
+                return Err(String::from("WITHDRAWAL_ERROR: No recipients"));
+            }
+            let mut totals: Vec<(RuneId, u128)> = vec![];
+            let recipients = recipients
+                .into_iter()
+                .map(|Recipient { to, runeid, amount }| {
+                    let receiver = chains::btc::address_validation(&to)
+                        .map_err(|err| format!("WITHDRAWAL_ERROR: {}", err))?;
+                    match totals.iter_mut().find(|(id, _)| id == &runeid) {
+                        Some((_, total)) => {
+                            *total = total
+                                .checked_add(amount)
+                                .ok_or_else(|| String::from("WITHDRAWAL_ERROR: Amount too large"))?
+                        }
+                        None => totals.push((runeid.clone(), amount)),
+                    }
+                    Ok(RuneRecipient {
+                        receiver,
+                        runeid,
+                        amount,
+                    })
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            let fee_per_vbytes = chains::btc::get_fee_per_vbyte(FeePurpose::Withdrawal, fee).await;
+
+            let (sources, fee_source) = read_utxo_manager(|manager| {
+                let sources = totals
+                    .iter()
+                    .map(|(runeid, total)| {
+                        manager
+                            .address_with_runestone_balance(
+                                runeid,
+                                *total,
+                                &caller_addresses.bitcoin,
+                            )
+                            .ok_or_else(|| {
+                                String::from("WITHDRAWAL_ERROR: Not enough spendable runes")
+                            })
+                    })
+                    .collect::<Result<Vec<_>, String>>()?;
+                let fee_source = manager
+                    .address_with_bitcoin_balance(
+                        DEFAULT_POSTAGE * (recipients.len() as u64 + 1),
+                        &sources[0],
+                    )
+                    .ok_or_else(|| String::from("WITHDRAWAL_ERROR: Not enough bitcoin for fee"))?;
+                Ok::<_, String>((sources, fee_source))
+            })?;
+            let sources = totals
+                .iter()
+                .zip(sources)
+                .map(|((runeid, _), source)| {
+                    let (sender, sender_account) = canister_address(&source)?;
+                    Ok(RuneSource {
+                        runeid: runeid.clone(),
+                        sender,
+                        sender_account,
+                    })
+                })
+                .collect::<Result<_, String>>()?;
+            let (fee_payer, fee_payer_account) = canister_address(&fee_source)?;
+
+            let txn = transfer_many(MultiTransferArgs {
+                recipients,
+                sources,
+                fee_payer,
+                fee_payer_account,
+                postage: None,
+                fee_per_vbytes,
+                strategy: Strategy::for_fee_rate(fee_per_vbytes),
+            })
+            .map_err(|err| format!("WITHDRAWAL_ERROR: {}", err))?;
+            let mut debits = totals
+                .into_iter()
+                .map(|(runeid, total)| (TokenType::Runestone(runeid), total))
+                .collect::<Vec<_>>();
+            debits.push((TokenType::Bitcoin, txn.bitcoin_cost() as u128));
+            Ok((txn, debits))
+        }
+        WithdrawalType::RunePair {
+            to,
+            rune0,
+            amount0,
+            rune1,
+            amount1,
+            fee,
+        } => {
+            let receiver = chains::btc::address_validation(&to)
+                .map_err(|err| format!("WITHDRAWAL_ERROR: {}", err))?;
+            let pair = read_pool_manager(|pools| {
+                pools.get_pool_id_by_tokens(
+                    TokenType::Runestone(rune0.clone()),
+                    TokenType::Runestone(rune1.clone()),
+                )
+            });
+            if rune0 == rune1 || pair.is_none() {
+                return Err(String::from("WITHDRAWAL_ERROR: Non-existing Pair"));
+            }
+            let fee_per_vbytes = chains::btc::get_fee_per_vbyte(FeePurpose::Withdrawal, fee).await;
+
+            let (source0, source1, fee_source) = read_utxo_manager(|manager| {
+                let source = |runeid: &RuneId, amount: u128| {
+                    manager
+                        .address_with_runestone_balance(runeid, amount, &caller_addresses.bitcoin)
+                        .ok_or_else(|| String::from("WITHDRAWAL_ERROR: Not enough spendable runes"))
+                };
+                let source0 = source(&rune0, amount0)?;
+                let source1 = source(&rune1, amount1)?;
+                // a postage output per receiver and per sender's change
+                let fee_source = manager
+                    .address_with_bitcoin_balance(DEFAULT_POSTAGE * 4, &source0)
+                    .ok_or_else(|| String::from("WITHDRAWAL_ERROR: Not enough bitcoin for fee"))?;
+                Ok::<_, String>((source0, source1, fee_source))
+            })?;
+            let (rune0_sender, rune0_sender_account) = canister_address(&source0)?;
+            let (rune1_sender, rune1_sender_account) = canister_address(&source1)?;
+            let (fee_payer, fee_payer_account) = canister_address(&fee_source)?;
+
+            let txn = chains::btc::transaction::rune_pair::transfer(RunePairTransactionArgs {
+                rune0: rune0.clone(),
+                rune0_amount: amount0,
+                rune0_sender,
+                rune0_receiver: receiver.clone(),
+                rune0_sender_account,
+                rune1: rune1.clone(),
+                rune1_amount: amount1,
+                rune1_sender,
+                rune1_receiver: receiver,
+                rune1_sender_account,
+                fee_payer,
+                fee_payer_account,
+                postage: None,
+                fee_per_vbytes,
+                strategy: Strategy::for_fee_rate(fee_per_vbytes),
+            })
+            .map_err(|(runes0, runes1, sats)| {
+                format!(
+                    "WITHDRAWAL_ERROR: {} and {} runes and {} sats required including fee",
+                    runes0, runes1, sats
+                )
+            })?;
+            let debits = vec![
+                (TokenType::Runestone(rune0), amount0),
+                (TokenType::Runestone(rune1), amount1),
+                (TokenType::Bitcoin, txn.bitcoin_cost() as u128),
+            ];
+            Ok((txn, debits))
+        }
+        WithdrawalType::Icp { to, amount } => {
+            let receiver = AccountIdentifier::from_hex(&to)
+                .map_err(|err| format!("WITHDRAWAL_ERROR: {}", err))?;
+            let txn = chains::ic::icp::transfer(DEFAULT_SUBACCOUNT.0, receiver, amount, true)
+                .await
+                .map_err(|err| err.to_string())?;
+            let fee = match txn {
+                TransactionType::Icp { ref txn, .. } => txn.fee.e8s(),
+                _ => unreachable!(),
+            };
+            let total = amount
+                .checked_add(fee)
+                .ok_or_else(|| String::from("WITHDRAWAL_ERROR: Amount too large"))?
+                as u128;
+            if read_user_manager(|users| users.balance(caller, &TokenType::Icp)) < total {
+                return Err(String::from("WITHDRAWAL_ERROR: Insufficient balance"));
+            }
+            Ok((txn, vec![(TokenType::Icp, total)]))
+        }
+        WithdrawalType::Icrc1 { to, icrc1, amount } => {
+            // icrc1 tokens aren't tracked internally, they move straight from
+            // the caller's subaccount
+            let receiver =
+                Account::from_str(&to).map_err(|err| format!("WITHDRAWAL_ERROR: {}", err))?;
+            let balance = chains::ic::icrc1::balance_of(icrc1, caller_addresses.icrc1).await?;
+            let fee = chains::ic::icrc1::get_transfer_fee(icrc1).await?;
+            if amount
+                .checked_add(fee)
+                .filter(|total| *total <= balance)
+                .is_none()
+            {
+                return Err(String::from("WITHDRAWAL_ERROR: Insufficient balance"));
+            }
+
+            let txn = chains::ic::icrc1::transfer(
+                icrc1,
+                principal_to_subaccount(caller),
+                receiver,
+                amount,
+                fee,
+            );
+            Ok((txn, vec![]))
+        }
+    }
+}
+
+#[update]
+pub async fn withdraw(withdrawal_type: WithdrawalType) -> Result<SubmittedTxidType, String> {
+    let caller = ic_cdk::caller();
+
+    updater::credit_deposits(&caller).await;
+
+    // funds are pooled across the canister's addresses, the internal balance
+    // decides what the caller may take out. the debit happens together with
+    // utxo selection, without awaiting in between.
+    let (txn, debits) = build_withdrawal(&caller, withdrawal_type).await?;
+
+    write_user_manager(|users| {
+        for (token, amount) in debits.iter() {
+            if let Err(err) = users.debit(&caller, token.clone(), *amount) {
+                ic_cdk::trap(&format!("WITHDRAWAL_ERROR: {}", err))
+            }
+        }
+    });
+
+    match txn
+        .build_and_submit(Operation::Withdrawal { user: caller })
+        .await
+    {
+        Ok(txid) => Ok(txid),
+        Err(err) => {
+            txn.release_utxos();
+            write_user_manager(|users| {
+                for (token, amount) in debits {
+                    users.credit(&caller, token, amount);
+                }
+            });
+            Err(err)
+        }
+    }
+}
+
+// builds the transaction a bitcoin or rune withdrawal would submit and
+// returns it as an unsigned psbt. the selected utxos go right back to the
+// utxo manager and nothing is debited, but the caller's balance has to
+// cover the withdrawal like it would for `withdraw`.
+#[update]
+pub async fn export_psbt(withdrawal_type: WithdrawalType) -> Result<PsbtExport, String> {
+    if matches!(
+        withdrawal_type,
+        WithdrawalType::Icp { .. } | WithdrawalType::Icrc1 { .. }
+    ) {
+        return Err(String::from(
+            "only bitcoin and rune withdrawals have a psbt",
+        ));
+    }
     let caller = ic_cdk::caller();
-    let caller_addresses = Addresses::from(&caller);
-    todo!()
+    updater::credit_deposits(&caller).await;
+
+    let (txn, debits) = build_withdrawal(&caller, withdrawal_type).await?;
+    let covered = read_user_manager(|users| {
+        debits
+            .iter()
+            .all(|(token, amount)| users.balance(&caller, token) >= *amount)
+    });
+    let export = if covered {
+        chains::btc::psbt::export(&txn).await
+    } else {
+        Err(String::from("WITHDRAWAL_ERROR: Insufficient balance"))
+    };
+    txn.release_utxos();
+    export
 }
 
 #[derive(CandidType, Deserialize)]
@@ -210,6 +756,12 @@ pub fn create_pair(CreatePairArgs { token0, token1 }: CreatePairArgs) -> u128 {
         let current_time = ic_cdk::api::time();
 
         let subaccount = generate_subaccount_for_pool(current_count, current_time);
+        let addresses = Addresses::from(subaccount);
+        write_user_manager(|users| {
+            for addr in addresses.bitcoin_addresses() {
+                users.register_address(addr, subaccount);
+            }
+        });
 
         let pool_info = PoolInfo {
             pool_id: current_count,
@@ -246,7 +798,7 @@ pub fn pools() -> Vec<PoolInfoQuery> {
     read_pool_manager(|manager| {
         let mut pools = vec![];
         for (_, pool) in manager.pool_mapping.iter() {
-            let query = pool.into_query();
+            let query = pool.to_query();
             pools.push(query);
         }
         pools
@@ -275,19 +827,21 @@ pub async fn add_liquidity(
     }: AddLiquidityArgs,
 ) -> (u64, Vec<SubmittedTxidType>) {
     let caller = ic_cdk::caller();
-    let caller_addresses = Addresses::from(&caller);
 
     if token0 == token1 {
         ic_cdk::trap("ADD_LIQUIDITY_ERROR: Same Token");
     }
 
-    let (pool_id, pool_addresses, amount0, amount1) = write_pool_manager(|pools| {
+    updater::credit_deposits(&caller).await;
+
+    // the quote, the debits and the reserves it was quoted against move
+    // together, nothing below awaits
+    let (pool_id, amount0, amount1) = write_pool_manager(|pools| {
         let pool_info = match pools.get_pool_id_by_tokens(token0.clone(), token1.clone()) {
             None => ic_cdk::trap("ADD_LIQUIDITY_ERROR: Non-existing Pair"),
             Some(id) => pools.pool_mapping.get(&id).unwrap(),
         };
 
-        let pool_addresses = Addresses::from(pool_info.allocated_raw_subaccount);
         if token0 != pool_info.token0 {
             std::mem::swap(&mut token0, &mut token1);
             std::mem::swap(&mut amount0_desired, &mut amount1_desired);
@@ -322,247 +876,15 @@ pub async fn add_liquidity(
         if let Err(err) = pool_info.pre_mint(amount0, amount1) {
             ic_cdk::trap(&err)
         };
-        (pool_info.pool_id, pool_addresses, amount0, amount1)
+        (pool_info.pool_id, amount0, amount1)
     });
 
-    updater::fetch_utxos_and_update_balances(
-        &caller_addresses.bitcoin,
-        TargetType::Bitcoin { target: u64::MAX },
-    )
-    .await;
-
-    if let (TokenType::Bitcoin, TokenType::Runestone(rune)) = (token0.clone(), token1.clone()) {
-        read_utxo_manager(|manager| {
-            let btc_balance = manager.get_bitcoin_balance(&caller_addresses.bitcoin);
-            let rune_balance = manager.get_runestone_balance(&caller_addresses.bitcoin, &rune);
-            if btc_balance < amount0 || rune_balance < amount1 as u128 {
-                ic_cdk::trap("Insufficient balance")
-            }
-        });
-        let sender = chains::btc::address_validation(&caller_addresses.bitcoin).unwrap();
-        let receiver = chains::btc::address_validation(&pool_addresses.bitcoin).unwrap();
-        let fee_per_vbytes = chains::btc::get_fee_per_vbyte().await;
-
-        let txn = chains::btc::transaction::combined::transfer(CombinedTransactionArgs {
-            runeid: rune,
-            rune_amount: amount1 as u128,
-            rune_sender: sender.clone(),
-            rune_receiver: receiver.clone(),
-            rune_sender_account: caller_addresses.icrc1,
-            btc_amount: amount0,
-            bitcoin_sender: sender.clone(),
-            bitcoin_receiver: receiver,
-            bitcoin_sender_account: caller_addresses.icrc1,
-            fee_payer: sender,
-            fee_payer_account: caller_addresses.icrc1,
-            postage: None,
-            fee_per_vbytes,
-        })
-        .unwrap();
-        let txid = txn.build_and_submit().await;
-        let liquidity = write_pool_manager(|pools| {
-            let mut pool_info = pools.pool_mapping.get(&pool_id).unwrap();
-            let liquidity = pool_info.post_mint(caller, amount0, amount1).unwrap();
-            pools.pool_mapping.insert(pool_id, pool_info);
-            liquidity
-        });
-        return (liquidity, vec![txid]);
-    }
-
-    if let (TokenType::Runestone(rune), TokenType::Bitcoin) = (token0.clone(), token1.clone()) {
-        read_utxo_manager(|manager| {
-            let btc_balance = manager.get_bitcoin_balance(&caller_addresses.bitcoin);
-            let rune_balance = manager.get_runestone_balance(&caller_addresses.bitcoin, &rune);
-            if btc_balance < amount1 || rune_balance < amount0 as u128 {
-                ic_cdk::trap("Insufficient balance")
-            }
-        });
-        let sender = chains::btc::address_validation(&caller_addresses.bitcoin).unwrap();
-        let receiver = chains::btc::address_validation(&pool_addresses.bitcoin).unwrap();
-        let fee_per_vbytes = chains::btc::get_fee_per_vbyte().await;
-
-        let txn = chains::btc::transaction::combined::transfer(CombinedTransactionArgs {
-            runeid: rune,
-            rune_amount: amount0 as u128,
-            rune_sender: sender.clone(),
-            rune_receiver: receiver.clone(),
-            rune_sender_account: caller_addresses.icrc1,
-            btc_amount: amount1,
-            bitcoin_sender: sender.clone(),
-            bitcoin_receiver: receiver,
-            bitcoin_sender_account: caller_addresses.icrc1,
-            fee_payer: sender,
-            fee_payer_account: caller_addresses.icrc1,
-            postage: None,
-            fee_per_vbytes,
-        })
-        .unwrap();
-        let txid = txn.build_and_submit().await;
-        let liquidity = write_pool_manager(|pools| {
-            let mut pool_info = pools.pool_mapping.get(&pool_id).unwrap();
-            let liquidity = pool_info.post_mint(caller, amount0, amount1).unwrap();
-            pools.pool_mapping.insert(pool_id, pool_info);
-            liquidity
-        });
-        return (liquidity, vec![txid]);
-    }
-
-    let mut txns = vec![];
-
-    match token0 {
-        TokenType::Bitcoin => {
-            read_utxo_manager(|manager| {
-                let btc_balance = manager.get_bitcoin_balance(&caller_addresses.bitcoin);
-                if btc_balance < amount0 {
-                    ic_cdk::trap("Insufficient balance")
-                }
-            });
-            let sender = chains::btc::address_validation(&caller_addresses.bitcoin).unwrap();
-            let receiver = chains::btc::address_validation(&pool_addresses.bitcoin).unwrap();
-            let fee_per_vbytes = chains::btc::get_fee_per_vbyte().await;
-
-            let txn = chains::btc::transaction::transfer(BtcTransferArgs {
-                sender,
-                receiver,
-                amount: amount0,
-                sender_account: caller_addresses.icrc1,
-                paid_by_sender: true,
-                fee_per_vbytes,
-            })
-            .unwrap();
-            txns.push(txn);
-        }
-        TokenType::Runestone(rune) => {
-            read_utxo_manager(|manager| {
-                let balance = manager.get_runestone_balance(&caller_addresses.bitcoin, &rune);
-                if balance < amount0 as u128 {
-                    ic_cdk::trap("Insufficient balance")
-                }
-            });
-            let sender = chains::btc::address_validation(&caller_addresses.bitcoin).unwrap();
-            let receiver = chains::btc::address_validation(&pool_addresses.bitcoin).unwrap();
-            let fee_per_vbytes = chains::btc::get_fee_per_vbyte().await;
-
-            let txn = chains::btc::runestone::transfer(RuneTransferArgs {
-                runeid: rune.clone(),
-                amount: amount0 as u128,
-                sender_account: caller_addresses.icrc1,
-                sender: sender.clone(),
-                receiver,
-                fee_per_vbytes,
-                fee_payer: sender,
-                fee_payer_account: caller_addresses.icrc1,
-                postage: None,
-            })
-            .unwrap();
-            txns.push(txn);
-        }
-        TokenType::Icp => {
-            let subaccount = principal_to_subaccount(&caller);
-            let balance = account_balance(
-                ic_ledger_types::MAINNET_LEDGER_CANISTER_ID,
-                ic_ledger_types::AccountBalanceArgs {
-                    account: caller_addresses.account_identifier,
-                },
-            )
-            .await
-            .unwrap()
-            .e8s();
-
-            if balance < amount0 {
-                ic_cdk::trap("Insufficient balance")
-            }
-
-            let txn = chains::ic::icp::transfer(
-                subaccount,
-                pool_addresses.account_identifier,
-                amount0,
-                true,
-            );
-            txns.push(txn);
-        }
-        _ => unimplemented!(),
-    }
-
-    match token1 {
-        TokenType::Bitcoin => {
-            read_utxo_manager(|manager| {
-                let btc_balance = manager.get_bitcoin_balance(&caller_addresses.bitcoin);
-                if btc_balance < amount1 {
-                    ic_cdk::trap("Insufficient balance")
-                }
-            });
-            let sender = chains::btc::address_validation(&caller_addresses.bitcoin).unwrap();
-            let receiver = chains::btc::address_validation(&pool_addresses.bitcoin).unwrap();
-            let fee_per_vbytes = chains::btc::get_fee_per_vbyte().await;
-
-            let txn = chains::btc::transaction::transfer(BtcTransferArgs {
-                sender,
-                receiver,
-                amount: amount1,
-                sender_account: caller_addresses.icrc1,
-                paid_by_sender: true,
-                fee_per_vbytes,
-            })
-            .unwrap();
-            txns.push(txn);
-        }
-        TokenType::Runestone(rune) => {
-            read_utxo_manager(|manager| {
-                let balance = manager.get_runestone_balance(&caller_addresses.bitcoin, &rune);
-                if balance < amount1 as u128 {
-                    ic_cdk::trap("Insufficient balance")
-                }
-            });
-            let sender = chains::btc::address_validation(&caller_addresses.bitcoin).unwrap();
-            let receiver = chains::btc::address_validation(&pool_addresses.bitcoin).unwrap();
-            let fee_per_vbytes = chains::btc::get_fee_per_vbyte().await;
-
-            let txn = chains::btc::runestone::transfer(RuneTransferArgs {
-                runeid: rune.clone(),
-                amount: amount1 as u128,
-                sender_account: caller_addresses.icrc1,
-                sender: sender.clone(),
-                receiver,
-                fee_per_vbytes,
-                fee_payer: sender,
-                fee_payer_account: caller_addresses.icrc1,
-                postage: None,
-            })
-            .unwrap();
-            txns.push(txn);
-        }
-        TokenType::Icp => {
-            let subaccount = principal_to_subaccount(&caller);
-            let balance = account_balance(
-                ic_ledger_types::MAINNET_LEDGER_CANISTER_ID,
-                ic_ledger_types::AccountBalanceArgs {
-                    account: caller_addresses.account_identifier,
-                },
-            )
-            .await
-            .unwrap()
-            .e8s();
-
-            if balance < amount1 {
-                ic_cdk::trap("Insufficient balance")
-            }
-
-            let txn = chains::ic::icp::transfer(
-                subaccount,
-                pool_addresses.account_identifier,
-                amount1,
-                true,
-            );
-            txns.push(txn);
-        }
-        _ => unimplemented!(),
-    }
-    let mut txids = vec![];
-    for txn in txns {
-        let txid = txn.build_and_submit().await;
-        txids.push(txid);
-    }
+    write_user_manager(|users| {
+        users
+            .debit(&caller, token0, amount0 as u128)
+            .and_then(|_| users.debit(&caller, token1, amount1 as u128))
+    })
+    .unwrap_or_else(|err| ic_cdk::trap(&format!("ADD_LIQUIDITY_ERROR: {}", err)));
 
     let liquidity = write_pool_manager(|pools| {
         let mut pool_info = pools.pool_mapping.get(&pool_id).unwrap();
@@ -570,7 +892,7 @@ pub async fn add_liquidity(
         pools.pool_mapping.insert(pool_id, pool_info);
         liquidity
     });
-    (liquidity, txids)
+    (liquidity, vec![])
 }
 
 #[derive(CandidType, Deserialize)]
@@ -582,15 +904,7 @@ pub struct RemoveLiquidityArgs {
     pub liquidity: u64,
 }
 
-pub fn remove_liquidity(
-    RemoveLiquidityArgs {
-        token0,
-        token1,
-        amount0_min,
-        amount1_min,
-        liquidity,
-    }: RemoveLiquidityArgs,
-) {
+pub fn remove_liquidity(RemoveLiquidityArgs { token0, token1, .. }: RemoveLiquidityArgs) {
     if token0 == token1 {
         ic_cdk::trap("REMOVE_LIQUIDITY_ERROR: Same Token")
     }
@@ -603,12 +917,22 @@ pub struct SwapArgs {
     pub token_out: TokenType,
     pub amount_in: u64,
     pub amount_out_min: u64,
+    // how fast the payout should be mined. the batch goes out at the highest
+    // rate its payouts ask for.
+    pub fee: Option<FeePreference>,
+    // bitcoin address to send `token_out` to with the next batch instead of
+    // crediting it to the internal balance
+    pub payout: Option<String>,
 }
 
+// swaps settle on the internal balance or in the next payout batch, they
+// have no transaction of their own
 #[derive(CandidType)]
 pub struct SwapResult {
     pub amount_out: u64,
-    pub txids: Vec<SubmittedTxidType>,
+    // the payout queued when `payout` is given. `get_payouts` lists it with
+    // its status, which carries the txid of the batch once it's submitted.
+    pub payout_id: Option<u64>,
 }
 
 #[update]
@@ -618,68 +942,35 @@ pub async fn swap(
         token_out,
         amount_in,
         amount_out_min,
+        fee,
+        payout,
     }: SwapArgs,
 ) -> SwapResult {
     let caller = ic_cdk::caller();
-    let caller_addresses = Addresses::from(&caller);
     if token_in == token_out {
         ic_cdk::trap("SWAP_ERROR: Same Token")
     }
-    let (pool_id, pool_addresses) = read_pool_manager(|pools| {
-        let pool_info = match pools.get_pool_id_by_tokens(token_in.clone(), token_out.clone()) {
-            None => ic_cdk::trap("ADD_LIQUIDITY_ERROR: Non-existing Pair"),
-            Some(id) => pools.pool_mapping.get(&id).unwrap(),
-        };
-        (
-            pool_info.pool_id,
-            Addresses::from(pool_info.allocated_raw_subaccount),
-        )
-    });
-
-    updater::fetch_utxos_and_update_balances(
-        &caller_addresses.bitcoin,
-        TargetType::Bitcoin { target: u64::MAX },
-    )
-    .await;
-    updater::fetch_utxos_and_update_balances(
-        &pool_addresses.bitcoin,
-        TargetType::Bitcoin { target: u64::MAX },
-    )
-    .await;
-
-    // balance check
-    match token_in.clone() {
-        TokenType::Icp => {
-            let balance = account_balance(
-                ic_ledger_types::MAINNET_LEDGER_CANISTER_ID,
-                ic_ledger_types::AccountBalanceArgs {
-                    account: caller_addresses.account_identifier,
-                },
-            )
-            .await
-            .unwrap()
-            .e8s();
-            if balance < amount_in {
-                ic_cdk::trap("Insufficient Balance")
-            }
-        }
-        TokenType::Bitcoin => {
-            let balance =
-                read_utxo_manager(|manager| manager.get_bitcoin_balance(&caller_addresses.bitcoin));
-            if balance < amount_in {
-                ic_cdk::trap("Insufficient Balance")
-            }
-        }
-        TokenType::Runestone(rune) => {
-            let balance = read_utxo_manager(|manager| {
-                manager.get_runestone_balance(&caller_addresses.bitcoin, &rune)
-            });
-            if balance < amount_in as u128 {
-                ic_cdk::trap("Insufficient Balance")
-            }
+    if let Some(ref to) = payout {
+        if !matches!(token_out, TokenType::Bitcoin | TokenType::Runestone(_)) {
+            ic_cdk::trap("SWAP_ERROR: Only bitcoin and runes are paid out on chain")
         }
-        _ => unimplemented!(),
+        chains::btc::address_validation(to)
+            .unwrap_or_else(|err| ic_cdk::trap(&format!("SWAP_ERROR: {}", err)));
+    } else if fee.is_some() {
+        ic_cdk::trap("SWAP_ERROR: A fee preference only applies to payouts")
     }
+    let pool_id = read_pool_manager(|pools| {
+        match pools.get_pool_id_by_tokens(token_in.clone(), token_out.clone()) {
+            None => ic_cdk::trap("SWAP_ERROR: Non-existing Pair"),
+            Some(id) => id,
+        }
+    });
+
+    updater::credit_deposits(&caller).await;
+
+    // balances and reserves move together, nothing below awaits
+    write_user_manager(|users| users.debit(&caller, token_in.clone(), amount_in as u128))
+        .unwrap_or_else(|err| ic_cdk::trap(&format!("SWAP_ERROR: {}", err)));
 
     let swap_result = write_pool_manager(|manager| {
         let mut pool = manager.pool_mapping.get(&pool_id).unwrap();
@@ -700,81 +991,461 @@ pub async fn swap(
         swap_result
     });
 
-    if let (TokenType::Bitcoin, TokenType::Runestone(rune)) =
-        (token_in.clone(), swap_result.token.clone())
-    {
-        read_utxo_manager(|manager| {
-            let btc_balance = manager.get_bitcoin_balance(&caller_addresses.bitcoin);
-            let rune_balance = manager.get_runestone_balance(&pool_addresses.bitcoin, &rune);
-            if btc_balance < amount_in || rune_balance < swap_result.amount as u128 {
-                ic_cdk::trap("Insufficient balance")
-            }
-        });
-        let caller_address = chains::btc::address_validation(&caller_addresses.bitcoin).unwrap();
-        let pool_address = chains::btc::address_validation(&pool_addresses.bitcoin).unwrap();
-        let fee_per_vbytes = chains::btc::get_fee_per_vbyte().await;
-
-        let txn = chains::btc::transaction::combined::transfer(CombinedTransactionArgs {
-            runeid: rune,
-            rune_amount: swap_result.amount as u128,
-            rune_sender: pool_address.clone(),
-            rune_receiver: caller_address.clone(),
-            rune_sender_account: pool_addresses.icrc1,
-            btc_amount: amount_in,
-            bitcoin_sender: caller_address.clone(),
-            bitcoin_receiver: pool_address,
-            bitcoin_sender_account: caller_addresses.icrc1,
-            fee_payer: caller_address,
-            fee_payer_account: caller_addresses.icrc1,
-            postage: None,
-            fee_per_vbytes,
+    // a payout is settled by the next batch, its amount stays out of the
+    // internal balance meanwhile
+    let payout_id = match payout {
+        Some(to) => Some(write_txn_manager(|txns| {
+            txns.queue_payout(Payout {
+                user: caller,
+                pool_id,
+                token: swap_result.token.clone(),
+                amount: swap_result.amount as u128,
+                to,
+                fee: 0,
+                fee_preference: fee,
+                queued_at: ic_cdk::api::time(),
+                status: PayoutStatus::Queued,
+            })
+        })),
+        None => {
+            write_user_manager(|users| {
+                users.credit(
+                    &caller,
+                    swap_result.token.clone(),
+                    swap_result.amount as u128,
+                )
+            });
+            None
+        }
+    };
+
+    SwapResult {
+        amount_out: swap_result.amount,
+        payout_id,
+    }
+}
+
+#[derive(CandidType)]
+pub struct PayoutQuery {
+    pub id: u64,
+    pub token: TokenType,
+    pub amount: u128,
+    pub to: String,
+    pub fee: u64,
+    pub queued_at: u64,
+    // shared by every payout of the same batch once submitted
+    pub status: PayoutStatus,
+}
+
+#[query]
+pub fn get_payouts() -> Vec<PayoutQuery> {
+    let caller = ic_cdk::caller();
+    read_txn_manager(|txns| txns.payouts_of(&caller))
+        .into_iter()
+        .map(|(id, payout)| PayoutQuery {
+            id,
+            token: payout.token,
+            amount: payout.amount,
+            to: payout.to,
+            fee: payout.fee,
+            queued_at: payout.queued_at,
+            status: payout.status,
         })
-        .unwrap();
-        let txid = txn.build_and_submit().await;
-        return SwapResult {
-            amount_out: swap_result.amount,
-            txids: vec![txid],
-        };
+        .collect()
+}
+
+// how long a swap psbt may take to come back signed
+const PSBT_SWAP_TTL_NANOS: u64 = 15 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Deserialize)]
+pub struct PsbtSwapArgs {
+    pub token_in: TokenType,
+    pub token_out: TokenType,
+    pub amount_in: u64,
+    pub amount_out_min: u64,
+    // segwit or taproot address of the user's own wallet
+    pub wallet_address: String,
+    pub fee: Option<FeePreference>,
+}
+
+#[derive(CandidType)]
+pub struct PsbtSwapQuote {
+    pub psbt: ByteBuf,
+    // of the unsigned transaction
+    pub txid: String,
+    pub amount_out: u64,
+    pub fee: u64,
+    pub expires_at: u64,
+}
+
+// the spendable canister address for `addr`
+fn canister_source(addr: &str) -> Result<(bitcoin::Address, Account), String> {
+    let address = chains::btc::address_validation(addr)?;
+    let account =
+        account_of_address(addr).ok_or_else(|| format!("{} isn't a canister address", addr))?;
+    Ok((address, account))
+}
+
+// quotes a swap between bitcoin and a rune settled in a single transaction
+// spending the caller's own wallet, which pays what goes in along with the
+// fee. the psbt comes back to `submit_swap_psbt` once the wallet signed its
+// inputs, the pool's utxos are held until then.
+#[update]
+pub async fn create_swap_psbt(
+    PsbtSwapArgs {
+        token_in,
+        token_out,
+        amount_in,
+        amount_out_min,
+        wallet_address,
+        fee,
+    }: PsbtSwapArgs,
+) -> Result<PsbtSwapQuote, String> {
+    let caller = ic_cdk::caller();
+    let runeid = match (&token_in, &token_out) {
+        (TokenType::Bitcoin, TokenType::Runestone(runeid))
+        | (TokenType::Runestone(runeid), TokenType::Bitcoin) => runeid.clone(),
+        _ => {
+            return Err(String::from(
+                "only bitcoin and rune pairs settle through a psbt",
+            ))
+        }
+    };
+    let wallet = chains::btc::address_validation(&wallet_address)?;
+    // the wallet's signatures are taken from its finalized witnesses, which
+    // legacy inputs don't have
+    if !matches!(
+        chains::btc::script_type(&wallet),
+        Some(ScriptType::P2wpkh | ScriptType::P2tr)
+    ) {
+        return Err(String::from("wallet must be a segwit or taproot address"));
+    }
+    if account_of_address(&wallet_address).is_some() {
+        return Err(String::from(
+            "wallet can't be one of the canister's addresses",
+        ));
+    }
+    let pool = read_pool_manager(|pools| {
+        pools
+            .get_pool_id_by_tokens(token_in.clone(), token_out.clone())
+            .and_then(|id| pools.pool_mapping.get(&id))
+    })
+    .ok_or_else(|| String::from("Non-existing Pair"))?;
+    let amount_out = pool.get_amount_out(amount_in, &token_in);
+    if amount_out < amount_out_min {
+        return Err(String::from("exceeds amount_out_min"));
     }
 
-    if let (TokenType::Runestone(rune), TokenType::Bitcoin) =
-        (token_in.clone(), swap_result.token.clone())
-    {
-        read_utxo_manager(|manager| {
-            let btc_balance = manager.get_bitcoin_balance(&pool_addresses.bitcoin);
-            let rune_balance = manager.get_runestone_balance(&caller_addresses.bitcoin, &rune);
-            if btc_balance < swap_result.amount || rune_balance < amount_in as u128 {
-                ic_cdk::trap("Insufficient balance")
-            }
-        });
-        let caller_address = chains::btc::address_validation(&caller_addresses.bitcoin).unwrap();
-        let pool_address = chains::btc::address_validation(&pool_addresses.bitcoin).unwrap();
-        let fee_per_vbytes = chains::btc::get_fee_per_vbyte().await;
+    let fee_per_vbytes = chains::btc::get_fee_per_vbyte(FeePurpose::Swap, fee).await;
+    let (utxos, runic_utxos) = updater::fetch_wallet_utxos(&wallet_address, Some(&runeid)).await?;
 
-        let txn = chains::btc::transaction::combined::transfer(CombinedTransactionArgs {
-            runeid: rune,
+    // nothing below awaits once the pool's utxos are selected
+    let pool_address = pool.deposit_addresses().bitcoin_segwit;
+    let receiver = chains::btc::address_validation(&pool_address)?;
+    // the wallet's inputs are signed by the user, never with this account
+    let wallet_account = Account {
+        owner: caller,
+        subaccount: None,
+    };
+    let wallet_utxos = Some(Wallet {
+        address: wallet.clone(),
+        utxos,
+        runic_utxos,
+    });
+    let args = if token_in == TokenType::Bitcoin {
+        let source = read_utxo_manager(|manager| {
+            manager.address_with_runestone_balance(&runeid, amount_out as u128, &pool_address)
+        })
+        .ok_or_else(|| String::from("not enough spendable runes"))?;
+        let (rune_sender, rune_sender_account) = canister_source(&source)?;
+        CombinedTransactionArgs {
+            runeid: runeid.clone(),
+            rune_amount: amount_out as u128,
+            rune_sender,
+            rune_receiver: wallet.clone(),
+            rune_sender_account,
+            btc_amount: amount_in,
+            bitcoin_sender: wallet.clone(),
+            bitcoin_receiver: receiver,
+            bitcoin_sender_account: wallet_account,
+            fee_payer: wallet.clone(),
+            fee_payer_account: wallet_account,
+            postage: None,
+            fee_per_vbytes,
+            strategy: Strategy::for_fee_rate(fee_per_vbytes),
+            wallet: wallet_utxos,
+        }
+    } else {
+        let source = read_utxo_manager(|manager| {
+            manager.address_with_bitcoin_balance(amount_out, &pool_address)
+        })
+        .ok_or_else(|| String::from("not enough spendable bitcoin"))?;
+        let (bitcoin_sender, bitcoin_sender_account) = canister_source(&source)?;
+        CombinedTransactionArgs {
+            runeid: runeid.clone(),
             rune_amount: amount_in as u128,
-            rune_sender: caller_address.clone(),
-            rune_receiver: pool_address.clone(),
-            rune_sender_account: caller_addresses.icrc1,
-            btc_amount: swap_result.amount,
-            bitcoin_sender: pool_address.clone(),
-            bitcoin_receiver: caller_address.clone(),
-            bitcoin_sender_account: pool_addresses.icrc1,
-            fee_payer: caller_address,
-            fee_payer_account: caller_addresses.icrc1,
+            rune_sender: wallet.clone(),
+            rune_receiver: receiver,
+            rune_sender_account: wallet_account,
+            btc_amount: amount_out,
+            bitcoin_sender,
+            bitcoin_receiver: wallet.clone(),
+            bitcoin_sender_account,
+            fee_payer: wallet.clone(),
+            fee_payer_account: wallet_account,
             postage: None,
             fee_per_vbytes,
+            strategy: Strategy::for_fee_rate(fee_per_vbytes),
+            wallet: wallet_utxos,
+        }
+    };
+    let txn =
+        chains::btc::transaction::combined::transfer(args).map_err(|(runes, sats, fee_sats)| {
+            format!(
+                "{} runes and {} sats required including fee",
+                runes,
+                sats + fee_sats
+            )
+        })?;
+
+    // inputs are told apart by the script they spend, every one has to be
+    // either the wallet's or the canister's
+    let wallet_script = wallet.script_pubkey();
+    let (mut wallet_inputs, mut inputs, mut unknown) = (vec![], vec![], false);
+    for input in txn.spent_inputs() {
+        let script =
+            chains::btc::address_validation(&input.address).map(|address| address.script_pubkey());
+        if script.as_ref() == Ok(&wallet_script) {
+            wallet_inputs.push(input);
+        } else if account_of_address(&input.address).is_some() {
+            inputs.push(input);
+        } else {
+            unknown = true;
+        }
+    }
+    if unknown {
+        release_inputs(inputs);
+        return Err(String::from(
+            "transaction spends an input of neither the wallet nor the canister",
+        ));
+    }
+    // the canister signs its inputs once the user hands the psbt back, after
+    // checking the wallet's signatures
+    if let Err(err) = txn.validate_runes() {
+        release_inputs(inputs);
+        return Err(err);
+    }
+    let psbt = match chains::btc::psbt::for_wallet(&txn, &wallet).await {
+        Ok(psbt) => psbt,
+        Err(err) => {
+            release_inputs(inputs);
+            return Err(err);
+        }
+    };
+    let spent = wallet_inputs
+        .iter()
+        .chain(inputs.iter())
+        .fold(0, |total, input| total + input.utxo.value);
+    let sent = psbt
+        .unsigned_tx
+        .output
+        .iter()
+        .fold(0, |total, output| total + output.value.to_sat());
+    let txid = psbt.unsigned_tx.compute_txid().to_string();
+    let expires_at = ic_cdk::api::time() + PSBT_SWAP_TTL_NANOS;
+    // the pool's utxos stay reserved for as long as the quote is valid
+    write_utxo_manager(|manager| manager.extend_reservations(&inputs, expires_at));
+
+    write_txn_manager(|txns| {
+        txns.record_psbt_swap(
+            txid.clone(),
+            PsbtSwap {
+                user: caller,
+                pool_id: pool.pool_id,
+                token_in,
+                amount_in,
+                amount_out,
+                raw: bitcoin::consensus::serialize(&psbt.unsigned_tx),
+                inputs,
+                wallet_address,
+                wallet_utxos: wallet_inputs.into_iter().map(|input| input.utxo).collect(),
+                expires_at,
+                submitted_at: None,
+            },
+        )
+    });
+    Ok(PsbtSwapQuote {
+        psbt: ByteBuf::from(psbt.serialize()),
+        txid,
+        amount_out,
+        fee: spent - sent,
+        expires_at,
+    })
+}
+
+// settles a swap quoted by `create_swap_psbt` with the psbt the wallet
+// signed its inputs of, as long as the price didn't get worse in the
+// meantime. the pool's reserves move at the quoted price once the
+// transaction confirms.
+#[update]
+pub async fn submit_swap_psbt(psbt: ByteBuf) -> Result<SubmittedTxidType, String> {
+    let caller = ic_cdk::caller();
+    let psbt = bitcoin::Psbt::deserialize(&psbt).map_err(|err| format!("invalid psbt: {}", err))?;
+    let txid = psbt.unsigned_tx.compute_txid().to_string();
+    let swap = read_txn_manager(|txns| txns.psbt_swap(&txid))
+        .ok_or_else(|| String::from("unknown swap"))?;
+    if swap.user != caller {
+        ic_cdk::trap("Unauthorized")
+    }
+    if swap.submitted_at.is_some() {
+        return Err(String::from("swap already submitted"));
+    }
+    if swap.expires_at <= ic_cdk::api::time() {
+        return Err(String::from("swap expired"));
+    }
+    let mut txn: bitcoin::Transaction = bitcoin::consensus::deserialize(&swap.raw)
+        .map_err(|err| format!("failed to decode transaction: {}", err))?;
+    let wallet_outpoints = swap
+        .wallet_utxos
+        .iter()
+        .map(|utxo| bitcoin::OutPoint {
+            txid: bitcoin::Txid::from_raw_hash(
+                bitcoin::hashes::Hash::from_slice(&utxo.outpoint.txid).unwrap(),
+            ),
+            vout: utxo.outpoint.vout,
         })
-        .unwrap();
-        let txid = txn.build_and_submit().await;
-        return SwapResult {
-            amount_out: swap_result.amount,
-            txids: vec![txid],
-        };
+        .collect::<Vec<_>>();
+    chains::btc::psbt::wallet_signatures(&psbt, &mut txn, &wallet_outpoints)?;
+
+    // the quote is checked against the reserves as they are now, they only
+    // move once the transaction confirms
+    let checked = read_pool_manager(|pools| {
+        let mut pool = pools.pool_mapping.get(&swap.pool_id).unwrap();
+        if pool.get_amount_out(swap.amount_in, &swap.token_in) < swap.amount_out {
+            return Err(String::from("price moved since the quote"));
+        }
+        let (amount0in, amount0out, amount1in, amount1out) =
+            pool.sort_tokens(&swap.token_in, swap.amount_in, swap.amount_out);
+        pool.swap(amount0in, amount1in, amount0out, amount1out)
+            .map(|_| ())
+    });
+    if let Err(err) = checked {
+        write_txn_manager(|txns| txns.remove_psbt_swap(&txid));
+        release_inputs(swap.inputs);
+        return Err(err);
+    }
+    write_txn_manager(|txns| txns.mark_psbt_swap_submitted(&txid, ic_cdk::api::time()));
+
+    match txn_handler::submit_psbt_swap(txn, &swap).await {
+        Ok(txid) => Ok(txid),
+        Err(err) => {
+            write_txn_manager(|txns| txns.remove_psbt_swap(&txid));
+            release_inputs(swap.inputs);
+            Err(err)
+        }
+    }
+}
+
+#[derive(CandidType)]
+pub struct SubmittedTxnQuery {
+    pub txid: String,
+    pub status: TxnStatus,
+    pub operation: Operation,
+    pub fee: u64,
+    pub fee_per_vbytes: u64,
+    pub broadcasts: u32,
+    pub submitted_at: u64,
+    pub last_broadcast_at: u64,
+}
+
+#[query]
+pub fn get_transaction_status(txid: String) -> Option<SubmittedTxnQuery> {
+    read_txn_manager(|txns| txns.get(&txid)).map(|txn| SubmittedTxnQuery {
+        txid,
+        status: txn.status,
+        operation: txn.operation,
+        fee: txn.fee,
+        fee_per_vbytes: txn.fee_per_vbytes,
+        broadcasts: txn.broadcasts,
+        submitted_at: txn.submitted_at,
+        last_broadcast_at: txn.last_broadcast_at,
+    })
+}
+
+// replaces a stuck transaction with one paying `fee_per_vbytes`, the current
+// median rate if not given. either stays within the fee policy's bounds.
+// only the user behind it or a controller may bump.
+#[update]
+pub async fn bump_fee(
+    txid: String,
+    fee_per_vbytes: Option<u64>,
+) -> Result<SubmittedTxidType, String> {
+    let caller = ic_cdk::caller();
+    let operation = read_txn_manager(|txns| txns.get(&txid))
+        .ok_or_else(|| String::from("unknown transaction"))?
+        .operation;
+    let allowed = match operation {
+        Operation::Withdrawal { user } | Operation::Swap { user, .. } => user == caller,
+        Operation::Consolidation { .. } | Operation::Payouts { .. } => false,
+    };
+    if !allowed && !read_config(|config| config.is_admin(&caller)) {
+        ic_cdk::trap("Unauthorized")
     }
+    let fee_per_vbytes = match fee_per_vbytes {
+        Some(rate) => {
+            let policy = read_config(|config| config.fee_policy());
+            rate.clamp(policy.min_fee_per_vbytes, policy.max_fee_per_vbytes)
+        }
+        None => chains::btc::get_fee_per_vbyte(FeePurpose::FeeBump, None).await,
+    };
+    txn_handler::bump_fee(&txid, fee_per_vbytes).await
+}
+
+#[derive(CandidType)]
+pub struct ReconciliationEntry {
+    pub token: TokenType,
+    pub user_balances: u128,
+    pub pool_reserves: u128,
+    // what the canister's bitcoin addresses hold, none for ledger tokens
+    pub held: Option<u128>,
+}
+
+#[query]
+pub fn get_reconciliation() -> Vec<ReconciliationEntry> {
+    let user_totals = read_user_manager(|users| users.totals());
+    let held = read_utxo_manager(|manager| manager.total_balances());
+    let reserves = read_pool_manager(|pools| {
+        let mut reserves: HashMap<TokenType, u128> = HashMap::new();
+        for (_, pool) in pools.pool_mapping.iter() {
+            *reserves.entry(pool.token0.clone()).or_insert(0) += pool.reserve0 as u128;
+            *reserves.entry(pool.token1.clone()).or_insert(0) += pool.reserve1 as u128;
+        }
+        reserves
+    });
 
-    unimplemented!("Unsupported Token Type: Supports swap between Runestones and Bitcoin only")
+    let mut tokens: Vec<TokenType> = user_totals
+        .keys()
+        .chain(reserves.keys())
+        .chain(held.keys())
+        .cloned()
+        .collect();
+    tokens.sort();
+    tokens.dedup();
+
+    tokens
+        .into_iter()
+        .map(|token| ReconciliationEntry {
+            user_balances: user_totals.get(&token).copied().unwrap_or(0),
+            pool_reserves: reserves.get(&token).copied().unwrap_or(0),
+            held: match token {
+                TokenType::Bitcoin | TokenType::Runestone(_) => {
+                    Some(held.get(&token).copied().unwrap_or(0))
+                }
+                TokenType::Icp | TokenType::CkBTC => None,
+            },
+            token,
+        })
+        .collect()
 }
 
 ic_cdk::export_candid!();
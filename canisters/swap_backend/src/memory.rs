@@ -11,6 +11,17 @@ pub enum MemoryIds {
     AssociatedPoolSet,
     Bitcoin,
     Runic,
+    Balances,
+    CreditedUtxos,
+    AddressBook,
+    PendingBalances,
+    ScriptTypes,
+    SubmittedTxns,
+    PsbtSwaps,
+    Payouts,
+    Reservations,
+    SyncStates,
+    RuneCache,
 }
 
 impl From<MemoryIds> for MemoryId {
@@ -21,6 +32,17 @@ impl From<MemoryIds> for MemoryId {
             MemoryIds::AssociatedPoolSet => 3,
             MemoryIds::Bitcoin => 4,
             MemoryIds::Runic => 5,
+            MemoryIds::Balances => 6,
+            MemoryIds::CreditedUtxos => 7,
+            MemoryIds::AddressBook => 8,
+            MemoryIds::PendingBalances => 9,
+            MemoryIds::ScriptTypes => 10,
+            MemoryIds::SubmittedTxns => 11,
+            MemoryIds::PsbtSwaps => 12,
+            MemoryIds::Payouts => 13,
+            MemoryIds::Reservations => 14,
+            MemoryIds::SyncStates => 15,
+            MemoryIds::RuneCache => 16,
         };
         MemoryId::new(id)
     }
@@ -1,17 +1,18 @@
-use candid::{CandidType, Deserialize, Principal};
-use ic_cdk::api::call::CallResult;
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::{call::CallResult, management_canister::bitcoin::Utxo};
 
-use crate::types::RuneId;
+use crate::{state::read_config, types::RuneId, updater::txid_to_string};
 
-const ORD_CANISTER: &str = "o25oi-jaaaa-aaaal-ajj6a-cai";
+// the most outpoints the ord canister looks up in one query
+const MAX_OUTPOINTS_PER_QUERY: usize = 500;
 
-#[derive(CandidType, Deserialize, Debug)]
+#[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct RuneBalance {
     pub id: RuneId,
     pub balance: u128,
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Debug)]
 pub enum MintError {
     Cap(u128),
     End(u64),
@@ -19,14 +20,14 @@ pub enum MintError {
     Unmintable,
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Debug)]
 pub enum RpcError {
     Io(String, String, String),
     Decode(String, String, String),
     Endpoint(String, String, String),
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Debug)]
 pub enum OrdError {
     Params(String),
     Overflow,
@@ -35,9 +36,38 @@ pub enum OrdError {
     Rpc(RpcError),
 }
 
-pub type GetRunesResult = Result<Vec<RuneBalance>, OrdError>;
+pub type GetRunesBatchResult = Result<Vec<Vec<RuneBalance>>, OrdError>;
+
+// the runes of every utxo, in the order given. large sets are split into
+// as few queries as the ord canister allows.
+pub async fn get_runes_by_utxos(utxos: &[Utxo]) -> CallResult<GetRunesBatchResult> {
+    let ord_canister = read_config(|config| config.ord_canister());
+    let mut runes = Vec::with_capacity(utxos.len());
+    for chunk in utxos.chunks(MAX_OUTPOINTS_PER_QUERY) {
+        let outpoints = chunk
+            .iter()
+            .map(|utxo| (txid_to_string(&utxo.outpoint.txid), utxo.outpoint.vout))
+            .collect::<Vec<_>>();
+        let (result,): (GetRunesBatchResult,) =
+            ic_cdk::call(ord_canister, "get_runes_by_utxos", (outpoints,)).await?;
+        match result {
+            Ok(found) => runes.extend(found),
+            Err(err) => return Ok(Err(err)),
+        }
+    }
+    Ok(Ok(runes))
+}
+
+pub type GetHeightResult = Result<(u32, String), OrdError>;
+
+pub async fn get_height() -> CallResult<(GetHeightResult,)> {
+    let ord_canister = read_config(|config| config.ord_canister());
+    ic_cdk::call(ord_canister, "get_height", ()).await
+}
+
+pub type GetRawTransactionResult = Result<Vec<u8>, OrdError>;
 
-pub async fn get_runes_by_utxo(txid: String, vout: u32) -> CallResult<(GetRunesResult,)> {
-    let ord_canister = Principal::from_text(ORD_CANISTER).unwrap();
-    ic_cdk::call(ord_canister, "get_runes_by_utxo", (txid, vout)).await
+pub async fn get_raw_transaction(txid: &str) -> CallResult<(GetRawTransactionResult,)> {
+    let ord_canister = read_config(|config| config.ord_canister());
+    ic_cdk::call(ord_canister, "get_raw_transaction", (txid,)).await
 }
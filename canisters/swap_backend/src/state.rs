@@ -3,18 +3,25 @@ use std::cell::RefCell;
 use config::{init_stable_config, Config, StableConfig};
 use ic_stable_structures::{memory_manager::MemoryManager, DefaultMemoryImpl};
 use pool_manager::PoolState;
+use txn_manager::TxnManager;
+use user_manager::UserManager;
 use utxo_manager::UtxoManager;
 
-mod config;
+pub mod config;
+pub mod migration;
 pub mod pool_manager;
-mod user_manager;
-mod utxo_manager;
+mod schema;
+pub mod txn_manager;
+pub mod user_manager;
+pub mod utxo_manager;
 
 thread_local! {
     pub static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
     pub static CONFIG: RefCell<StableConfig> = RefCell::new(init_stable_config());
     pub static UTXO_MANAGER: RefCell<UtxoManager> = RefCell::default();
     pub static POOL_MANAGER: RefCell<PoolState> = RefCell::default();
+    pub static USER_MANAGER: RefCell<UserManager> = RefCell::default();
+    pub static TXN_MANAGER: RefCell<TxnManager> = RefCell::default();
 }
 
 pub fn read_memory_manager<F, R>(f: F) -> R
@@ -65,3 +72,31 @@ where
 {
     POOL_MANAGER.with_borrow_mut(|pools| f(pools))
 }
+
+pub fn read_user_manager<F, R>(f: F) -> R
+where
+    F: FnOnce(&UserManager) -> R,
+{
+    USER_MANAGER.with_borrow(|users| f(users))
+}
+
+pub fn write_user_manager<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut UserManager) -> R,
+{
+    USER_MANAGER.with_borrow_mut(|users| f(users))
+}
+
+pub fn read_txn_manager<F, R>(f: F) -> R
+where
+    F: FnOnce(&TxnManager) -> R,
+{
+    TXN_MANAGER.with_borrow(|txns| f(txns))
+}
+
+pub fn write_txn_manager<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut TxnManager) -> R,
+{
+    TXN_MANAGER.with_borrow_mut(|txns| f(txns))
+}
@@ -2,15 +2,88 @@ use crate::{
     memory::{Memory, MemoryIds},
     EcdsaPublicKey,
 };
-use candid::{CandidType, Decode, Encode, Principal};
+use candid::{CandidType, Principal};
 use ic_cdk::api::management_canister::{
     bitcoin::BitcoinNetwork,
     ecdsa::{EcdsaCurve, EcdsaKeyId},
+    schnorr::{SchnorrAlgorithm, SchnorrKeyId, SchnorrPublicKeyResponse},
 };
 use ic_stable_structures::{storable::Bound, StableCell, Storable};
 use serde::Deserialize;
 
-use super::read_memory_manager;
+use super::{
+    migration::MigrationProgress,
+    read_memory_manager,
+    schema::{self, Versioned},
+};
+
+pub const DEFAULT_BTC_MIN_CONFIRMATIONS: u32 = 2;
+pub const DEFAULT_RUNE_MIN_CONFIRMATIONS: u32 = 6;
+// the public rune indexer, only indexing mainnet
+pub const MAINNET_ORD_CANISTER: &str = "o25oi-jaaaa-aaaal-ajj6a-cai";
+
+// what a bitcoin transaction is sent for, each picks its own fee percentile
+#[derive(Clone, Copy, Debug)]
+pub enum FeePurpose {
+    Withdrawal,
+    Swap,
+    Consolidation,
+    FeeBump,
+}
+
+// fee rates are in millisats per vbyte, same unit as the fee percentiles
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FeePolicy {
+    pub withdrawal_percentile: u8,
+    pub swap_percentile: u8,
+    pub consolidation_percentile: u8,
+    pub fee_bump_percentile: u8,
+    pub min_fee_per_vbytes: u64,
+    pub max_fee_per_vbytes: u64,
+    // sats a single transaction may pay in fees
+    pub max_fee: u64,
+}
+
+impl Default for FeePolicy {
+    fn default() -> Self {
+        Self {
+            withdrawal_percentile: 50,
+            swap_percentile: 50,
+            consolidation_percentile: 25,
+            fee_bump_percentile: 75,
+            min_fee_per_vbytes: 1_000,
+            max_fee_per_vbytes: 500_000,
+            max_fee: 1_000_000,
+        }
+    }
+}
+
+impl FeePolicy {
+    pub fn percentile(&self, purpose: FeePurpose) -> u8 {
+        match purpose {
+            FeePurpose::Withdrawal => self.withdrawal_percentile,
+            FeePurpose::Swap => self.swap_percentile,
+            FeePurpose::Consolidation => self.consolidation_percentile,
+            FeePurpose::FeeBump => self.fee_bump_percentile,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let percentiles = [
+            self.withdrawal_percentile,
+            self.swap_percentile,
+            self.consolidation_percentile,
+            self.fee_bump_percentile,
+        ];
+        if percentiles.iter().any(|percentile| *percentile > 100) {
+            return Err(String::from("percentiles go up to 100"));
+        }
+        if self.min_fee_per_vbytes > self.max_fee_per_vbytes {
+            return Err(String::from("min fee rate is above the max fee rate"));
+        }
+        Ok(())
+    }
+}
 
 #[derive(CandidType, Deserialize, Default, Clone)]
 pub struct Config {
@@ -18,16 +91,62 @@ pub struct Config {
     pub bitcoin_network: Option<BitcoinNetwork>,
     pub keyname: Option<String>,
     pub ecdsa_public_key: Option<EcdsaPublicKey>,
+    pub schnorr_keyname: Option<String>,
+    pub schnorr_public_key: Option<SchnorrPublicKeyResponse>,
     pub commission_receiver_principal: Option<Principal>,
+    pub icp_ledger: Option<Principal>,
+    pub btc_min_confirmations: Option<u32>,
+    pub rune_min_confirmations: Option<u32>,
+    pub fee_policy: Option<FeePolicy>,
+    pub ord_canister: Option<Principal>,
+    pub ckbtc_ledger: Option<Principal>,
+    pub admins: Option<Vec<Principal>>,
+    // none for canisters installed before the schema was versioned
+    pub schema_version: Option<u32>,
+    pub migration: Option<MigrationProgress>,
+}
+
+// the argument the canister is installed or upgraded with
+#[derive(CandidType, Deserialize)]
+pub enum SwapBackendArgs {
+    Init(InitArgs),
+    Upgrade(Option<UpgradeArgs>),
+}
+
+// the key names default to the network's threshold key
+#[derive(CandidType, Deserialize)]
+pub struct InitArgs {
+    pub bitcoin_network: BitcoinNetwork,
+    pub keyname: Option<String>,
+    pub schnorr_keyname: Option<String>,
+    pub settings: UpgradeArgs,
+}
+
+// settings left out keep their current value. the network and the key names
+// can't change after install, every derived address depends on them.
+#[derive(CandidType, Deserialize, Default)]
+pub struct UpgradeArgs {
+    pub ord_canister: Option<Principal>,
+    pub icp_ledger: Option<Principal>,
+    pub ckbtc_ledger: Option<Principal>,
+    pub fee_policy: Option<FeePolicy>,
+    pub commission_receiver: Option<Principal>,
+    pub admins: Option<Vec<Principal>>,
+    pub btc_min_confirmations: Option<u32>,
+    pub rune_min_confirmations: Option<u32>,
+}
+
+impl Versioned for Config {
+    const VERSION: u8 = 1;
 }
 
 impl Storable for Config {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        std::borrow::Cow::Owned(Encode!(self).expect("should encode"))
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        schema::to_bytes(self)
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).expect("should decode")
+        schema::from_bytes(bytes)
     }
 
     const BOUND: Bound = Bound::Unbounded;
@@ -66,9 +185,153 @@ impl Config {
         }
     }
 
+    // threshold keys share their names across algorithms, so configs written
+    // before taproot fall back to the ecdsa keyname
+    pub fn schnorr_keyname(&self) -> String {
+        match self.schnorr_keyname {
+            Some(ref keyname) => keyname.clone(),
+            None => self.keyname(),
+        }
+    }
+
+    pub fn schnorr_public_key(&self) -> SchnorrPublicKeyResponse {
+        if let Some(ref schnorr_key) = self.schnorr_public_key {
+            schnorr_key.clone()
+        } else {
+            ic_cdk::trap("canister's config uninitialized")
+        }
+    }
+
+    pub fn schnorrkeyid(&self) -> SchnorrKeyId {
+        let name = self.schnorr_keyname();
+        SchnorrKeyId {
+            algorithm: SchnorrAlgorithm::Bip340secp256k1,
+            name,
+        }
+    }
+
     pub fn commission_receiver_principal(&self) -> Principal {
         self.commission_receiver_principal.unwrap_or(ic_cdk::id())
     }
+
+    pub fn icp_ledger(&self) -> Principal {
+        self.icp_ledger
+            .unwrap_or(ic_ledger_types::MAINNET_LEDGER_CANISTER_ID)
+    }
+
+    pub fn btc_min_confirmations(&self) -> u32 {
+        self.btc_min_confirmations
+            .unwrap_or(DEFAULT_BTC_MIN_CONFIRMATIONS)
+    }
+
+    pub fn rune_min_confirmations(&self) -> u32 {
+        self.rune_min_confirmations
+            .unwrap_or(DEFAULT_RUNE_MIN_CONFIRMATIONS)
+    }
+
+    pub fn schema_version(&self) -> u32 {
+        self.schema_version.unwrap_or_default()
+    }
+
+    pub fn fee_policy(&self) -> FeePolicy {
+        self.fee_policy.clone().unwrap_or_default()
+    }
+
+    pub fn admins(&self) -> Vec<Principal> {
+        self.admins.clone().unwrap_or_default()
+    }
+
+    // controllers are always admins
+    pub fn is_admin(&self, principal: &Principal) -> bool {
+        ic_cdk::api::is_controller(principal) || self.admins().contains(principal)
+    }
+
+    pub fn apply_init(&mut self, args: InitArgs) -> Result<(), String> {
+        let default_keyname = match args.bitcoin_network {
+            BitcoinNetwork::Mainnet => "key_1",
+            BitcoinNetwork::Testnet => "test_key_1",
+            BitcoinNetwork::Regtest => "dfx_test_key",
+        };
+        let keyname = args.keyname.unwrap_or(String::from(default_keyname));
+        let schnorr_keyname = args.schnorr_keyname.unwrap_or(keyname.clone());
+        if keyname.is_empty() || schnorr_keyname.is_empty() {
+            return Err(String::from("key names can't be empty"));
+        }
+        self.keyname.replace(keyname);
+        self.schnorr_keyname.replace(schnorr_keyname);
+        self.bitcoin_network.replace(args.bitcoin_network);
+        self.apply_upgrade(args.settings)?;
+        if self.ord_canister.is_none() && args.bitcoin_network != BitcoinNetwork::Mainnet {
+            return Err(String::from("the ord canister is required off mainnet"));
+        }
+        Ok(())
+    }
+
+    pub fn apply_upgrade(&mut self, args: UpgradeArgs) -> Result<(), String> {
+        if let Some(ref policy) = args.fee_policy {
+            policy.validate()?;
+        }
+        let btc_min_confirmations = args
+            .btc_min_confirmations
+            .unwrap_or(self.btc_min_confirmations());
+        let rune_min_confirmations = args
+            .rune_min_confirmations
+            .unwrap_or(self.rune_min_confirmations());
+        if btc_min_confirmations == 0 || rune_min_confirmations == 0 {
+            return Err(String::from("confirmations must be at least 1"));
+        }
+        for (name, id) in [
+            ("ord canister", args.ord_canister),
+            ("icp ledger", args.icp_ledger),
+            ("ckbtc ledger", args.ckbtc_ledger),
+        ] {
+            if let Some(id) = id {
+                validate_canister_id(name, &id)?;
+            }
+        }
+        let icp_ledger = args.icp_ledger.unwrap_or(self.icp_ledger());
+        if args.ckbtc_ledger.or(self.ckbtc_ledger) == Some(icp_ledger) {
+            return Err(String::from("the ckbtc and icp ledgers must differ"));
+        }
+        let UpgradeArgs {
+            ord_canister,
+            icp_ledger,
+            ckbtc_ledger,
+            fee_policy,
+            commission_receiver,
+            admins,
+            btc_min_confirmations,
+            rune_min_confirmations,
+        } = args;
+        self.ord_canister = ord_canister.or(self.ord_canister);
+        self.icp_ledger = icp_ledger.or(self.icp_ledger);
+        self.ckbtc_ledger = ckbtc_ledger.or(self.ckbtc_ledger);
+        self.fee_policy = fee_policy.or(self.fee_policy.take());
+        self.commission_receiver_principal =
+            commission_receiver.or(self.commission_receiver_principal);
+        self.admins = admins.or(self.admins.take());
+        self.btc_min_confirmations = btc_min_confirmations.or(self.btc_min_confirmations);
+        self.rune_min_confirmations = rune_min_confirmations.or(self.rune_min_confirmations);
+        Ok(())
+    }
+
+    // testnet and regtest have no public indexer, theirs is set at init
+    pub fn ord_canister(&self) -> Principal {
+        match (self.ord_canister, self.bitcoin_network()) {
+            (Some(ord_canister), _) => ord_canister,
+            (None, BitcoinNetwork::Mainnet) => Principal::from_text(MAINNET_ORD_CANISTER).unwrap(),
+            (None, _) => ic_cdk::trap("ord canister unset"),
+        }
+    }
+}
+
+// anonymous and the management canister are what an omitted or mistyped id
+// usually ends up as
+fn validate_canister_id(name: &str, id: &Principal) -> Result<(), String> {
+    if *id == Principal::anonymous() || *id == Principal::management_canister() {
+        return Err(format!("{} isn't a canister id", name));
+    }
+    Ok(())
 }
 
 pub type StableConfig = StableCell<Config, Memory>;
@@ -0,0 +1,252 @@
+use std::{borrow::Cow, ops::Bound, time::Duration};
+
+use candid::CandidType;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use serde::Deserialize;
+
+use crate::memory::Memory;
+
+use super::{
+    read_config, schema::SCHEMA_VERSION, write_config, write_pool_manager, write_txn_manager,
+    write_user_manager, write_utxo_manager,
+};
+
+// records rewritten per timer tick, bounded to stay within the instruction limit
+const MIGRATION_BATCH: usize = 100;
+
+// how far the running migration got, kept in the config so an upgrade in
+// the middle of it picks up where it stopped
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MigrationProgress {
+    pub step: u32,
+    // the last key rewritten by the step
+    pub cursor: Option<Vec<u8>>,
+}
+
+// rewrites a batch of records after `cursor`, returning the cursor to
+// resume from or none once the step is done
+type Step = fn(Option<Vec<u8>>) -> Option<Vec<u8>>;
+
+// what a schema version needs rewritten, in order
+const STEPS: &[(u32, &str, Step)] = &[
+    (1, "config", rewrite_config),
+    (1, "pools", rewrite_pools),
+    (1, "bitcoin utxos", rewrite_bitcoin_utxos),
+    (1, "runic utxos", rewrite_runic_utxos),
+    (1, "reservations", rewrite_reservations),
+    (1, "sync states", rewrite_sync_states),
+    (1, "rune cache", rewrite_rune_cache),
+    (1, "balances", rewrite_balances),
+    (1, "pending deposits", rewrite_pending),
+    (1, "submitted transactions", rewrite_submitted_txns),
+    (1, "psbt swaps", rewrite_psbt_swaps),
+    (1, "payouts", rewrite_payouts),
+];
+
+fn rewrite_config(_: Option<Vec<u8>>) -> Option<Vec<u8>> {
+    write_config(|config| {
+        let temp = config.get().clone();
+        let _ = config.set(temp);
+    });
+    None
+}
+
+fn rewrite_pools(cursor: Option<Vec<u8>>) -> Option<Vec<u8>> {
+    write_pool_manager(|pools| rewrite(&mut pools.pool_mapping, cursor))
+}
+
+fn rewrite_bitcoin_utxos(cursor: Option<Vec<u8>>) -> Option<Vec<u8>> {
+    write_utxo_manager(|manager| rewrite(&mut manager.b, cursor))
+}
+
+fn rewrite_runic_utxos(cursor: Option<Vec<u8>>) -> Option<Vec<u8>> {
+    write_utxo_manager(|manager| rewrite(&mut manager.r, cursor))
+}
+
+fn rewrite_reservations(cursor: Option<Vec<u8>>) -> Option<Vec<u8>> {
+    write_utxo_manager(|manager| rewrite(&mut manager.reservations, cursor))
+}
+
+fn rewrite_sync_states(cursor: Option<Vec<u8>>) -> Option<Vec<u8>> {
+    write_utxo_manager(|manager| rewrite(&mut manager.syncs, cursor))
+}
+
+fn rewrite_rune_cache(cursor: Option<Vec<u8>>) -> Option<Vec<u8>> {
+    write_utxo_manager(|manager| rewrite(&mut manager.runes, cursor))
+}
+
+fn rewrite_balances(cursor: Option<Vec<u8>>) -> Option<Vec<u8>> {
+    write_user_manager(|users| rewrite(&mut users.balances, cursor))
+}
+
+fn rewrite_pending(cursor: Option<Vec<u8>>) -> Option<Vec<u8>> {
+    write_user_manager(|users| rewrite(&mut users.pending, cursor))
+}
+
+fn rewrite_submitted_txns(cursor: Option<Vec<u8>>) -> Option<Vec<u8>> {
+    write_txn_manager(|txns| rewrite(&mut txns.submitted, cursor))
+}
+
+fn rewrite_psbt_swaps(cursor: Option<Vec<u8>>) -> Option<Vec<u8>> {
+    write_txn_manager(|txns| rewrite(&mut txns.psbt_swaps, cursor))
+}
+
+fn rewrite_payouts(cursor: Option<Vec<u8>>) -> Option<Vec<u8>> {
+    write_txn_manager(|txns| rewrite(&mut txns.payouts, cursor))
+}
+
+// reading a record takes any version, inserting it back stores the current one
+fn rewrite<K, V>(map: &mut StableBTreeMap<K, V, Memory>, cursor: Option<Vec<u8>>) -> Option<Vec<u8>>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+{
+    let start = match cursor {
+        Some(bytes) => Bound::Excluded(K::from_bytes(Cow::Owned(bytes))),
+        None => Bound::Unbounded,
+    };
+    let batch = map
+        .range((start, Bound::Unbounded))
+        .take(MIGRATION_BATCH)
+        .collect::<Vec<_>>();
+    let last = batch.last().map(|(key, _)| key.to_bytes().into_owned());
+    let done = batch.len() < MIGRATION_BATCH;
+    for (key, value) in batch {
+        map.insert(key, value);
+    }
+    if done {
+        None
+    } else {
+        last
+    }
+}
+
+// fresh installs start out at the current schema
+pub fn init_schema() {
+    write_config(|config| {
+        let mut temp = config.get().clone();
+        temp.schema_version.replace(SCHEMA_VERSION);
+        let _ = config.set(temp);
+    });
+}
+
+// brings stable memory up to the current schema on timers. records of older
+// versions stay readable meanwhile, so nothing waits on it.
+pub fn start_migrations() {
+    let (version, progress) =
+        read_config(|config| (config.schema_version(), config.migration.clone()));
+    if version >= SCHEMA_VERSION {
+        return;
+    }
+    if progress.is_none() {
+        let step = STEPS
+            .iter()
+            .position(|(to_version, _, _)| *to_version > version)
+            .unwrap_or(STEPS.len()) as u32;
+        set_progress(Some(MigrationProgress { step, cursor: None }));
+    }
+    ic_cdk_timers::set_timer(Duration::ZERO, run_migration_batch);
+}
+
+fn set_progress(progress: Option<MigrationProgress>) {
+    write_config(|config| {
+        let mut temp = config.get().clone();
+        temp.migration = progress;
+        let _ = config.set(temp);
+    });
+}
+
+fn run_migration_batch() {
+    let Some(MigrationProgress { step, cursor }) = read_config(|config| config.migration.clone())
+    else {
+        return;
+    };
+    let Some((_, name, run)) = STEPS.get(step as usize) else {
+        write_config(|config| {
+            let mut temp = config.get().clone();
+            temp.schema_version.replace(SCHEMA_VERSION);
+            temp.migration = None;
+            let _ = config.set(temp);
+        });
+        ic_cdk::println!("migrated stable memory to schema {}", SCHEMA_VERSION);
+        return;
+    };
+    let progress = match run(cursor) {
+        Some(cursor) => MigrationProgress {
+            step,
+            cursor: Some(cursor),
+        },
+        None => {
+            ic_cdk::println!("migrated {}", name);
+            MigrationProgress {
+                step: step + 1,
+                cursor: None,
+            }
+        }
+    };
+    set_progress(Some(progress));
+    ic_cdk_timers::set_timer(Duration::ZERO, run_migration_batch);
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Encode;
+    use ic_stable_structures::{
+        memory_manager::{MemoryId, MemoryManager},
+        DefaultMemoryImpl,
+    };
+
+    use super::*;
+    use crate::{ord_canister::RuneBalance, state::utxo_manager::OutpointRunes, types::RuneId};
+
+    fn runes(block: u64) -> OutpointRunes {
+        OutpointRunes(vec![RuneBalance {
+            id: RuneId { block, tx: 1 },
+            balance: block as u128 * 10,
+        }])
+    }
+
+    #[test]
+    fn steps_are_in_schema_order() {
+        assert!(STEPS.windows(2).all(|steps| steps[0].0 <= steps[1].0));
+        assert!(STEPS
+            .iter()
+            .all(|(version, _, _)| *version <= SCHEMA_VERSION));
+    }
+
+    #[test]
+    fn rewrite_stores_every_record_at_the_current_version() {
+        let manager = MemoryManager::init(DefaultMemoryImpl::default());
+        let count = MIGRATION_BATCH as u64 * 2 + 50;
+
+        // records stored before versioning are bare candid
+        let mut raw: StableBTreeMap<String, Vec<u8>, Memory> =
+            StableBTreeMap::init(manager.get(MemoryId::new(0)));
+        for block in 0..count {
+            raw.insert(format!("{:04}", block), Encode!(&runes(block)).unwrap());
+        }
+
+        let mut map: StableBTreeMap<String, OutpointRunes, Memory> =
+            StableBTreeMap::init(manager.get(MemoryId::new(0)));
+        let (mut cursor, mut batches) = (None, 0);
+        loop {
+            cursor = rewrite(&mut map, cursor);
+            batches += 1;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(batches, 3);
+
+        let raw: StableBTreeMap<String, Vec<u8>, Memory> =
+            StableBTreeMap::init(manager.get(MemoryId::new(0)));
+        assert_eq!(raw.len(), count);
+        assert!(raw.iter().all(|(_, bytes)| bytes[0] == 1));
+        for (key, OutpointRunes(balances)) in map.iter() {
+            let block = key.parse::<u64>().unwrap();
+            assert_eq!(balances.len(), 1);
+            assert_eq!(balances[0].id, RuneId { block, tx: 1 });
+            assert_eq!(balances[0].balance, block as u128 * 10);
+        }
+    }
+}
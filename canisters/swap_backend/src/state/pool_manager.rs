@@ -12,7 +12,10 @@ use crate::{
     PoolInfoQuery,
 };
 
-use super::{read_config, read_memory_manager};
+use super::{
+    read_config, read_memory_manager,
+    schema::{self, Versioned},
+};
 
 const MINIMUM_LIQUIDITY: u64 = 1_000;
 
@@ -31,18 +34,24 @@ pub struct PoolInfo {
     pub holders: HashMap<Principal, u64>,
 }
 
+impl Versioned for PoolInfo {
+    const VERSION: u8 = 1;
+}
+
 impl Storable for PoolInfo {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        std::borrow::Cow::Owned(Encode!(self).expect("should encode"))
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        schema::to_bytes(self)
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).expect("should decode")
+        schema::from_bytes(bytes)
     }
 
     const BOUND: Bound = Bound::Unbounded;
 }
 
+// for remove_liquidity, which isn't implemented yet
+#[allow(dead_code)]
 pub struct BurnResult {
     pub raw_subaccount: [u8; 32],
     pub token0: TokenType,
@@ -52,13 +61,12 @@ pub struct BurnResult {
 }
 
 pub struct SwapResult {
-    pub raw_subaccount: [u8; 32],
     pub token: TokenType,
     pub amount: u64,
 }
 
 impl PoolInfo {
-    pub fn into_query(&self) -> PoolInfoQuery {
+    pub fn to_query(&self) -> PoolInfoQuery {
         PoolInfoQuery {
             pool_id: self.pool_id,
             deposit_addresses: self.deposit_addresses(),
@@ -187,6 +195,7 @@ impl PoolInfo {
         self.holders.insert(*from, current_liquidity - liquidity);
     }
 
+    #[allow(dead_code)]
     pub fn burn(
         &mut self,
         caller: &Principal,
@@ -263,11 +272,50 @@ impl PoolInfo {
         } else {
             (self.token1.clone(), amount1_out)
         };
-        Ok(SwapResult {
-            raw_subaccount: self.allocated_raw_subaccount,
-            token,
-            amount,
-        })
+        Ok(SwapResult { token, amount })
+    }
+
+    // the bitcoin reserve of the pool, if it trades bitcoin
+    fn bitcoin_reserve(&mut self) -> Option<&mut u64> {
+        if self.token0 == TokenType::Bitcoin {
+            Some(&mut self.reserve0)
+        } else if self.token1 == TokenType::Bitcoin {
+            Some(&mut self.reserve1)
+        } else {
+            None
+        }
+    }
+
+    // on-chain fees for moving the pool's own utxos around come out of its
+    // bitcoin reserve
+    pub fn pay_fee(&mut self, fee: u64) -> Result<(), String> {
+        let reserve = self
+            .bitcoin_reserve()
+            .ok_or_else(|| String::from("pool has no bitcoin reserve"))?;
+        *reserve = reserve
+            .checked_sub(fee)
+            .ok_or_else(|| String::from("bitcoin reserve can't cover the fee"))?;
+        Ok(())
+    }
+
+    pub fn refund_fee(&mut self, fee: u64) {
+        if let Some(reserve) = self.bitcoin_reserve() {
+            *reserve += fee;
+        }
+    }
+
+    // takes in a swap once its transaction confirmed. it was checked against
+    // the reserves when submitted, whatever they went through since.
+    pub fn settle_swap(
+        &mut self,
+        amount0_in: u64,
+        amount1_in: u64,
+        amount0_out: u64,
+        amount1_out: u64,
+    ) {
+        self.reserve0 = (self.reserve0 + amount0_in).saturating_sub(amount0_out);
+        self.reserve1 = (self.reserve1 + amount1_in).saturating_sub(amount1_out);
+        self.last_updated = ic_cdk::api::time();
     }
 }
 
@@ -288,7 +336,7 @@ impl Storable for AssociatedPoolKey {
         Decode!(bytes.as_ref(), Self).expect("should decode")
     }
 
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         std::borrow::Cow::Owned(Encode!(self).expect("shoulde encode"))
     }
 
@@ -338,6 +386,13 @@ impl PoolState {
         None
     }
 
+    pub fn pool_id_by_subaccount(&self, subaccount: &[u8; 32]) -> Option<u128> {
+        self.pool_mapping
+            .iter()
+            .find(|(_, pool)| &pool.allocated_raw_subaccount == subaccount)
+            .map(|(id, _)| id)
+    }
+
     pub fn create_pair(&mut self, pool_info: PoolInfo) {
         self.associated_map.insert(
             AssociatedPoolKey(pool_info.token0.clone(), pool_info.token1.clone()),
@@ -0,0 +1,128 @@
+use std::borrow::Cow;
+
+use candid::{CandidType, Decode, Encode};
+use serde::de::DeserializeOwned;
+
+// the layout of stable memory the code expects. bump it along with the
+// version of every record that changes shape, and add the migrations
+// rewriting them.
+pub const SCHEMA_VERSION: u32 = 1;
+
+// bare candid starts with its magic, which is how records stored before
+// versioning are told apart. record versions stay below its first byte.
+const CANDID_MAGIC: &[u8] = b"DIDL";
+
+// a record stored as its version byte followed by its candid
+pub trait Versioned: CandidType + DeserializeOwned {
+    const VERSION: u8;
+
+    // reads a record written with an older `version`, 0 being the ones
+    // stored before versioning. every version so far shares the current shape.
+    fn decode_version(version: u8, bytes: &[u8]) -> Result<Self, candid::Error> {
+        let _ = version;
+        Decode!(bytes, Self)
+    }
+}
+
+pub fn to_bytes<T: Versioned>(record: &T) -> Cow<'_, [u8]> {
+    let mut bytes = vec![T::VERSION];
+    bytes.extend(Encode!(record).expect("should encode"));
+    Cow::Owned(bytes)
+}
+
+pub fn from_bytes<T: Versioned>(bytes: Cow<[u8]>) -> T {
+    let (version, payload) = if bytes.starts_with(CANDID_MAGIC) {
+        (0, bytes.as_ref())
+    } else {
+        (bytes[0], &bytes[1..])
+    };
+    if version > T::VERSION {
+        ic_cdk::trap("record written by a newer schema")
+    }
+    if version == T::VERSION {
+        return Decode!(payload, T).expect("should decode");
+    }
+    T::decode_version(version, payload).expect("should decode")
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Deserialize;
+
+    use super::*;
+
+    #[derive(CandidType, Deserialize, PartialEq, Debug)]
+    struct Record {
+        value: u64,
+        note: Option<String>,
+    }
+
+    impl Versioned for Record {
+        const VERSION: u8 = 1;
+    }
+
+    // the shape of `Record` before `note` was added
+    #[derive(CandidType, Deserialize)]
+    struct RecordWithoutNote {
+        value: u64,
+    }
+
+    // a record whose current version changed shape
+    #[derive(CandidType, Deserialize, PartialEq, Debug)]
+    struct Renamed {
+        amount: u64,
+    }
+
+    impl Versioned for Renamed {
+        const VERSION: u8 = 2;
+
+        fn decode_version(version: u8, bytes: &[u8]) -> Result<Self, candid::Error> {
+            assert_eq!(version, 1);
+            let old = Decode!(bytes, RecordWithoutNote)?;
+            Ok(Renamed { amount: old.value })
+        }
+    }
+
+    #[test]
+    fn round_trips_at_the_current_version() {
+        let record = Record {
+            value: 7,
+            note: Some(String::from("kept")),
+        };
+        let bytes = to_bytes(&record);
+        assert_eq!(bytes[0], Record::VERSION);
+        assert_eq!(from_bytes::<Record>(bytes), record);
+    }
+
+    #[test]
+    fn bare_candid_is_read_as_version_0() {
+        let record = Record {
+            value: 7,
+            note: None,
+        };
+        let bytes = Encode!(&record).unwrap();
+        assert_eq!(from_bytes::<Record>(Cow::Owned(bytes)), record);
+    }
+
+    #[test]
+    fn optional_fields_added_later_read_as_none() {
+        let bytes = Encode!(&RecordWithoutNote { value: 3 }).unwrap();
+        assert_eq!(
+            from_bytes::<Record>(Cow::Owned(bytes)),
+            Record {
+                value: 3,
+                note: None
+            }
+        );
+    }
+
+    #[test]
+    fn older_versions_go_through_decode_version() {
+        let mut bytes = vec![1];
+        bytes.extend(Encode!(&RecordWithoutNote { value: 5 }).unwrap());
+        assert_eq!(
+            from_bytes::<Renamed>(Cow::Owned(bytes)),
+            Renamed { amount: 5 }
+        );
+    }
+}
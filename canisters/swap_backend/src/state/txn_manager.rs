@@ -0,0 +1,309 @@
+use std::collections::HashSet;
+
+use candid::{CandidType, Principal};
+use ic_cdk::api::management_canister::bitcoin::Utxo;
+use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    memory::{Memory, MemoryIds},
+    types::{FeePreference, RuneId, TokenType},
+};
+
+use super::{
+    read_memory_manager,
+    schema::{self, Versioned},
+};
+
+// what made the canister submit a transaction
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Operation {
+    Withdrawal { user: Principal },
+    Consolidation { address: String },
+    // spends the user's own wallet next to the pool's utxos
+    Swap { user: Principal, pool_id: u128 },
+    // settles the queued payouts of many swaps at once
+    Payouts { ids: Vec<u64> },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum TxnStatus {
+    Pending,
+    Confirmed { height: u32 },
+    // superseded by a fee bump spending the same inputs
+    Replaced { txid: String },
+    // never confirmed, its inputs went back to the utxo manager
+    Dropped,
+}
+
+// a utxo spent by a submitted transaction along with the address it was
+// held by, enough to hand it back to the utxo manager
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SpentInput {
+    pub address: String,
+    pub utxo: Utxo,
+    pub rune: Option<(RuneId, u128)>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SubmittedTxn {
+    pub raw: Vec<u8>,
+    pub inputs: Vec<SpentInput>,
+    pub fee: u64,
+    // millisatoshi per vbyte, same unit as the fee percentiles
+    pub fee_per_vbytes: u64,
+    pub operation: Operation,
+    // output polled for confirmations
+    pub watch_address: String,
+    pub watch_vout: u32,
+    pub status: TxnStatus,
+    pub submitted_at: u64,
+    pub last_broadcast_at: u64,
+    pub broadcasts: u32,
+}
+
+impl Versioned for SubmittedTxn {
+    const VERSION: u8 = 1;
+}
+
+impl Storable for SubmittedTxn {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        schema::to_bytes(self)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        schema::from_bytes(bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+pub type SubmittedTxnMap = StableBTreeMap<String, SubmittedTxn, Memory>;
+
+pub fn init_submitted_txn_map() -> SubmittedTxnMap {
+    read_memory_manager(|manager| {
+        let memory = manager.get(MemoryIds::SubmittedTxns.into());
+        SubmittedTxnMap::init(memory)
+    })
+}
+
+// a swap spending a user's wallet, out with the user for signing. the
+// canister's inputs stay out of the utxo manager until the psbt comes back
+// or expires. once submitted it's kept until the transaction confirms, which
+// is when the pool's reserves move.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PsbtSwap {
+    pub user: Principal,
+    pub pool_id: u128,
+    pub token_in: TokenType,
+    pub amount_in: u64,
+    pub amount_out: u64,
+    // the unsigned transaction
+    pub raw: Vec<u8>,
+    pub inputs: Vec<SpentInput>,
+    pub wallet_address: String,
+    pub wallet_utxos: Vec<Utxo>,
+    pub expires_at: u64,
+    pub submitted_at: Option<u64>,
+}
+
+impl Versioned for PsbtSwap {
+    const VERSION: u8 = 1;
+}
+
+impl Storable for PsbtSwap {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        schema::to_bytes(self)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        schema::from_bytes(bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+pub type PsbtSwapMap = StableBTreeMap<String, PsbtSwap, Memory>;
+
+pub fn init_psbt_swap_map() -> PsbtSwapMap {
+    read_memory_manager(|manager| {
+        let memory = manager.get(MemoryIds::PsbtSwaps.into());
+        PsbtSwapMap::init(memory)
+    })
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum PayoutStatus {
+    Queued,
+    // part of a batch being signed
+    Batched,
+    Submitted { txid: String },
+    // the amount went back to the user's balance
+    Cancelled { reason: String },
+}
+
+// the output of a swap waiting to be sent to `to` with the next batch
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Payout {
+    pub user: Principal,
+    pub pool_id: u128,
+    pub token: TokenType,
+    pub amount: u128,
+    pub to: String,
+    // the user's share of the batch fee, in sats
+    pub fee: u64,
+    // none for payouts queued before swaps took a preference
+    pub fee_preference: Option<FeePreference>,
+    pub queued_at: u64,
+    pub status: PayoutStatus,
+}
+
+impl Versioned for Payout {
+    const VERSION: u8 = 1;
+}
+
+impl Storable for Payout {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        schema::to_bytes(self)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        schema::from_bytes(bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+pub type PayoutMap = StableBTreeMap<u64, Payout, Memory>;
+
+pub fn init_payout_map() -> PayoutMap {
+    read_memory_manager(|manager| {
+        let memory = manager.get(MemoryIds::Payouts.into());
+        PayoutMap::init(memory)
+    })
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TxnManager {
+    #[serde(skip, default = "init_submitted_txn_map")]
+    pub submitted: SubmittedTxnMap,
+    #[serde(skip, default = "init_psbt_swap_map")]
+    pub psbt_swaps: PsbtSwapMap,
+    #[serde(skip, default = "init_payout_map")]
+    pub payouts: PayoutMap,
+    // txids with a fee bump between signing and submitting, kept on the heap
+    // since no call survives an upgrade
+    #[serde(skip)]
+    pub bumping: HashSet<String>,
+}
+
+impl Default for TxnManager {
+    fn default() -> Self {
+        Self {
+            submitted: init_submitted_txn_map(),
+            psbt_swaps: init_psbt_swap_map(),
+            payouts: init_payout_map(),
+            bumping: HashSet::new(),
+        }
+    }
+}
+
+impl TxnManager {
+    pub fn record(&mut self, txid: String, txn: SubmittedTxn) {
+        self.submitted.insert(txid, txn);
+    }
+
+    pub fn get(&self, txid: &str) -> Option<SubmittedTxn> {
+        self.submitted.get(&String::from(txid))
+    }
+
+    pub fn pending(&self) -> Vec<(String, SubmittedTxn)> {
+        self.submitted
+            .iter()
+            .filter(|(_, txn)| txn.status == TxnStatus::Pending)
+            .collect()
+    }
+
+    pub fn set_status(&mut self, txid: &str, status: TxnStatus) {
+        let txid = String::from(txid);
+        if let Some(mut txn) = self.submitted.get(&txid) {
+            txn.status = status;
+            self.submitted.insert(txid, txn);
+        }
+    }
+
+    pub fn mark_broadcasted(&mut self, txid: &str, now: u64) {
+        let txid = String::from(txid);
+        if let Some(mut txn) = self.submitted.get(&txid) {
+            txn.last_broadcast_at = now;
+            txn.broadcasts += 1;
+            self.submitted.insert(txid, txn);
+        }
+    }
+
+    // keyed by the txid of the unsigned transaction
+    pub fn record_psbt_swap(&mut self, txid: String, swap: PsbtSwap) {
+        self.psbt_swaps.insert(txid, swap);
+    }
+
+    pub fn psbt_swap(&self, txid: &str) -> Option<PsbtSwap> {
+        self.psbt_swaps.get(&String::from(txid))
+    }
+
+    pub fn mark_psbt_swap_submitted(&mut self, txid: &str, now: u64) {
+        let txid = String::from(txid);
+        if let Some(mut swap) = self.psbt_swaps.get(&txid) {
+            swap.submitted_at = Some(now);
+            self.psbt_swaps.insert(txid, swap);
+        }
+    }
+
+    pub fn remove_psbt_swap(&mut self, txid: &str) -> Option<PsbtSwap> {
+        self.psbt_swaps.remove(&String::from(txid))
+    }
+
+    pub fn take_expired_psbt_swaps(&mut self, now: u64) -> Vec<PsbtSwap> {
+        let expired = self
+            .psbt_swaps
+            .iter()
+            .filter(|(_, swap)| swap.submitted_at.is_none() && swap.expires_at <= now)
+            .map(|(txid, _)| txid)
+            .collect::<Vec<_>>();
+        expired
+            .into_iter()
+            .filter_map(|txid| self.psbt_swaps.remove(&txid))
+            .collect()
+    }
+
+    pub fn queue_payout(&mut self, payout: Payout) -> u64 {
+        let id = self.payouts.last_key_value().map_or(0, |(id, _)| id + 1);
+        self.payouts.insert(id, payout);
+        id
+    }
+
+    pub fn queued_payouts(&self) -> Vec<(u64, Payout)> {
+        self.payouts
+            .iter()
+            .filter(|(_, payout)| payout.status == PayoutStatus::Queued)
+            .collect()
+    }
+
+    pub fn payouts_of(&self, user: &Principal) -> Vec<(u64, Payout)> {
+        self.payouts
+            .iter()
+            .filter(|(_, payout)| &payout.user == user)
+            .collect()
+    }
+
+    pub fn payout(&self, id: u64) -> Option<Payout> {
+        self.payouts.get(&id)
+    }
+
+    pub fn set_payout_status(&mut self, id: u64, status: PayoutStatus, fee: u64) {
+        if let Some(mut payout) = self.payouts.get(&id) {
+            payout.status = status;
+            payout.fee = fee;
+            self.payouts.insert(id, payout);
+        }
+    }
+}
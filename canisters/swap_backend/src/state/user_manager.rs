@@ -1,9 +1,204 @@
-use std::collections::HashSet;
+use std::collections::HashMap;
 
-use candid::CandidType;
+use candid::{CandidType, Principal};
+use ic_cdk::api::management_canister::bitcoin::Utxo;
+use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
 
-#[derive(CandidType)]
-pub struct UserHoldings {
-    pub icp: u64, // icp balance
-    pub holdings: HashSet<u128>,
+use crate::{
+    memory::{Memory, MemoryIds},
+    types::TokenType,
+    updater::txid_to_string,
+};
+
+use super::{
+    read_memory_manager,
+    schema::{self, Versioned},
+};
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct UserBalances(HashMap<TokenType, u128>);
+
+impl Versioned for UserBalances {
+    const VERSION: u8 = 1;
+}
+
+impl Storable for UserBalances {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        schema::from_bytes(bytes)
+    }
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        schema::to_bytes(self)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+pub type BalanceMap = StableBTreeMap<Principal, UserBalances, Memory>;
+
+pub fn init_balance_map() -> BalanceMap {
+    read_memory_manager(|manager| {
+        let memory = manager.get(MemoryIds::Balances.into());
+        BalanceMap::init(memory)
+    })
+}
+
+// deposits seen on chain which haven't reached the confirmation depth yet,
+// replaced on every refresh
+pub fn init_pending_map() -> BalanceMap {
+    read_memory_manager(|manager| {
+        let memory = manager.get(MemoryIds::PendingBalances.into());
+        BalanceMap::init(memory)
+    })
+}
+
+// outpoints which are either credited as a deposit or are outputs of the
+// canister's own transactions, they must never be credited again.
+pub type CreditedUtxoSet = StableBTreeMap<String, (), Memory>;
+
+pub fn init_credited_set() -> CreditedUtxoSet {
+    read_memory_manager(|manager| {
+        let memory = manager.get(MemoryIds::CreditedUtxos.into());
+        CreditedUtxoSet::init(memory)
+    })
+}
+
+// bitcoin address to the subaccount whose derived key controls it
+pub type AddressBook = StableBTreeMap<String, [u8; 32], Memory>;
+
+pub fn init_address_book() -> AddressBook {
+    read_memory_manager(|manager| {
+        let memory = manager.get(MemoryIds::AddressBook.into());
+        AddressBook::init(memory)
+    })
+}
+
+pub fn utxo_key(utxo: &Utxo) -> String {
+    outpoint_key(&txid_to_string(&utxo.outpoint.txid), utxo.outpoint.vout)
+}
+
+pub fn outpoint_key(txid: &str, vout: u32) -> String {
+    format!("{}:{}", txid, vout)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UserManager {
+    #[serde(skip, default = "init_balance_map")]
+    pub balances: BalanceMap,
+    #[serde(skip, default = "init_credited_set")]
+    pub credited: CreditedUtxoSet,
+    #[serde(skip, default = "init_address_book")]
+    pub address_book: AddressBook,
+    #[serde(skip, default = "init_pending_map")]
+    pub pending: BalanceMap,
+}
+
+impl Default for UserManager {
+    fn default() -> Self {
+        Self {
+            balances: init_balance_map(),
+            credited: init_credited_set(),
+            address_book: init_address_book(),
+            pending: init_pending_map(),
+        }
+    }
+}
+
+impl UserManager {
+    pub fn balance(&self, user: &Principal, token: &TokenType) -> u128 {
+        self.balances
+            .get(user)
+            .and_then(|balances| balances.0.get(token).copied())
+            .unwrap_or(0)
+    }
+
+    pub fn all_balances(&self, user: &Principal) -> HashMap<TokenType, u128> {
+        self.balances.get(user).unwrap_or_default().0
+    }
+
+    pub fn pending_balances(&self, user: &Principal) -> HashMap<TokenType, u128> {
+        self.pending.get(user).unwrap_or_default().0
+    }
+
+    pub fn set_pending(&mut self, user: &Principal, pending: HashMap<TokenType, u128>) {
+        if pending.is_empty() {
+            self.pending.remove(user);
+        } else {
+            self.pending.insert(*user, UserBalances(pending));
+        }
+    }
+
+    pub fn credit(&mut self, user: &Principal, token: TokenType, amount: u128) {
+        if amount == 0 {
+            return;
+        }
+        let mut balances = self.balances.get(user).unwrap_or_default().0;
+        let current = balances.get(&token).copied().unwrap_or(0);
+        balances.insert(token, current + amount);
+        self.balances.insert(*user, UserBalances(balances));
+    }
+
+    pub fn debit(
+        &mut self,
+        user: &Principal,
+        token: TokenType,
+        amount: u128,
+    ) -> Result<(), String> {
+        let mut balances = self.balances.get(user).unwrap_or_default().0;
+        let current = balances.get(&token).copied().unwrap_or(0);
+        if current < amount {
+            return Err(String::from("Insufficient balance"));
+        }
+        balances.insert(token, current - amount);
+        self.balances.insert(*user, UserBalances(balances));
+        Ok(())
+    }
+
+    // credits the contents of a deposited utxo once, returns false if the
+    // utxo was already accounted for.
+    pub fn credit_deposit(
+        &mut self,
+        user: &Principal,
+        utxo: &Utxo,
+        amounts: Vec<(TokenType, u128)>,
+    ) -> bool {
+        let key = utxo_key(utxo);
+        if self.credited.contains_key(&key) {
+            return false;
+        }
+        for (token, amount) in amounts {
+            self.credit(user, token, amount);
+        }
+        self.credited.insert(key, ());
+        true
+    }
+
+    pub fn is_credited(&self, utxo: &Utxo) -> bool {
+        self.credited.contains_key(&utxo_key(utxo))
+    }
+
+    pub fn mark_as_internal(&mut self, txid: &str, vout: u32) {
+        self.credited.insert(outpoint_key(txid, vout), ());
+    }
+
+    pub fn register_address(&mut self, addr: &str, subaccount: [u8; 32]) {
+        if !self.address_book.contains_key(&addr.to_string()) {
+            self.address_book.insert(addr.to_string(), subaccount);
+        }
+    }
+
+    pub fn subaccount_of(&self, addr: &str) -> Option<[u8; 32]> {
+        self.address_book.get(&addr.to_string())
+    }
+
+    pub fn totals(&self) -> HashMap<TokenType, u128> {
+        let mut totals = HashMap::new();
+        for (_, balances) in self.balances.iter() {
+            for (token, amount) in balances.0 {
+                *totals.entry(token).or_insert(0) += amount;
+            }
+        }
+        totals
+    }
 }
@@ -1,27 +1,42 @@
 use std::collections::{HashMap, HashSet};
 
-use candid::{CandidType, Decode, Encode};
-use ic_cdk::api::management_canister::bitcoin::Utxo;
+use candid::CandidType;
+use ic_cdk::api::management_canister::bitcoin::{Outpoint, Utxo};
 use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    chains::btc::{
+        address_validation,
+        coin_selection::{self, Strategy},
+        script_type, DUST_THRESHOLD,
+    },
     memory::{Memory, MemoryIds},
-    types::{RuneId, RunicUtxo},
+    ord_canister::RuneBalance,
+    types::{RuneId, RunicUtxo, ScriptType, TokenType},
 };
 
-use super::read_memory_manager;
+use super::{
+    read_memory_manager, read_user_manager,
+    schema::{self, Versioned},
+    txn_manager::SpentInput,
+    user_manager::utxo_key,
+};
 
 #[derive(CandidType, Deserialize, Default)]
 pub struct RunicUtxoMap(HashMap<RuneId, HashSet<RunicUtxo>>);
 
+impl Versioned for RunicUtxoMap {
+    const VERSION: u8 = 1;
+}
+
 impl Storable for RunicUtxoMap {
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).expect("should decode")
+        schema::from_bytes(bytes)
     }
 
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        std::borrow::Cow::Owned(Encode!(self).expect("should encode"))
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        schema::to_bytes(self)
     }
 
     const BOUND: Bound = Bound::Unbounded;
@@ -39,13 +54,17 @@ pub fn init_runic_map() -> RunicMap {
 #[derive(CandidType, Deserialize, Default)]
 pub struct BitcoinUtxos(HashSet<Utxo>);
 
+impl Versioned for BitcoinUtxos {
+    const VERSION: u8 = 1;
+}
+
 impl Storable for BitcoinUtxos {
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).expect("should decode")
+        schema::from_bytes(bytes)
     }
 
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        std::borrow::Cow::Owned(Encode!(self).expect("should encode"))
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        schema::to_bytes(self)
     }
 
     const BOUND: Bound = Bound::Unbounded;
@@ -60,12 +79,146 @@ pub fn init_btc_map() -> BtcMap {
     })
 }
 
+// script type of the utxos held by an address, every utxo locked to the
+// same address shares it
+pub type ScriptTypeMap = StableBTreeMap<String, ScriptType, Memory>;
+
+pub fn init_script_type_map() -> ScriptTypeMap {
+    read_memory_manager(|manager| {
+        let memory = manager.get(MemoryIds::ScriptTypes.into());
+        ScriptTypeMap::init(memory)
+    })
+}
+
+// a reservation lasts this long unless extended, past it the timer hands
+// the utxo back
+pub const RESERVATION_TTL_NANOS: u64 = 10 * 60 * 1_000_000_000;
+
+// where a utxo of the canister stands. available utxos sit in the bitcoin
+// and runic maps, the others are kept aside until they're handed back or
+// their spend confirms.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum UtxoState {
+    Available,
+    // picked by a transaction builder. utxos reserved by the same message
+    // share the operation, the time it ran at.
+    Reserved { operation: u64, expires_at: u64 },
+    // spent by a submitted transaction, the bitcoin canister keeps
+    // reporting it unspent until the transaction confirms
+    Spent { txid: String },
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Reservation {
+    pub input: SpentInput,
+    pub state: UtxoState,
+}
+
+impl Versioned for Reservation {
+    const VERSION: u8 = 1;
+}
+
+impl Storable for Reservation {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        schema::from_bytes(bytes)
+    }
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        schema::to_bytes(self)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// outpoint to the reservation of a utxo which isn't available
+pub type ReservationMap = StableBTreeMap<String, Reservation, Memory>;
+
+pub fn init_reservation_map() -> ReservationMap {
+    read_memory_manager(|manager| {
+        let memory = manager.get(MemoryIds::Reservations.into());
+        ReservationMap::init(memory)
+    })
+}
+
+// how far the utxos of an address are synced. every utxo mined at or below
+// `processed_height` has been classified already, the tip tells whether
+// anything changed since.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SyncState {
+    pub tip_height: u32,
+    pub tip_hash: Vec<u8>,
+    pub processed_height: u32,
+}
+
+impl Versioned for SyncState {
+    const VERSION: u8 = 1;
+}
+
+impl Storable for SyncState {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        schema::from_bytes(bytes)
+    }
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        schema::to_bytes(self)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+pub type SyncStateMap = StableBTreeMap<String, SyncState, Memory>;
+
+pub fn init_sync_state_map() -> SyncStateMap {
+    read_memory_manager(|manager| {
+        let memory = manager.get(MemoryIds::SyncStates.into());
+        SyncStateMap::init(memory)
+    })
+}
+
+// runes the ord canister reported for an outpoint, which never change
+// once indexed. keyed like the reservations.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct OutpointRunes(pub Vec<RuneBalance>);
+
+impl Versioned for OutpointRunes {
+    const VERSION: u8 = 1;
+}
+
+impl Storable for OutpointRunes {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        schema::from_bytes(bytes)
+    }
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        schema::to_bytes(self)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+pub type RuneCacheMap = StableBTreeMap<String, OutpointRunes, Memory>;
+
+pub fn init_rune_cache_map() -> RuneCacheMap {
+    read_memory_manager(|manager| {
+        let memory = manager.get(MemoryIds::RuneCache.into());
+        RuneCacheMap::init(memory)
+    })
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct UtxoManager {
     #[serde(skip, default = "init_runic_map")]
     pub r: RunicMap,
     #[serde(skip, default = "init_btc_map")]
     pub b: BtcMap,
+    #[serde(skip, default = "init_script_type_map")]
+    pub s: ScriptTypeMap,
+    #[serde(skip, default = "init_reservation_map")]
+    pub reservations: ReservationMap,
+    #[serde(skip, default = "init_sync_state_map")]
+    pub syncs: SyncStateMap,
+    #[serde(skip, default = "init_rune_cache_map")]
+    pub runes: RuneCacheMap,
 }
 
 impl Default for UtxoManager {
@@ -73,17 +226,65 @@ impl Default for UtxoManager {
         Self {
             r: init_runic_map(),
             b: init_btc_map(),
+            s: init_script_type_map(),
+            reservations: init_reservation_map(),
+            syncs: init_sync_state_map(),
+            runes: init_rune_cache_map(),
         }
     }
 }
 
+// a deposit only becomes spendable once it's credited to its owner, the
+// canister's own outputs are marked credited when submitted
+fn is_spendable(utxo: &Utxo) -> bool {
+    read_user_manager(|users| users.is_credited(utxo))
+}
+
+// whether `utxo` is also recorded under a rune other than `runeid`. moving
+// such a utxo would have to account for every rune it carries.
+fn carries_other_runes(
+    map: &HashMap<RuneId, HashSet<RunicUtxo>>,
+    runeid: &RuneId,
+    utxo: &Utxo,
+) -> bool {
+    map.iter().any(|(other, utxos)| {
+        other != runeid
+            && utxos
+                .iter()
+                .any(|runic| runic.utxo.outpoint == utxo.outpoint)
+    })
+}
+
 impl UtxoManager {
+    fn track_script_type(&mut self, addr: &str) {
+        if self.s.contains_key(&String::from(addr)) {
+            return;
+        }
+        let script_type = address_validation(addr)
+            .ok()
+            .and_then(|address| script_type(&address));
+        if let Some(script_type) = script_type {
+            self.s.insert(String::from(addr), script_type);
+        }
+    }
+
+    pub fn script_type(&self, addr: &str) -> Option<ScriptType> {
+        self.s.get(&String::from(addr))
+    }
+
     pub fn record_runic_utxos(&mut self, addr: &str, runeid: RuneId, utxos: Vec<RunicUtxo>) {
+        self.track_script_type(addr);
         let addr = String::from(addr);
         let mut map = self.r.get(&addr).unwrap_or_default().0;
         let mut current_utxos = map.remove(&runeid).unwrap_or_default();
         for utxo in utxos {
-            if current_utxos.contains(&utxo) {
+            // the change of the canister's own transactions is recorded
+            // before the bitcoin canister reports it along with its height
+            if current_utxos
+                .iter()
+                .any(|current| current.utxo.outpoint == utxo.utxo.outpoint)
+                || !self.is_available(&utxo.utxo)
+            {
                 continue;
             }
             current_utxos.insert(utxo);
@@ -93,10 +294,15 @@ impl UtxoManager {
     }
 
     pub fn record_btc_utxos(&mut self, addr: &str, utxos: Vec<Utxo>) {
+        self.track_script_type(addr);
         let addr = String::from(addr);
         let mut current_utxos = self.b.get(&addr).unwrap_or_default().0;
         for utxo in utxos {
-            if current_utxos.contains(&utxo) {
+            if current_utxos
+                .iter()
+                .any(|current| current.outpoint == utxo.outpoint)
+                || !self.is_available(&utxo)
+            {
                 continue;
             }
             current_utxos.insert(utxo);
@@ -104,28 +310,158 @@ impl UtxoManager {
         self.b.insert(addr, BitcoinUtxos(current_utxos));
     }
 
-    pub fn get_bitcoin_utxo(&mut self, addr: &str) -> Option<Utxo> {
+    // takes the utxos selected by `strategy` to cover `target` sats out of
+    // the ones held by `addr`. a leftover under the dust threshold is fine as
+    // it wouldn't make it into a change output anyway.
+    pub fn select_bitcoin_utxos(
+        &mut self,
+        addr: &str,
+        target: u64,
+        strategy: Strategy,
+    ) -> Option<Vec<Utxo>> {
         let addr = String::from(addr);
-        ic_cdk::println!("checking for utxo with lowest balance");
-        let mut utxos = self.b.get(&addr)?.0;
-        let min_utxo = utxos.iter().min_by_key(|utxo| utxo.value)?.clone();
-        ic_cdk::println!("utxo found with balance of: {}", min_utxo.value);
-        utxos.remove(&min_utxo);
-        self.b.insert(addr, BitcoinUtxos(utxos));
-        Some(min_utxo)
+        let mut utxos = self
+            .b
+            .get(&addr)?
+            .0
+            .into_iter()
+            .filter(is_spendable)
+            .collect::<Vec<_>>();
+        utxos.sort_by(|a, b| {
+            (a.value, &a.outpoint.txid, a.outpoint.vout).cmp(&(
+                b.value,
+                &b.outpoint.txid,
+                b.outpoint.vout,
+            ))
+        });
+        let amounts = utxos
+            .iter()
+            .map(|utxo| utxo.value as u128)
+            .collect::<Vec<_>>();
+        let selected =
+            coin_selection::select(&amounts, target as u128, DUST_THRESHOLD as u128, strategy)?;
+        let selected = selected
+            .into_iter()
+            .map(|index| utxos[index].clone())
+            .collect::<Vec<_>>();
+        let mut current_utxos = self.b.get(&addr).unwrap_or_default().0;
+        for utxo in selected.iter() {
+            current_utxos.remove(utxo);
+            self.reserve(&addr, utxo, None);
+        }
+        self.b.insert(addr, BitcoinUtxos(current_utxos));
+        Some(selected)
     }
 
-    pub fn get_runic_utxo(&mut self, addr: &str, runeid: RuneId) -> Option<RunicUtxo> {
+    // runic counterpart of `select_bitcoin_utxos`, only an exact match spares
+    // the rune change output. utxos carrying other runes are left alone.
+    pub fn select_runic_utxos(
+        &mut self,
+        addr: &str,
+        runeid: &RuneId,
+        target: u128,
+        strategy: Strategy,
+    ) -> Option<Vec<RunicUtxo>> {
         let addr = String::from(addr);
-        ic_cdk::println!("checking for utxo with lowest balance");
         let mut map = self.r.get(&addr)?.0;
-        let mut utxos = map.remove(&runeid).unwrap_or_default();
-        let min_utxo = utxos.iter().min_by_key(|utxo| utxo.balance)?.clone();
-        ic_cdk::println!("utxo found with balance of: {}", min_utxo.balance);
-        utxos.remove(&min_utxo);
-        map.insert(runeid, utxos);
+        let mut utxos = map
+            .get(runeid)?
+            .iter()
+            .filter(|runic| {
+                !carries_other_runes(&map, runeid, &runic.utxo)
+                    && self.is_available(&runic.utxo)
+                    && is_spendable(&runic.utxo)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        utxos.sort_by(|a, b| {
+            (a.balance, &a.utxo.outpoint.txid, a.utxo.outpoint.vout).cmp(&(
+                b.balance,
+                &b.utxo.outpoint.txid,
+                b.utxo.outpoint.vout,
+            ))
+        });
+        let amounts = utxos.iter().map(|utxo| utxo.balance).collect::<Vec<_>>();
+        let selected = coin_selection::select(&amounts, target, 0, strategy)?;
+        let selected = selected
+            .into_iter()
+            .map(|index| utxos[index].clone())
+            .collect::<Vec<_>>();
+        let current_utxos = map.entry(runeid.clone()).or_default();
+        for utxo in selected.iter() {
+            current_utxos.remove(utxo);
+            self.reserve(&addr, &utxo.utxo, Some((runeid.clone(), utxo.balance)));
+        }
         self.r.insert(addr, RunicUtxoMap(map));
-        Some(min_utxo)
+        Some(selected)
+    }
+
+    // takes up to `count` of the smallest bitcoin utxos held by `addr`
+    pub fn take_smallest_bitcoin_utxos(&mut self, addr: &str, count: usize) -> Vec<Utxo> {
+        let addr = String::from(addr);
+        let mut utxos = self.b.get(&addr).unwrap_or_default().0;
+        let mut smallest = utxos
+            .iter()
+            .filter(|utxo| is_spendable(utxo))
+            .cloned()
+            .collect::<Vec<_>>();
+        smallest.sort_by(|a, b| {
+            (a.value, &a.outpoint.txid, a.outpoint.vout).cmp(&(
+                b.value,
+                &b.outpoint.txid,
+                b.outpoint.vout,
+            ))
+        });
+        smallest.truncate(count);
+        if smallest.is_empty() {
+            return smallest;
+        }
+        for utxo in smallest.iter() {
+            utxos.remove(utxo);
+            self.reserve(&addr, utxo, None);
+        }
+        self.b.insert(addr, BitcoinUtxos(utxos));
+        smallest
+    }
+
+    // takes up to `count` of the runic utxos held by `addr` with the least of
+    // `runeid`, leaving alone the ones carrying other runes
+    pub fn take_smallest_runic_utxos(
+        &mut self,
+        addr: &str,
+        runeid: &RuneId,
+        count: usize,
+    ) -> Vec<RunicUtxo> {
+        let addr = String::from(addr);
+        let mut map = self.r.get(&addr).unwrap_or_default().0;
+        let mut smallest = map
+            .get(runeid)
+            .map(|utxos| {
+                utxos
+                    .iter()
+                    .filter(|runic| {
+                        !carries_other_runes(&map, runeid, &runic.utxo) && is_spendable(&runic.utxo)
+                    })
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        smallest.sort_by(|a, b| {
+            (a.balance, &a.utxo.outpoint.txid, a.utxo.outpoint.vout).cmp(&(
+                b.balance,
+                &b.utxo.outpoint.txid,
+                b.utxo.outpoint.vout,
+            ))
+        });
+        smallest.truncate(count);
+        if let Some(utxos) = map.get_mut(runeid) {
+            for utxo in smallest.iter() {
+                utxos.remove(utxo);
+                self.reserve(&addr, &utxo.utxo, Some((runeid.clone(), utxo.balance)));
+            }
+        }
+        self.r.insert(addr, RunicUtxoMap(map));
+        smallest
     }
 
     pub fn is_recorded_as_runic(&self, addr: &str, utxo: &Utxo) -> bool {
@@ -133,7 +469,10 @@ impl UtxoManager {
         let mut flag = false;
         if let Some(map) = self.r.get(&addr) {
             for (_, utxos) in map.0.iter() {
-                if utxos.contains(utxo) {
+                if utxos
+                    .iter()
+                    .any(|runic| runic.utxo.outpoint == utxo.outpoint)
+                {
                     flag = true;
                     break;
                 }
@@ -174,6 +513,119 @@ impl UtxoManager {
         balances
     }
 
+    // every utxo held by `addr` along with the tokens it carries
+    pub fn deposits(&self, addr: &str) -> Vec<(Utxo, Vec<(TokenType, u128)>)> {
+        let addr = String::from(addr);
+        let mut deposits: HashMap<Utxo, Vec<(TokenType, u128)>> = HashMap::new();
+        if let Some(utxos) = self.b.get(&addr) {
+            for utxo in utxos.0 {
+                let value = utxo.value as u128;
+                deposits
+                    .entry(utxo)
+                    .or_default()
+                    .push((TokenType::Bitcoin, value));
+            }
+        }
+        if let Some(map) = self.r.get(&addr) {
+            for (runeid, utxos) in map.0 {
+                for RunicUtxo { utxo, balance } in utxos {
+                    deposits
+                        .entry(utxo)
+                        .or_default()
+                        .push((TokenType::Runestone(runeid.clone()), balance));
+                }
+            }
+        }
+        deposits.into_iter().collect()
+    }
+
+    // returns `preferred` if it holds at least `amount`, otherwise the address
+    // holding the most bitcoin as long as it covers `amount`. segwit and taproot
+    // addresses go first as their inputs are cheaper to spend.
+    pub fn address_with_bitcoin_balance(&self, amount: u64, preferred: &str) -> Option<String> {
+        let spendable = |utxos: BitcoinUtxos| {
+            utxos
+                .0
+                .iter()
+                .filter(|utxo| is_spendable(utxo))
+                .fold(0, |balance, utxo| balance + utxo.value)
+        };
+        let preferred_balance = self.b.get(&String::from(preferred)).map_or(0, spendable);
+        if preferred_balance >= amount {
+            return Some(String::from(preferred));
+        }
+        self.b
+            .iter()
+            .map(|(addr, utxos)| (addr, spendable(utxos)))
+            .filter(|(_, balance)| *balance >= amount)
+            .max_by_key(|(addr, balance)| {
+                (
+                    matches!(
+                        self.script_type(addr),
+                        Some(ScriptType::P2wpkh | ScriptType::P2tr)
+                    ),
+                    *balance,
+                )
+            })
+            .map(|(addr, _)| addr)
+    }
+
+    pub fn address_with_runestone_balance(
+        &self,
+        runeid: &RuneId,
+        amount: u128,
+        preferred: &str,
+    ) -> Option<String> {
+        let spendable = |map: RunicUtxoMap| {
+            map.0.get(runeid).map(|utxos| {
+                utxos
+                    .iter()
+                    .filter(|runic| {
+                        !carries_other_runes(&map.0, runeid, &runic.utxo)
+                            && is_spendable(&runic.utxo)
+                    })
+                    .fold(0, |balance, utxo| balance + utxo.balance)
+            })
+        };
+        let preferred_balance = self
+            .r
+            .get(&String::from(preferred))
+            .and_then(spendable)
+            .unwrap_or_default();
+        if preferred_balance >= amount {
+            return Some(String::from(preferred));
+        }
+        self.r
+            .iter()
+            .filter_map(|(addr, map)| Some((addr, spendable(map)?)))
+            .filter(|(_, balance)| *balance >= amount)
+            .max_by_key(|(addr, balance)| {
+                (
+                    matches!(
+                        self.script_type(addr),
+                        Some(ScriptType::P2wpkh | ScriptType::P2tr)
+                    ),
+                    *balance,
+                )
+            })
+            .map(|(addr, _)| addr)
+    }
+
+    pub fn total_balances(&self) -> HashMap<TokenType, u128> {
+        let mut totals = HashMap::new();
+        for (_, utxos) in self.b.iter() {
+            let balance = utxos.0.iter().fold(0, |balance, utxo| balance + utxo.value);
+            *totals.entry(TokenType::Bitcoin).or_insert(0) += balance as u128;
+        }
+        for (_, map) in self.r.iter() {
+            for (runeid, utxos) in map.0 {
+                let balance = utxos.iter().fold(0, |balance, utxo| balance + utxo.balance);
+                *totals.entry(TokenType::Runestone(runeid)).or_insert(0) += balance;
+            }
+        }
+        totals
+    }
+
     pub fn remove_btc_utxo(&mut self, addr: &str, utxo: &Utxo) {
         let addr = String::from(addr);
         let mut current_utxos = self.b.get(&addr).unwrap_or_default().0;
@@ -182,4 +634,297 @@ impl UtxoManager {
         ic_cdk::println!("btc utxo's len after removal: {}", current_utxos.len());
         self.b.insert(addr, BitcoinUtxos(current_utxos));
     }
+
+    // drops the utxo at the outpoint of `utxo` from the available ones of
+    // `addr`, whatever it carries
+    pub fn remove_utxo(&mut self, addr: &str, utxo: &Utxo) {
+        let addr = String::from(addr);
+        if let Some(mut utxos) = self.b.get(&addr) {
+            let before = utxos.0.len();
+            utxos.0.retain(|current| current.outpoint != utxo.outpoint);
+            if utxos.0.len() != before {
+                self.b.insert(addr.clone(), utxos);
+            }
+        }
+        if let Some(mut map) = self.r.get(&addr) {
+            let mut removed = false;
+            for utxos in map.0.values_mut() {
+                let before = utxos.len();
+                utxos.retain(|runic| runic.utxo.outpoint != utxo.outpoint);
+                removed |= utxos.len() != before;
+            }
+            if removed {
+                self.r.insert(addr, map);
+            }
+        }
+    }
+
+    fn reserve(&mut self, addr: &str, utxo: &Utxo, rune: Option<(RuneId, u128)>) {
+        let now = ic_cdk::api::time();
+        self.reservations.insert(
+            utxo_key(utxo),
+            Reservation {
+                input: SpentInput {
+                    address: String::from(addr),
+                    utxo: utxo.clone(),
+                    rune,
+                },
+                state: UtxoState::Reserved {
+                    operation: now,
+                    expires_at: now + RESERVATION_TTL_NANOS,
+                },
+            },
+        );
+    }
+
+    pub fn is_available(&self, utxo: &Utxo) -> bool {
+        matches!(self.utxo_state(utxo), UtxoState::Available)
+    }
+
+    pub fn utxo_state(&self, utxo: &Utxo) -> UtxoState {
+        self.reservations
+            .get(&utxo_key(utxo))
+            .map(|reservation| reservation.state)
+            .unwrap_or(UtxoState::Available)
+    }
+
+    // hands reserved utxos back to the available ones. utxos spent in the
+    // meantime stay out.
+    pub fn release(&mut self, inputs: Vec<SpentInput>) {
+        for input in inputs {
+            let key = utxo_key(&input.utxo);
+            if let Some(Reservation {
+                state: UtxoState::Spent { .. },
+                ..
+            }) = self.reservations.get(&key)
+            {
+                continue;
+            }
+            self.reservations.remove(&key);
+            match input.rune {
+                Some((runeid, balance)) => self.record_runic_utxos(
+                    &input.address,
+                    runeid,
+                    vec![RunicUtxo {
+                        utxo: input.utxo,
+                        balance,
+                    }],
+                ),
+                None => self.record_btc_utxos(&input.address, vec![input.utxo]),
+            }
+        }
+    }
+
+    // hands back utxos spent by a transaction which never made it on chain
+    pub fn unspend(&mut self, inputs: Vec<SpentInput>) {
+        for input in inputs.iter() {
+            let key = utxo_key(&input.utxo);
+            if let Some(Reservation {
+                state: UtxoState::Spent { .. },
+                ..
+            }) = self.reservations.get(&key)
+            {
+                self.reservations.remove(&key);
+            }
+        }
+        self.release(inputs);
+    }
+
+    pub fn release_btc_utxos(&mut self, addr: &str, utxos: Vec<Utxo>) {
+        let inputs = utxos
+            .into_iter()
+            .map(|utxo| SpentInput {
+                address: String::from(addr),
+                utxo,
+                rune: None,
+            })
+            .collect();
+        self.release(inputs);
+    }
+
+    pub fn release_runic_utxos(&mut self, addr: &str, runeid: RuneId, utxos: Vec<RunicUtxo>) {
+        let inputs = utxos
+            .into_iter()
+            .map(|RunicUtxo { utxo, balance }| SpentInput {
+                address: String::from(addr),
+                utxo,
+                rune: Some((runeid.clone(), balance)),
+            })
+            .collect();
+        self.release(inputs);
+    }
+
+    // keeps the reservation of `inputs` until `expires_at`
+    pub fn extend_reservations(&mut self, inputs: &[SpentInput], expires_at: u64) {
+        for input in inputs {
+            let key = utxo_key(&input.utxo);
+            let Some(mut reservation) = self.reservations.get(&key) else {
+                continue;
+            };
+            if let UtxoState::Reserved {
+                expires_at: ref mut expiry,
+                ..
+            } = reservation.state
+            {
+                *expiry = expires_at;
+                self.reservations.insert(key, reservation);
+            }
+        }
+    }
+
+    // hands back every reservation expired by `now`, returning how many
+    pub fn release_expired(&mut self, now: u64) -> usize {
+        let expired = self
+            .reservations
+            .iter()
+            .filter(|(_, reservation)| {
+                matches!(reservation.state, UtxoState::Reserved { expires_at, .. } if expires_at <= now)
+            })
+            .map(|(_, reservation)| reservation.input)
+            .collect::<Vec<_>>();
+        let count = expired.len();
+        self.release(expired);
+        count
+    }
+
+    // `inputs` went out in `txid`. a reservation which expired before the
+    // submit got through may have handed them back already.
+    pub fn mark_spent(&mut self, inputs: &[SpentInput], txid: &str) {
+        for input in inputs {
+            self.remove_utxo(&input.address, &input.utxo);
+            self.reservations.insert(
+                utxo_key(&input.utxo),
+                Reservation {
+                    input: input.clone(),
+                    state: UtxoState::Spent {
+                        txid: String::from(txid),
+                    },
+                },
+            );
+        }
+    }
+
+    // the spend of `inputs` confirmed, the bitcoin canister won't report
+    // them anymore
+    pub fn forget_spent(&mut self, inputs: &[SpentInput]) {
+        for input in inputs {
+            let key = utxo_key(&input.utxo);
+            self.runes.remove(&key);
+            if let Some(Reservation {
+                state: UtxoState::Spent { .. },
+                ..
+            }) = self.reservations.get(&key)
+            {
+                self.reservations.remove(&key);
+            }
+        }
+    }
+
+    pub fn sync_state(&self, addr: &str) -> Option<SyncState> {
+        self.syncs.get(&String::from(addr))
+    }
+
+    pub fn set_sync_state(&mut self, addr: &str, state: SyncState) {
+        self.syncs.insert(String::from(addr), state);
+    }
+
+    // whether the outpoint of `utxo` is among the available ones of `addr`
+    pub fn is_recorded(&self, addr: &str, utxo: &Utxo) -> bool {
+        let addr = String::from(addr);
+        self.b.get(&addr).is_some_and(|utxos| {
+            utxos
+                .0
+                .iter()
+                .any(|current| current.outpoint == utxo.outpoint)
+        }) || self.is_recorded_as_runic(&addr, utxo)
+    }
+
+    // takes the height the bitcoin canister reports for a utxo recorded
+    // before it was mined
+    pub fn update_height(&mut self, addr: &str, utxo: &Utxo) {
+        let addr = String::from(addr);
+        if let Some(mut utxos) = self.b.get(&addr) {
+            let recorded = utxos
+                .0
+                .iter()
+                .find(|current| current.outpoint == utxo.outpoint && current.height != utxo.height)
+                .cloned();
+            if let Some(recorded) = recorded {
+                utxos.0.remove(&recorded);
+                utxos.0.insert(utxo.clone());
+                self.b.insert(addr.clone(), utxos);
+            }
+        }
+        if let Some(mut map) = self.r.get(&addr) {
+            let mut updated = false;
+            for utxos in map.0.values_mut() {
+                let recorded = utxos
+                    .iter()
+                    .find(|runic| {
+                        runic.utxo.outpoint == utxo.outpoint && runic.utxo.height != utxo.height
+                    })
+                    .cloned();
+                if let Some(recorded) = recorded {
+                    utxos.remove(&recorded);
+                    utxos.insert(RunicUtxo {
+                        utxo: utxo.clone(),
+                        balance: recorded.balance,
+                    });
+                    updated = true;
+                }
+            }
+            if updated {
+                self.r.insert(addr, map);
+            }
+        }
+    }
+
+    // drops the mined utxos of `addr` the bitcoin canister no longer
+    // reports, they were spent elsewhere or reorged out. change not mined
+    // yet is kept. returns how many were dropped.
+    pub fn retain_reported(&mut self, addr: &str, reported: &HashSet<Outpoint>) -> usize {
+        let addr = String::from(addr);
+        let is_stale = |utxo: &Utxo| utxo.height != 0 && !reported.contains(&utxo.outpoint);
+        let mut stale = vec![];
+        if let Some(mut utxos) = self.b.get(&addr) {
+            let before = utxos.0.len();
+            utxos.0.retain(|utxo| {
+                if is_stale(utxo) {
+                    stale.push(utxo_key(utxo));
+                }
+                !is_stale(utxo)
+            });
+            if utxos.0.len() != before {
+                self.b.insert(addr.clone(), utxos);
+            }
+        }
+        if let Some(mut map) = self.r.get(&addr) {
+            let mut removed = false;
+            for utxos in map.0.values_mut() {
+                let before = utxos.len();
+                utxos.retain(|runic| {
+                    if is_stale(&runic.utxo) {
+                        stale.push(utxo_key(&runic.utxo));
+                    }
+                    !is_stale(&runic.utxo)
+                });
+                removed |= utxos.len() != before;
+            }
+            if removed {
+                self.r.insert(addr, map);
+            }
+        }
+        for key in stale.iter() {
+            self.runes.remove(key);
+        }
+        stale.len()
+    }
+
+    pub fn cached_runes(&self, utxo: &Utxo) -> Option<Vec<RuneBalance>> {
+        self.runes.get(&utxo_key(utxo)).map(|runes| runes.0)
+    }
+
+    pub fn cache_runes(&mut self, utxo: &Utxo, runes: Vec<RuneBalance>) {
+        self.runes.insert(utxo_key(utxo), OutpointRunes(runes));
+    }
 }
@@ -1,40 +1,56 @@
 use bitcoin::{
-    absolute::LockTime,
+    ecdsa,
     hashes::Hash,
     script::{Builder, PushBytesBuf},
-    sighash::SighashCache,
-    transaction::Version,
-    Address, Amount, EcdsaSighashType, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut,
-    Txid, Witness,
+    secp256k1::{Message, Secp256k1},
+    sighash::{Prevouts, SighashCache, TapSighashType},
+    taproot, Address, Amount, CompressedPublicKey, EcdsaSighashType, ScriptBuf, Transaction, TxOut,
+    Witness, XOnlyPublicKey,
 };
+use candid::{Nat, Principal};
 use ic_cdk::api::management_canister::bitcoin::{
-    bitcoin_send_transaction, SendTransactionRequest, Utxo,
+    bitcoin_send_transaction, Outpoint, SendTransactionRequest, Utxo,
 };
 use ic_ledger_types::TransferArgs;
-use ic_management_canister_types::DerivationPath;
-use icrc_ledger_types::icrc1::account::Account;
-use ordinals::{Edict, Runestone};
+use icrc_ledger_types::icrc1::{
+    account::Account,
+    transfer::{TransferArg, TransferError},
+};
 
 use crate::{
-    chains::btc::{
-        signer::ecdsa_sign,
-        utils::{account_to_derivation_path, derive_public_key, sec1_to_der},
+    chains::{
+        account_of_address,
+        btc::{
+            address_validation,
+            runestone::{
+                validation::{replay, validate_runestone, Allocation},
+                DEFAULT_POSTAGE,
+            },
+            script_type,
+            signer::{ecdsa_sign, schnorr_sign},
+            to_bitcoin_network,
+            utils::{account_to_derivation_path, derive_public_key, sec1_to_der},
+            DUST_THRESHOLD,
+        },
+        ic::icp,
+    },
+    state::{
+        read_config, read_pool_manager, read_txn_manager, read_utxo_manager,
+        txn_manager::{Operation, PayoutStatus, PsbtSwap, SpentInput, SubmittedTxn, TxnStatus},
+        write_pool_manager, write_txn_manager, write_user_manager, write_utxo_manager,
     },
-    state::read_config,
-    types::{RuneId, RunicUtxo, SubmittedTxidType},
+    types::{RuneId, RunicUtxo, ScriptType, SubmittedTxidType, TokenType},
 };
 
 pub enum TransactionType {
     Combined {
+        txn: Transaction,
         runeid: RuneId,
-        rune_amount: u128,
         rune_sender: Box<Address>,
-        rune_receiver: Box<Address>,
         rune_sender_account: Account,
         runic_utxos: Vec<RunicUtxo>,
         btc_amount: u64,
         btc_sender: Box<Address>,
-        btc_receiver: Box<Address>,
         btc_sender_account: Account,
         btc_utxos: Vec<Utxo>,
         fee: u64,
@@ -42,6 +58,7 @@ pub enum TransactionType {
         fee_utxos: Vec<Utxo>,
         fee_payer: Box<Address>,
         postage: Amount,
+        allocations: Vec<Allocation>,
     },
     Bitcoin {
         txn: Transaction,
@@ -50,561 +67,1184 @@ pub enum TransactionType {
         sender_account: Account,
     },
     Rune {
+        txn: Transaction,
         rune: RuneId,
-        rune_amount: u128,
         runic_utxos: Vec<RunicUtxo>,
         sender: Box<Address>,
-        receiver: Box<Address>,
         sender_account: Account,
         fee: u64,
         fee_payer: Box<Address>,
         fee_payer_account: Account,
         fee_utxos: Vec<Utxo>,
         postage: Amount,
+        allocations: Vec<Allocation>,
+    },
+    RunePair {
+        txn: Transaction,
+        rune0: RuneId,
+        rune0_utxos: Vec<RunicUtxo>,
+        rune0_sender: Box<Address>,
+        rune0_sender_account: Account,
+        rune1: RuneId,
+        rune1_utxos: Vec<RunicUtxo>,
+        rune1_sender: Box<Address>,
+        rune1_sender_account: Account,
+        fee_utxos: Vec<Utxo>,
+        fee_payer: Box<Address>,
+        fee_payer_account: Account,
+        allocations: Vec<Allocation>,
+    },
+    Batch {
+        txn: Transaction,
+        inputs: Vec<SpentInput>,
+        // address and account holding every input
+        signers: Vec<(Address, Account)>,
+        // where the change of every group goes
+        change: Vec<Address>,
+        allocations: Vec<Allocation>,
     },
     Icp {
+        ledger: Principal,
         txn: TransferArgs,
     },
+    Icrc1 {
+        ledger: Principal,
+        txn: TransferArg,
+    },
+}
+
+// ecdsa signature in der encoding with the sighash type appended
+async fn der_signature(sighash: [u8; 32], path: &[Vec<u8>]) -> Vec<u8> {
+    let signature = ecdsa_sign(sighash.to_vec(), path.to_vec()).await.signature;
+    let mut signature = sec1_to_der(signature);
+    signature.push(EcdsaSighashType::All.to_u32() as u8);
+    signature
+}
+
+// signs every input with the key of its owner, `owners[index]` being the
+// address, account and value of the utxo spent by input `index`. p2pkh
+// inputs get a legacy script_sig, p2wpkh inputs a bip-143 witness and p2tr
+// inputs a key path schnorr signature.
+async fn sign_inputs(txn: &mut Transaction, owners: &[(&Address, &Account, u64)]) {
+    let prevouts = owners
+        .iter()
+        .map(|(owner, _, value)| TxOut {
+            value: Amount::from_sat(*value),
+            script_pubkey: owner.script_pubkey(),
+        })
+        .collect::<Vec<_>>();
+    let signers = owners
+        .iter()
+        .map(|(owner, account, _)| Some((*owner, *account)))
+        .collect::<Vec<_>>();
+    sign_canister_inputs(txn, &prevouts, &signers).await
+}
+
+// signs the inputs which have a signer, leaving the others as they are.
+// `prevouts` holds the output spent by every input, taproot sighashes
+// commit to all of them.
+async fn sign_canister_inputs(
+    txn: &mut Transaction,
+    prevouts: &[TxOut],
+    signers: &[Option<(&Address, &Account)>],
+) {
+    let keys = read_config(|config| {
+        let ecdsa_key = config.ecdsa_public_key();
+        signers
+            .iter()
+            .map(|signer| {
+                signer.map(|(owner, account)| {
+                    let path = account_to_derivation_path(account);
+                    let pubkey = derive_public_key(&ecdsa_key, &path).public_key;
+                    let path = path
+                        .into_iter()
+                        .map(|index| index.into_vec())
+                        .collect::<Vec<_>>();
+                    (owner, path, pubkey)
+                })
+            })
+            .collect::<Vec<_>>()
+    });
+
+    let mut txn_cache = SighashCache::new(txn.clone());
+
+    for (index, input) in txn.input.iter_mut().enumerate() {
+        let Some((owner, path, pubkey)) = &keys[index] else {
+            continue;
+        };
+        let value = prevouts[index].value;
+        match script_type(owner) {
+            Some(ScriptType::P2tr) => {
+                let sighash = txn_cache
+                    .taproot_key_spend_signature_hash(
+                        index,
+                        &Prevouts::All(prevouts),
+                        TapSighashType::Default,
+                    )
+                    .unwrap();
+                let signature = schnorr_sign(sighash.to_byte_array().to_vec(), path.clone())
+                    .await
+                    .signature;
+                input.script_sig = ScriptBuf::new();
+                input.witness = Witness::from_slice(&[signature]);
+            }
+            Some(ScriptType::P2wpkh) => {
+                let sighash = txn_cache
+                    .p2wpkh_signature_hash(
+                        index,
+                        &owner.script_pubkey(),
+                        value,
+                        EcdsaSighashType::All,
+                    )
+                    .unwrap();
+                let signature = der_signature(sighash.to_byte_array(), path).await;
+                input.script_sig = ScriptBuf::new();
+                input.witness = Witness::from_slice(&[signature, pubkey.clone()]);
+            }
+            _ => {
+                let sighash = txn_cache
+                    .legacy_signature_hash(
+                        index,
+                        &owner.script_pubkey(),
+                        EcdsaSighashType::All.to_u32(),
+                    )
+                    .unwrap();
+                let signature = der_signature(sighash.to_byte_array(), path).await;
+                let signature = PushBytesBuf::try_from(signature).unwrap();
+                let pubkey = PushBytesBuf::try_from(pubkey.clone()).unwrap();
+                input.script_sig = Builder::new()
+                    .push_slice(signature)
+                    .push_slice(pubkey)
+                    .into_script();
+                input.witness.clear();
+            }
+        }
+    }
+}
+
+// checks the witness of every input without a signer is a valid key spend
+// of the output it spends, p2wpkh or key path p2tr, before the canister adds
+// its own signatures next to them
+fn verify_wallet_inputs(
+    txn: &Transaction,
+    prevouts: &[TxOut],
+    signers: &[Option<(&Address, &Account)>],
+) -> Result<(), String> {
+    let secp = Secp256k1::verification_only();
+    let mut cache = SighashCache::new(txn);
+    for (index, input) in txn.input.iter().enumerate() {
+        if signers[index].is_some() {
+            continue;
+        }
+        let prevout = &prevouts[index];
+        let invalid = || format!("wallet input {} isn't validly signed", index);
+        let witness = input.witness.to_vec();
+        if prevout.script_pubkey.is_p2wpkh() {
+            let [signature, pubkey] = witness.as_slice() else {
+                return Err(invalid());
+            };
+            let signature = ecdsa::Signature::from_slice(signature).map_err(|_| invalid())?;
+            let pubkey = CompressedPublicKey::from_slice(pubkey).map_err(|_| invalid())?;
+            if ScriptBuf::new_p2wpkh(&pubkey.wpubkey_hash()) != prevout.script_pubkey {
+                return Err(invalid());
+            }
+            let sighash = cache
+                .p2wpkh_signature_hash(
+                    index,
+                    &prevout.script_pubkey,
+                    prevout.value,
+                    signature.sighash_type,
+                )
+                .map_err(|err| err.to_string())?;
+            secp.verify_ecdsa(
+                &Message::from_digest(sighash.to_byte_array()),
+                &signature.signature,
+                &pubkey.0,
+            )
+            .map_err(|_| invalid())?;
+        } else if prevout.script_pubkey.is_p2tr() {
+            let [signature] = witness.as_slice() else {
+                return Err(invalid());
+            };
+            let signature = taproot::Signature::from_slice(signature).map_err(|_| invalid())?;
+            let key = XOnlyPublicKey::from_slice(&prevout.script_pubkey.as_bytes()[2..])
+                .map_err(|_| invalid())?;
+            let sighash = cache
+                .taproot_key_spend_signature_hash(
+                    index,
+                    &Prevouts::All(prevouts),
+                    signature.sighash_type,
+                )
+                .map_err(|err| err.to_string())?;
+            secp.verify_schnorr(
+                &signature.signature,
+                &Message::from_digest(sighash.to_byte_array()),
+                &key,
+            )
+            .map_err(|_| invalid())?;
+        } else {
+            return Err(format!(
+                "wallet input {} spends an unsupported script",
+                index
+            ));
+        }
+    }
+    Ok(())
+}
+
+// refuses transactions paying more in fees than the fee policy allows,
+// checked before anything gets signed
+fn check_fee_cap(txn: &Transaction, inputs: &[SpentInput]) -> Result<(), String> {
+    let spent = inputs
+        .iter()
+        .fold(0, |total, input| total + input.utxo.value);
+    let sent = txn
+        .output
+        .iter()
+        .fold(0, |total, output| total + output.value.to_sat());
+    let fee = spent.saturating_sub(sent);
+    let max_fee = read_config(|config| config.fee_policy().max_fee);
+    if fee > max_fee {
+        return Err(format!(
+            "fee of {} sats is above the cap of {} sats",
+            fee, max_fee
+        ));
+    }
+    Ok(())
+}
+
+// the addresses of the canister `txn` pays to
+fn canister_outputs(txn: &Transaction) -> Vec<Address> {
+    let network = to_bitcoin_network(read_config(|config| config.bitcoin_network()));
+    txn.output
+        .iter()
+        .filter_map(|output| Address::from_script(&output.script_pubkey, network).ok())
+        .filter(|address| account_of_address(&address.to_string()).is_some())
+        .collect()
+}
+
+// the outputs of `txn` paying to `addresses`, as the utxos they become. the
+// height stays 0 until the bitcoin canister reports them.
+fn own_outputs<'a>(txn: &Transaction, addresses: &[&'a Address]) -> Vec<(&'a Address, Utxo)> {
+    let txid = txn.compute_txid().to_byte_array().to_vec();
+    txn.output
+        .iter()
+        .enumerate()
+        .filter_map(|(vout, output)| {
+            let address = addresses
+                .iter()
+                .find(|addr| addr.script_pubkey() == output.script_pubkey)?;
+            let utxo = Utxo {
+                outpoint: Outpoint {
+                    txid: txid.clone(),
+                    vout: vout as u32,
+                },
+                value: output.value.to_sat(),
+                height: 0,
+            };
+            Some((*address, utxo))
+        })
+        .collect()
+}
+
+// records the change of a submitted transaction as spendable right away,
+// the canister trusts its own transactions to confirm. the runes of every
+// output are worked out from the runestone.
+fn record_change(txn: &Transaction, inputs: &[SpentInput], change_addresses: &[&Address]) {
+    let Ok((runes, _)) = replay(txn, inputs) else {
+        return;
+    };
+    write_utxo_manager(|manager| {
+        for (address, utxo) in own_outputs(txn, change_addresses) {
+            let address = address.to_string();
+            let carried = runes
+                .iter()
+                .filter(|((vout, _), _)| *vout == utxo.outpoint.vout)
+                .collect::<Vec<_>>();
+            if carried.is_empty() {
+                manager.record_btc_utxos(&address, vec![utxo]);
+                continue;
+            }
+            for ((_, runeid), balance) in carried {
+                manager.record_runic_utxos(
+                    &address,
+                    runeid.clone(),
+                    vec![RunicUtxo {
+                        utxo: utxo.clone(),
+                        balance: *balance,
+                    }],
+                );
+            }
+        }
+    });
+}
+
+// broadcasts a signed transaction and keeps track of it until it confirms.
+// outputs paying back to `change_addresses` stay with the canister, so they
+// are marked to never be credited as deposits. `external_spent` is what the
+// inputs not held by the canister bring in.
+async fn submit_bitcoin_transaction(
+    txn: &Transaction,
+    change_addresses: &[&Address],
+    inputs: Vec<SpentInput>,
+    external_spent: u64,
+    operation: Operation,
+) -> Result<SubmittedTxidType, String> {
+    let txid = txn.compute_txid().to_string();
+    let txn_bytes = bitcoin::consensus::serialize(txn);
+    ic_cdk::println!("{}", hex::encode(&txn_bytes));
+
+    let network = read_config(|config| config.bitcoin_network());
+    bitcoin_send_transaction(SendTransactionRequest {
+        network,
+        transaction: txn_bytes.clone(),
+    })
+    .await
+    .map_err(|(code, msg)| format!("failed to submit bitcoin txn: {:?} {}", code, msg))?;
+    write_utxo_manager(|manager| manager.mark_spent(&inputs, &txid));
+
+    let is_change = |output: &TxOut| {
+        change_addresses
+            .iter()
+            .any(|addr| addr.script_pubkey() == output.script_pubkey)
+    };
+
+    write_user_manager(|users| {
+        for (vout, output) in txn.output.iter().enumerate() {
+            if is_change(output) {
+                users.mark_as_internal(&txid, vout as u32);
+            }
+        }
+    });
+    // the runes the user's wallet brings in aren't fully known, that change
+    // waits for the bitcoin canister
+    if external_spent == 0 {
+        record_change(txn, &inputs, change_addresses);
+    }
+
+    // the canister's own change is watched first, the receiver might spend
+    // its output before the poll gets to see it
+    let (watch_vout, watch_address) = txn
+        .output
+        .iter()
+        .enumerate()
+        .filter(|(_, output)| !output.script_pubkey.is_op_return())
+        .max_by_key(|(vout, output)| (is_change(output), std::cmp::Reverse(*vout)))
+        .and_then(|(vout, output)| {
+            Address::from_script(&output.script_pubkey, to_bitcoin_network(network))
+                .ok()
+                .map(|addr| (vout as u32, addr.to_string()))
+        })
+        .unwrap_or_default();
+
+    let spent = inputs
+        .iter()
+        .fold(external_spent, |total, input| total + input.utxo.value);
+    let fee = spent
+        - txn
+            .output
+            .iter()
+            .fold(0, |total, output| total + output.value.to_sat());
+    let now = ic_cdk::api::time();
+    write_txn_manager(|txns| {
+        txns.record(
+            txid.clone(),
+            SubmittedTxn {
+                raw: txn_bytes,
+                inputs,
+                fee,
+                fee_per_vbytes: fee * 1000 / txn.vsize() as u64,
+                operation,
+                watch_address,
+                watch_vout,
+                status: TxnStatus::Pending,
+                submitted_at: now,
+                last_broadcast_at: now,
+                broadcasts: 1,
+            },
+        )
+    });
+
+    Ok(SubmittedTxidType::Bitcoin { txid })
 }
 
 impl TransactionType {
-    pub async fn build_and_submit(&self) -> SubmittedTxidType {
+    // every utxo the transaction spends along with the address holding it
+    pub fn spent_inputs(&self) -> Vec<SpentInput> {
+        let btc_inputs = |address: &Address, utxos: &[Utxo]| {
+            utxos
+                .iter()
+                .map(|utxo| SpentInput {
+                    address: address.to_string(),
+                    utxo: utxo.clone(),
+                    rune: None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let runic_inputs = |address: &Address, rune: &RuneId, utxos: &[RunicUtxo]| {
+            utxos
+                .iter()
+                .map(|RunicUtxo { utxo, balance }| SpentInput {
+                    address: address.to_string(),
+                    utxo: utxo.clone(),
+                    rune: Some((rune.clone(), *balance)),
+                })
+                .collect::<Vec<_>>()
+        };
         match self {
             Self::Combined {
                 runeid,
-                rune_amount,
                 rune_sender,
-                rune_receiver,
-                rune_sender_account,
                 runic_utxos,
-                btc_amount,
                 btc_sender,
-                btc_receiver,
-                btc_sender_account,
                 btc_utxos,
-                fee,
-                fee_payer_account,
+                fee_payer,
                 fee_utxos,
+                ..
+            } => [
+                runic_inputs(rune_sender, runeid, runic_utxos),
+                btc_inputs(btc_sender, btc_utxos),
+                btc_inputs(fee_payer, fee_utxos),
+            ]
+            .concat(),
+            Self::Bitcoin { utxos, sender, .. } => btc_inputs(sender, utxos),
+            Self::Rune {
+                rune,
+                runic_utxos,
+                sender,
                 fee_payer,
-                postage,
-            } => {
-                const DUST_THRESHOLD: u64 = 1_000;
-
-                let mut runic_total_spent = 0;
-                let mut btc_in_runic_spent = 0;
-                let mut btc_total_spent = 0;
-                let mut fee_total_spent = 0;
-
-                let mut index_of_utxos_to_be_signed_by_rune_sender = vec![];
-                let mut index_of_utxos_to_be_signed_by_btc_sender = vec![];
-                let mut index_of_utxos_to_be_signed_by_fee_payer = vec![];
-
-                let (mut input, mut output) = (vec![], vec![]);
-
-                runic_utxos.iter().for_each(|RunicUtxo { utxo, balance }| {
-                    runic_total_spent += balance;
-                    btc_in_runic_spent += utxo.value;
-                    let txin = TxIn {
-                        script_sig: ScriptBuf::new(),
-                        witness: Witness::new(),
-                        sequence: Sequence::MAX,
-                        previous_output: OutPoint {
-                            txid: Txid::from_raw_hash(
-                                Hash::from_slice(&utxo.outpoint.txid).unwrap(),
-                            ),
-                            vout: utxo.outpoint.vout,
-                        },
-                    };
-                    let len = input.len();
-                    index_of_utxos_to_be_signed_by_rune_sender.push(len);
-                    input.push(txin);
-                });
-
-                let need_change_rune_output =
-                    runic_total_spent > *rune_amount || runic_utxos.len() > 1;
-                let required_postage_btc = if need_change_rune_output {
-                    postage.to_sat() * 2
-                } else {
-                    postage.to_sat()
-                } - btc_in_runic_spent;
-
-                if need_change_rune_output {
-                    let runestone = Runestone {
-                        edicts: vec![Edict {
-                            id: ordinals::RuneId {
-                                block: runeid.block,
-                                tx: runeid.tx,
-                            },
-                            amount: *rune_amount,
-                            output: 2,
-                        }],
-                        ..Default::default()
-                    };
-                    output.push(TxOut {
-                        script_pubkey: runestone.encipher(),
-                        value: Amount::from_sat(0),
-                    });
-
-                    output.push(TxOut {
-                        script_pubkey: rune_sender.script_pubkey(),
-                        value: *postage,
-                    });
-
-                    output.push(TxOut {
-                        script_pubkey: rune_receiver.script_pubkey(),
-                        value: *postage,
-                    });
-                } else {
-                    output.push(TxOut {
-                        script_pubkey: rune_receiver.script_pubkey(),
-                        value: *postage,
-                    });
-                }
+                fee_utxos,
+                ..
+            } => [
+                runic_inputs(sender, rune, runic_utxos),
+                btc_inputs(fee_payer, fee_utxos),
+            ]
+            .concat(),
+            Self::RunePair {
+                rune0,
+                rune0_utxos,
+                rune0_sender,
+                rune1,
+                rune1_utxos,
+                rune1_sender,
+                fee_utxos,
+                fee_payer,
+                ..
+            } => [
+                runic_inputs(rune0_sender, rune0, rune0_utxos),
+                runic_inputs(rune1_sender, rune1, rune1_utxos),
+                btc_inputs(fee_payer, fee_utxos),
+            ]
+            .concat(),
+            Self::Batch { inputs, .. } => inputs.clone(),
+            Self::Icp { .. } | Self::Icrc1 { .. } => vec![],
+        }
+    }
 
-                // btc
-                btc_utxos.iter().for_each(|utxo| {
-                    btc_total_spent += utxo.value;
-                    let txin = TxIn {
-                        script_sig: ScriptBuf::new(),
-                        witness: Witness::new(),
-                        sequence: Sequence::MAX,
-                        previous_output: OutPoint {
-                            txid: Txid::from_raw_hash(
-                                Hash::from_slice(&utxo.outpoint.txid).unwrap(),
-                            ),
-                            vout: utxo.outpoint.vout,
-                        },
-                    };
-                    let len = input.len();
-                    index_of_utxos_to_be_signed_by_btc_sender.push(len);
-                    input.push(txin);
-                });
-
-                if fee_payer == btc_sender {
-                    let remaining = btc_total_spent - btc_amount - fee - required_postage_btc;
-
-                    output.push(TxOut {
-                        script_pubkey: btc_receiver.script_pubkey(),
-                        value: Amount::from_sat(*btc_amount),
-                    });
+    // returns the utxos selected by the builder to the utxo manager, for
+    // transactions that end up never being submitted.
+    pub fn release_utxos(&self) {
+        release_inputs(self.spent_inputs());
+    }
 
-                    if remaining > DUST_THRESHOLD {
-                        output.push(TxOut {
-                            script_pubkey: btc_sender.script_pubkey(),
-                            value: Amount::from_sat(remaining),
-                        });
-                    }
-                } else {
-                    output.push(TxOut {
-                        script_pubkey: btc_receiver.script_pubkey(),
-                        value: Amount::from_sat(*btc_amount),
-                    });
+    // sats leaving the canister's addresses, fee included
+    pub fn bitcoin_cost(&self) -> u64 {
+        match self {
+            Self::Combined {
+                btc_amount,
+                fee,
+                postage,
+                ..
+            } => btc_amount + fee + postage.to_sat(),
+            Self::Bitcoin {
+                txn, utxos, sender, ..
+            } => {
+                let spent = utxos.iter().fold(0, |total, utxo| total + utxo.value);
+                let change = txn
+                    .output
+                    .iter()
+                    .filter(|output| output.script_pubkey == sender.script_pubkey())
+                    .fold(0, |total, output| total + output.value.to_sat());
+                spent - change
+            }
+            Self::Rune { fee, postage, .. } => fee + postage.to_sat(),
+            Self::RunePair {
+                txn,
+                rune0_utxos,
+                rune0_sender,
+                rune1_utxos,
+                rune1_sender,
+                fee_utxos,
+                fee_payer,
+                ..
+            } => {
+                let spent = rune0_utxos
+                    .iter()
+                    .chain(rune1_utxos.iter())
+                    .map(|RunicUtxo { utxo, balance: _ }| utxo)
+                    .chain(fee_utxos.iter())
+                    .fold(0, |total, utxo| total + utxo.value);
+                let change = txn
+                    .output
+                    .iter()
+                    .filter(|output| {
+                        [rune0_sender, rune1_sender, fee_payer]
+                            .iter()
+                            .any(|addr| addr.script_pubkey() == output.script_pubkey)
+                    })
+                    .fold(0, |total, output| total + output.value.to_sat());
+                spent - change
+            }
+            Self::Batch {
+                txn,
+                inputs,
+                signers,
+                change,
+                ..
+            } => {
+                let spent = inputs
+                    .iter()
+                    .fold(0, |total, input| total + input.utxo.value);
+                let change = txn
+                    .output
+                    .iter()
+                    .filter(|output| {
+                        signers
+                            .iter()
+                            .map(|(addr, _)| addr)
+                            .chain(change.iter())
+                            .any(|addr| addr.script_pubkey() == output.script_pubkey)
+                    })
+                    .fold(0, |total, output| total + output.value.to_sat());
+                spent - change
+            }
+            Self::Icp { .. } | Self::Icrc1 { .. } => 0,
+        }
+    }
 
-                    // fee
-
-                    fee_utxos.iter().for_each(|utxo| {
-                        fee_total_spent += utxo.value;
-                        let txin = TxIn {
-                            sequence: Sequence::MAX,
-                            script_sig: ScriptBuf::new(),
-                            witness: Witness::new(),
-                            previous_output: OutPoint {
-                                txid: Txid::from_raw_hash(
-                                    Hash::from_slice(&utxo.outpoint.txid).unwrap(),
-                                ),
-                                vout: utxo.outpoint.vout,
-                            },
-                        };
-                        let len = input.len();
-                        index_of_utxos_to_be_signed_by_fee_payer.push(len);
-                        input.push(txin);
-                    });
+    // sats left to the miners
+    pub fn mining_fee(&self) -> u64 {
+        let spent = self
+            .spent_inputs()
+            .iter()
+            .fold(0, |total, input| total + input.utxo.value);
+        let sent = self.bitcoin_txn().map_or(0, |txn| {
+            txn.output
+                .iter()
+                .fold(0, |total, output| total + output.value.to_sat())
+        });
+        spent.saturating_sub(sent)
+    }
 
-                    let remaining = fee_total_spent - fee - required_postage_btc;
-                    output.push(TxOut {
-                        script_pubkey: fee_payer.script_pubkey(),
-                        value: Amount::from_sat(remaining),
-                    });
-                }
+    pub fn bitcoin_txn(&self) -> Option<&Transaction> {
+        match self {
+            Self::Combined { txn, .. }
+            | Self::Bitcoin { txn, .. }
+            | Self::Rune { txn, .. }
+            | Self::RunePair { txn, .. }
+            | Self::Batch { txn, .. } => Some(txn),
+            Self::Icp { .. } | Self::Icrc1 { .. } => None,
+        }
+    }
 
-                let mut txn = Transaction {
-                    input,
-                    output,
-                    version: Version(2),
-                    lock_time: LockTime::ZERO,
-                };
+    // replays the runestone against the runes of the spent utxos, refusing
+    // a transaction which would move them anywhere but where the builder
+    // meant to or burn any of them
+    pub fn validate_runes(&self) -> Result<(), String> {
+        let Some(txn) = self.bitcoin_txn() else {
+            return Ok(());
+        };
+        let allocations = match self {
+            Self::Combined { allocations, .. }
+            | Self::Rune { allocations, .. }
+            | Self::RunePair { allocations, .. }
+            | Self::Batch { allocations, .. } => allocations.as_slice(),
+            _ => &[],
+        };
+        validate_runestone(txn, &self.spent_inputs(), allocations)
+            .map_err(|err| format!("RUNESTONE_ERROR: {}", err))
+    }
 
-                // signing
-
-                let (
-                    rune_sender_path,
-                    rune_sender_pubkey,
-                    btc_sender_path,
-                    btc_sender_pubkey,
-                    fee_payer_path,
-                    fee_payer_pubkey,
-                ) = read_config(|config| {
-                    let ecdsa_key = config.ecdsa_public_key();
-                    let rune_sender_path = account_to_derivation_path(rune_sender_account);
-                    let rune_sender_pubkey = derive_public_key(&ecdsa_key, &rune_sender_path);
-                    let btc_sender_path = account_to_derivation_path(btc_sender_account);
-                    let btc_sender_pubkey = derive_public_key(&ecdsa_key, &btc_sender_path);
-                    let fee_payer_path = account_to_derivation_path(fee_payer_account);
-                    let fee_payer_pubkey = derive_public_key(&ecdsa_key, &fee_payer_path);
-                    (
-                        DerivationPath::new(rune_sender_path),
-                        rune_sender_pubkey.public_key,
-                        DerivationPath::new(btc_sender_path),
-                        btc_sender_pubkey.public_key,
-                        DerivationPath::new(fee_payer_path),
-                        fee_payer_pubkey.public_key,
+    pub async fn build_and_submit(
+        &self,
+        operation: Operation,
+    ) -> Result<SubmittedTxidType, String> {
+        if let Some(txn) = self.bitcoin_txn() {
+            check_fee_cap(txn, &self.spent_inputs())?;
+        }
+        self.validate_runes()?;
+        match self {
+            Self::Combined {
+                txn,
+                rune_sender,
+                rune_sender_account,
+                runic_utxos,
+                btc_sender,
+                btc_sender_account,
+                btc_utxos,
+                fee_payer_account,
+                fee_utxos,
+                fee_payer,
+                ..
+            } => {
+                // inputs are laid out by the builder as runic, bitcoin and then fee utxos
+                let owners = runic_utxos
+                    .iter()
+                    .map(|RunicUtxo { utxo, balance: _ }| {
+                        (rune_sender.as_ref(), rune_sender_account, utxo.value)
+                    })
+                    .chain(
+                        btc_utxos
+                            .iter()
+                            .map(|utxo| (btc_sender.as_ref(), btc_sender_account, utxo.value)),
                     )
-                });
-
-                let txn_cache = SighashCache::new(txn.clone());
-
-                for (index, input) in txn.input.iter_mut().enumerate() {
-                    if index_of_utxos_to_be_signed_by_rune_sender.contains(&index) {
-                        let sighash = txn_cache
-                            .legacy_signature_hash(
-                                index,
-                                &rune_sender.script_pubkey(),
-                                EcdsaSighashType::All.to_u32(),
-                            )
-                            .unwrap();
-                        let signature = ecdsa_sign(
-                            sighash.as_byte_array().to_vec(),
-                            rune_sender_path.clone().into_inner(),
-                        )
-                        .await
-                        .signature;
-                        let mut signature = sec1_to_der(signature);
-                        signature.push(EcdsaSighashType::All.to_u32() as u8);
-                        let signature = PushBytesBuf::try_from(signature).unwrap();
-                        let pubkey = PushBytesBuf::try_from(rune_sender_pubkey.clone()).unwrap();
-                        input.script_sig = Builder::new()
-                            .push_slice(signature)
-                            .push_slice(pubkey)
-                            .into_script();
-                        input.witness.clear();
-                    } else if index_of_utxos_to_be_signed_by_btc_sender.contains(&index) {
-                        let sighash = txn_cache
-                            .legacy_signature_hash(
-                                index,
-                                &btc_sender.script_pubkey(),
-                                EcdsaSighashType::All.to_u32(),
-                            )
-                            .unwrap();
-                        let signature = ecdsa_sign(
-                            sighash.as_byte_array().to_vec(),
-                            btc_sender_path.clone().into_inner(),
-                        )
-                        .await
-                        .signature;
-                        let mut signature = sec1_to_der(signature);
-                        signature.push(EcdsaSighashType::All.to_u32() as u8);
-                        let signature = PushBytesBuf::try_from(signature).unwrap();
-                        let pubkey = PushBytesBuf::try_from(btc_sender_pubkey.clone()).unwrap();
-                        input.script_sig = Builder::new()
-                            .push_slice(signature)
-                            .push_slice(pubkey)
-                            .into_script();
-                        input.witness.clear();
-                    } else if index_of_utxos_to_be_signed_by_fee_payer.contains(&index) {
-                        let sighash = txn_cache
-                            .legacy_signature_hash(
-                                index,
-                                &fee_payer.script_pubkey(),
-                                EcdsaSighashType::All.to_u32(),
-                            )
-                            .unwrap();
-                        let signature = ecdsa_sign(
-                            sighash.as_byte_array().to_vec(),
-                            fee_payer_path.clone().into_inner(),
-                        )
-                        .await
-                        .signature;
-                        let mut signature = sec1_to_der(signature);
-                        signature.push(EcdsaSighashType::All.to_u32() as u8);
-                        let signature = PushBytesBuf::try_from(signature).unwrap();
-                        let pubkey = PushBytesBuf::try_from(fee_payer_pubkey.clone()).unwrap();
-                        input.script_sig = Builder::new()
-                            .push_slice(signature)
-                            .push_slice(pubkey)
-                            .into_script();
-                        input.witness.clear();
-                    }
-                }
+                    .chain(
+                        fee_utxos
+                            .iter()
+                            .map(|utxo| (fee_payer.as_ref(), fee_payer_account, utxo.value)),
+                    )
+                    .collect::<Vec<_>>();
 
-                let txid = txn.compute_txid().to_string();
-                let txn_bytes = bitcoin::consensus::serialize(&txn);
-                ic_cdk::println!("{}", hex::encode(&txn_bytes));
+                let mut txn = txn.clone();
+                sign_inputs(&mut txn, &owners).await;
 
-                bitcoin_send_transaction(SendTransactionRequest {
-                    network: read_config(|config| config.bitcoin_network()),
-                    transaction: txn_bytes,
-                })
+                submit_bitcoin_transaction(
+                    &txn,
+                    &[
+                        rune_sender.as_ref(),
+                        btc_sender.as_ref(),
+                        fee_payer.as_ref(),
+                    ],
+                    self.spent_inputs(),
+                    0,
+                    operation,
+                )
                 .await
-                .expect("failed to submit bitcoin txn");
-
-                SubmittedTxidType::Bitcoin { txid }
             }
             Self::Bitcoin {
                 txn,
-                utxos: _,
+                utxos,
                 sender,
                 sender_account,
             } => {
-                let (path, pubkey) = read_config(|config| {
-                    let ecdsa_key = config.ecdsa_public_key();
-                    let path = account_to_derivation_path(sender_account);
-                    let pubkey = derive_public_key(&ecdsa_key, &path).public_key;
-                    (DerivationPath::new(path), pubkey)
-                });
+                let owners = utxos
+                    .iter()
+                    .map(|utxo| (sender, sender_account, utxo.value))
+                    .collect::<Vec<_>>();
+
                 let mut txn = txn.clone();
-                let txn_cache = SighashCache::new(txn.clone());
-                for (index, input) in txn.input.iter_mut().enumerate() {
-                    let sighash = txn_cache
-                        .legacy_signature_hash(
-                            index,
-                            &sender.script_pubkey(),
-                            EcdsaSighashType::All.to_u32(),
-                        )
-                        .unwrap();
-                    let signature =
-                        ecdsa_sign(sighash.as_byte_array().to_vec(), path.clone().into_inner())
-                            .await
-                            .signature;
-                    let mut signature = sec1_to_der(signature);
-                    signature.push(EcdsaSighashType::All.to_u32() as u8);
-                    let signature = PushBytesBuf::try_from(signature).unwrap();
-                    let pubkey = PushBytesBuf::try_from(pubkey.clone()).unwrap();
-                    input.script_sig = Builder::new()
-                        .push_slice(signature)
-                        .push_slice(pubkey)
-                        .into_script();
-                    input.witness.clear();
-                }
-                let txid = txn.compute_txid().to_string();
-                let txn_bytes = bitcoin::consensus::serialize(&txn);
-                ic_cdk::println!("{}", hex::encode(&txn_bytes));
-                bitcoin_send_transaction(SendTransactionRequest {
-                    transaction: txn_bytes,
-                    network: read_config(|config| config.bitcoin_network()),
-                })
-                .await
-                .unwrap();
-                SubmittedTxidType::Bitcoin { txid }
+                sign_inputs(&mut txn, &owners).await;
+
+                submit_bitcoin_transaction(&txn, &[sender], self.spent_inputs(), 0, operation).await
             }
             Self::Rune {
-                rune,
-                rune_amount,
+                txn,
                 runic_utxos,
                 sender,
-                receiver,
                 sender_account,
-                fee,
                 fee_payer,
                 fee_payer_account,
                 fee_utxos,
-                postage,
+                ..
             } => {
-                const DUST_THRESHOLD: u64 = 1_000;
-
-                let mut runic_total_spent = 0;
-                let mut btc_in_runic_spent = 0;
-                let mut btc_total_spent = 0;
-                let mut fee_total_spent = 0;
-
-                let mut index_of_utxos_to_be_signed_by_rune_sender = vec![];
-
-                let (mut input, mut output) = (vec![], vec![]);
-
-                runic_utxos.iter().for_each(|RunicUtxo { utxo, balance }| {
-                    runic_total_spent += balance;
-                    btc_in_runic_spent += utxo.value;
-                    let txin = TxIn {
-                        script_sig: ScriptBuf::new(),
-                        witness: Witness::new(),
-                        sequence: Sequence::MAX,
-                        previous_output: OutPoint {
-                            txid: Txid::from_raw_hash(
-                                Hash::from_slice(&utxo.outpoint.txid).unwrap(),
-                            ),
-                            vout: utxo.outpoint.vout,
-                        },
-                    };
-                    let len = input.len();
-                    index_of_utxos_to_be_signed_by_rune_sender.push(len);
-                    input.push(txin);
-                });
-
-                let need_change_rune_output =
-                    runic_total_spent > *rune_amount || runic_utxos.len() > 1;
-                let required_postage_btc = if need_change_rune_output {
-                    postage.to_sat() * 2
-                } else {
-                    postage.to_sat()
-                } - btc_in_runic_spent;
-
-                if need_change_rune_output {
-                    let runestone = Runestone {
-                        edicts: vec![Edict {
-                            id: ordinals::RuneId {
-                                block: rune.block,
-                                tx: rune.tx,
-                            },
-                            amount: *rune_amount,
-                            output: 2,
-                        }],
-                        ..Default::default()
-                    };
-                    output.push(TxOut {
-                        script_pubkey: runestone.encipher(),
-                        value: Amount::from_sat(0),
-                    });
+                // inputs are laid out by the builder as runic and then fee utxos
+                let owners = runic_utxos
+                    .iter()
+                    .map(|RunicUtxo { utxo, balance: _ }| {
+                        (sender.as_ref(), sender_account, utxo.value)
+                    })
+                    .chain(
+                        fee_utxos
+                            .iter()
+                            .map(|utxo| (fee_payer.as_ref(), fee_payer_account, utxo.value)),
+                    )
+                    .collect::<Vec<_>>();
 
-                    output.push(TxOut {
-                        script_pubkey: sender.script_pubkey(),
-                        value: *postage,
-                    });
+                let mut txn = txn.clone();
+                sign_inputs(&mut txn, &owners).await;
 
-                    output.push(TxOut {
-                        script_pubkey: receiver.script_pubkey(),
-                        value: *postage,
-                    });
-                } else {
-                    output.push(TxOut {
-                        script_pubkey: receiver.script_pubkey(),
-                        value: *postage,
-                    });
-                }
+                submit_bitcoin_transaction(
+                    &txn,
+                    &[sender.as_ref(), fee_payer.as_ref()],
+                    self.spent_inputs(),
+                    0,
+                    operation,
+                )
+                .await
+            }
+            Self::RunePair {
+                txn,
+                rune0: _,
+                rune0_utxos,
+                rune0_sender,
+                rune0_sender_account,
+                rune1: _,
+                rune1_utxos,
+                rune1_sender,
+                rune1_sender_account,
+                fee_utxos,
+                fee_payer,
+                fee_payer_account,
+                ..
+            } => {
+                // inputs are laid out by the builder as rune0's, rune1's and then fee payer's
+                let owners = rune0_utxos
+                    .iter()
+                    .map(|RunicUtxo { utxo, balance: _ }| {
+                        (rune0_sender.as_ref(), rune0_sender_account, utxo.value)
+                    })
+                    .chain(rune1_utxos.iter().map(|RunicUtxo { utxo, balance: _ }| {
+                        (rune1_sender.as_ref(), rune1_sender_account, utxo.value)
+                    }))
+                    .chain(
+                        fee_utxos
+                            .iter()
+                            .map(|utxo| (fee_payer.as_ref(), fee_payer_account, utxo.value)),
+                    )
+                    .collect::<Vec<_>>();
 
-                // fee
-
-                fee_utxos.iter().for_each(|utxo| {
-                    fee_total_spent += utxo.value;
-                    let txin = TxIn {
-                        sequence: Sequence::MAX,
-                        script_sig: ScriptBuf::new(),
-                        witness: Witness::new(),
-                        previous_output: OutPoint {
-                            txid: Txid::from_raw_hash(
-                                Hash::from_slice(&utxo.outpoint.txid).unwrap(),
-                            ),
-                            vout: utxo.outpoint.vout,
-                        },
-                    };
-                    let len = input.len();
-                    input.push(txin);
-                });
-
-                let remaining = fee_total_spent - fee - required_postage_btc;
-                output.push(TxOut {
-                    script_pubkey: fee_payer.script_pubkey(),
-                    value: Amount::from_sat(remaining),
-                });
-
-                let mut txn = Transaction {
-                    input,
-                    output,
-                    version: Version(2),
-                    lock_time: LockTime::ZERO,
-                };
+                let mut txn = txn.clone();
+                sign_inputs(&mut txn, &owners).await;
 
-                // signing
-
-                let (rune_sender_path, rune_sender_pubkey, fee_payer_path, fee_payer_pubkey) =
-                    read_config(|config| {
-                        let ecdsa_key = config.ecdsa_public_key();
-                        let rune_sender_path = account_to_derivation_path(sender_account);
-                        let rune_sender_pubkey = derive_public_key(&ecdsa_key, &rune_sender_path);
-                        let fee_payer_path = account_to_derivation_path(fee_payer_account);
-                        let fee_payer_pubkey = derive_public_key(&ecdsa_key, &fee_payer_path);
-                        (
-                            DerivationPath::new(rune_sender_path),
-                            rune_sender_pubkey.public_key,
-                            DerivationPath::new(fee_payer_path),
-                            fee_payer_pubkey.public_key,
-                        )
-                    });
+                submit_bitcoin_transaction(
+                    &txn,
+                    &[
+                        rune0_sender.as_ref(),
+                        rune1_sender.as_ref(),
+                        fee_payer.as_ref(),
+                    ],
+                    self.spent_inputs(),
+                    0,
+                    operation,
+                )
+                .await
+            }
+            Self::Batch {
+                txn,
+                inputs,
+                signers,
+                change,
+                ..
+            } => {
+                let owners = signers
+                    .iter()
+                    .zip(inputs.iter())
+                    .map(|((address, account), input)| (address, account, input.utxo.value))
+                    .collect::<Vec<_>>();
 
-                let txn_cache = SighashCache::new(txn.clone());
-
-                for (index, input) in txn.input.iter_mut().enumerate() {
-                    if index_of_utxos_to_be_signed_by_rune_sender.contains(&index) {
-                        let sighash = txn_cache
-                            .legacy_signature_hash(
-                                index,
-                                &sender.script_pubkey(),
-                                EcdsaSighashType::All.to_u32(),
-                            )
-                            .unwrap();
-                        let signature = ecdsa_sign(
-                            sighash.as_byte_array().to_vec(),
-                            rune_sender_path.clone().into_inner(),
-                        )
-                        .await
-                        .signature;
-                        let mut signature = sec1_to_der(signature);
-                        signature.push(EcdsaSighashType::All.to_u32() as u8);
-                        let signature = PushBytesBuf::try_from(signature).unwrap();
-                        let pubkey = PushBytesBuf::try_from(rune_sender_pubkey.clone()).unwrap();
-                        input.script_sig = Builder::new()
-                            .push_slice(signature)
-                            .push_slice(pubkey)
-                            .into_script();
-                        input.witness.clear();
-                    } else {
-                        let sighash = txn_cache
-                            .legacy_signature_hash(
-                                index,
-                                &fee_payer.script_pubkey(),
-                                EcdsaSighashType::All.to_u32(),
-                            )
-                            .unwrap();
-                        let signature = ecdsa_sign(
-                            sighash.as_byte_array().to_vec(),
-                            fee_payer_path.clone().into_inner(),
-                        )
+                let mut txn = txn.clone();
+                sign_inputs(&mut txn, &owners).await;
+
+                let change_addresses = signers
+                    .iter()
+                    .map(|(address, _)| address)
+                    .chain(change.iter())
+                    .collect::<Vec<_>>();
+                submit_bitcoin_transaction(
+                    &txn,
+                    &change_addresses,
+                    self.spent_inputs(),
+                    0,
+                    operation,
+                )
+                .await
+            }
+            Self::Icp { ledger, txn } => icp::submit(*ledger, txn.clone())
+                .await
+                .map_err(|err| err.to_string()),
+            Self::Icrc1 { ledger, txn } => {
+                let (result,): (Result<Nat, TransferError>,) =
+                    ic_cdk::call(*ledger, "icrc1_transfer", (txn.clone(),))
                         .await
-                        .signature;
-                        let mut signature = sec1_to_der(signature);
-                        signature.push(EcdsaSighashType::All.to_u32() as u8);
-                        let signature = PushBytesBuf::try_from(signature).unwrap();
-                        let pubkey = PushBytesBuf::try_from(fee_payer_pubkey.clone()).unwrap();
-                        input.script_sig = Builder::new()
-                            .push_slice(signature)
-                            .push_slice(pubkey)
-                            .into_script();
-                        input.witness.clear();
-                    }
-                }
+                        .map_err(|(code, msg)| format!("{:?}: {}", code, msg))?;
+                let txid = result.map_err(|err| format!("ICRC1_TRANSFER_ERROR: {:?}", err))?;
+                Ok(SubmittedTxidType::Icrc1 {
+                    txid: txid.0.try_into().unwrap(),
+                })
+            }
+        }
+    }
+}
 
-                let txid = txn.compute_txid().to_string();
-                let txn_bytes = bitcoin::consensus::serialize(&txn);
-                ic_cdk::println!("{}", hex::encode(&txn_bytes));
+// rebroadcasts a pending transaction at `fee_per_vbytes`, spending the very
+// same inputs. the extra fee is taken out of the largest output paying back
+// to the canister and charged to the user behind the operation, or to the
+// pool holding that output.
+// keeps a transaction marked as being bumped while alive, turning away
+// concurrent bumps of it. the ic drops it even when a callback traps.
+struct BumpGuard(String);
 
-                bitcoin_send_transaction(SendTransactionRequest {
-                    network: read_config(|config| config.bitcoin_network()),
-                    transaction: txn_bytes,
-                })
-                .await
-                .expect("failed to submit bitcoin txn");
+impl BumpGuard {
+    fn new(txid: &str) -> Result<Self, String> {
+        if !write_txn_manager(|txns| txns.bumping.insert(txid.to_string())) {
+            return Err(String::from("transaction is being bumped already"));
+        }
+        Ok(Self(txid.to_string()))
+    }
+}
 
-                SubmittedTxidType::Bitcoin { txid }
-            }
-            Self::Icp { txn } => {
-                let txid = ic_ledger_types::transfer(
-                    ic_ledger_types::MAINNET_LEDGER_CANISTER_ID,
-                    txn.clone(),
+impl Drop for BumpGuard {
+    fn drop(&mut self) {
+        write_txn_manager(|txns| txns.bumping.remove(&self.0));
+    }
+}
+
+pub async fn bump_fee(txid: &str, fee_per_vbytes: u64) -> Result<SubmittedTxidType, String> {
+    let _guard = BumpGuard::new(txid)?;
+    let submitted = read_txn_manager(|txns| txns.get(txid))
+        .ok_or_else(|| String::from("unknown transaction"))?;
+    if submitted.status != TxnStatus::Pending {
+        return Err(String::from("transaction isn't pending"));
+    }
+    let original: Transaction = bitcoin::consensus::deserialize(&submitted.raw)
+        .map_err(|err| format!("failed to decode transaction: {}", err))?;
+    let mut txn = original.clone();
+    if matches!(submitted.operation, Operation::Swap { .. }) {
+        return Err(String::from(
+            "swaps spend inputs signed by the user, they can't be re-signed",
+        ));
+    }
+    if !txn.is_explicitly_rbf() {
+        return Err(String::from("transaction doesn't signal replaceability"));
+    }
+
+    // a replacement pays at least 1 sat/vbyte on top of the fee it replaces
+    let vsize = txn.vsize() as u64;
+    let fee = (vsize * fee_per_vbytes / 1000).max(submitted.fee + vsize);
+    let extra = fee - submitted.fee;
+
+    let owners = submitted
+        .inputs
+        .iter()
+        .map(|input| {
+            let address = address_validation(&input.address)?;
+            let account = account_of_address(&input.address)
+                .ok_or_else(|| format!("{} isn't a canister address", input.address))?;
+            Ok((address, account, input.utxo.value))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let change_addresses = owners
+        .iter()
+        .map(|(address, _, _)| address)
+        .collect::<Vec<_>>();
+
+    let change = txn
+        .output
+        .iter_mut()
+        .filter(|output| {
+            change_addresses
+                .iter()
+                .any(|addr| addr.script_pubkey() == output.script_pubkey)
+        })
+        .max_by_key(|output| output.value)
+        .filter(|output| output.value.to_sat() >= extra + DUST_THRESHOLD)
+        .ok_or_else(|| String::from("no change output can cover the bump"))?;
+    change.value -= Amount::from_sat(extra);
+    let change_address = change_addresses
+        .iter()
+        .find(|addr| addr.script_pubkey() == change.script_pubkey)
+        .map(|addr| addr.to_string())
+        .unwrap_or_default();
+    check_fee_cap(&txn, &submitted.inputs)?;
+
+    // the change of the original goes away with it. a transaction spending
+    // that change already would be evicted along, those aren't bumped.
+    let canister_outputs = canister_outputs(&original);
+    let canister_outputs = canister_outputs.iter().collect::<Vec<_>>();
+    let recorded = own_outputs(&original, &canister_outputs);
+    if read_utxo_manager(|manager| recorded.iter().any(|(_, utxo)| !manager.is_available(utxo))) {
+        return Err(String::from(
+            "the transaction's change is spent by a later transaction",
+        ));
+    }
+
+    let payer = match submitted.operation {
+        Operation::Withdrawal { user } => Some(user),
+        // the pool whose change covers the bump pays for it
+        Operation::Consolidation { .. } | Operation::Swap { .. } | Operation::Payouts { .. } => {
+            None
+        }
+    };
+    match payer {
+        Some(user) => {
+            write_user_manager(|users| users.debit(&user, TokenType::Bitcoin, extra as u128))?
+        }
+        None => charge_pool_fee(&change_address, extra)?,
+    }
+
+    write_utxo_manager(|manager| {
+        for (address, utxo) in recorded.iter() {
+            manager.remove_utxo(&address.to_string(), utxo);
+        }
+    });
+
+    for input in txn.input.iter_mut() {
+        input.script_sig = ScriptBuf::new();
+        input.witness.clear();
+    }
+    let signers = owners
+        .iter()
+        .map(|(address, account, value)| (address, account, *value))
+        .collect::<Vec<_>>();
+    sign_inputs(&mut txn, &signers).await;
+
+    let result = submit_bitcoin_transaction(
+        &txn,
+        &change_addresses,
+        submitted.inputs.clone(),
+        0,
+        submitted.operation,
+    )
+    .await;
+    match result {
+        Ok(SubmittedTxidType::Bitcoin {
+            txid: ref replacement,
+        }) => {
+            write_txn_manager(|txns| {
+                txns.set_status(
+                    txid,
+                    TxnStatus::Replaced {
+                        txid: replacement.clone(),
+                    },
                 )
-                .await
-                .unwrap()
-                .unwrap();
-                SubmittedTxidType::Ic { txid }
+            });
+        }
+        Ok(_) => {}
+        Err(_) => {
+            match payer {
+                Some(user) => write_user_manager(|users| {
+                    users.credit(&user, TokenType::Bitcoin, extra as u128)
+                }),
+                None => refund_pool_fee(&change_address, extra),
             }
-            _ => unimplemented!(),
+            record_change(&original, &submitted.inputs, &canister_outputs);
+        }
+    }
+    result
+}
+
+// fees of transactions moving a pool's own utxos around are taken out of its
+// bitcoin reserve. pools not trading bitcoin have them paid out of the
+// commission receiver's balance.
+pub fn charge_pool_fee(address: &str, fee: u64) -> Result<(), String> {
+    let pool_id = pool_of_address(address)?;
+    let charged = write_pool_manager(|pools| {
+        let mut pool = pools.pool_mapping.get(&pool_id)?;
+        let paid = pool.pay_fee(fee);
+        if paid.is_ok() {
+            pools.pool_mapping.insert(pool_id, pool);
+        }
+        Some(paid)
+    });
+    match charged {
+        Some(Ok(())) => Ok(()),
+        Some(Err(err)) if !pool_trades_bitcoin(pool_id) => {
+            let receiver = read_config(|config| config.commission_receiver_principal());
+            write_user_manager(|users| users.debit(&receiver, TokenType::Bitcoin, fee as u128))
+                .map_err(|_| err)
         }
+        Some(Err(err)) => Err(err),
+        None => Err(String::from("unknown pool")),
+    }
+}
+
+// hands back a fee charged by `charge_pool_fee`
+pub fn refund_pool_fee(address: &str, fee: u64) {
+    let Ok(pool_id) = pool_of_address(address) else {
+        return;
+    };
+    if pool_trades_bitcoin(pool_id) {
+        write_pool_manager(|pools| {
+            if let Some(mut pool) = pools.pool_mapping.get(&pool_id) {
+                pool.refund_fee(fee);
+                pools.pool_mapping.insert(pool_id, pool);
+            }
+        });
+    } else {
+        let receiver = read_config(|config| config.commission_receiver_principal());
+        write_user_manager(|users| users.credit(&receiver, TokenType::Bitcoin, fee as u128));
     }
 }
+
+// pools pay for consolidating their own addresses, the commission receiver
+// pays for the users' deposit addresses
+pub fn charge_consolidation_fee(address: &str, fee: u64) -> Result<(), String> {
+    if pool_of_address(address).is_ok() {
+        return charge_pool_fee(address, fee);
+    }
+    let receiver = read_config(|config| config.commission_receiver_principal());
+    write_user_manager(|users| users.debit(&receiver, TokenType::Bitcoin, fee as u128))
+}
+
+// hands back a fee charged by `charge_consolidation_fee`
+pub fn refund_consolidation_fee(address: &str, fee: u64) {
+    if pool_of_address(address).is_ok() {
+        return refund_pool_fee(address, fee);
+    }
+    let receiver = read_config(|config| config.commission_receiver_principal());
+    write_user_manager(|users| users.credit(&receiver, TokenType::Bitcoin, fee as u128));
+}
+
+fn pool_of_address(address: &str) -> Result<u128, String> {
+    account_of_address(address)
+        .and_then(|account| account.subaccount)
+        .and_then(|subaccount| read_pool_manager(|pools| pools.pool_id_by_subaccount(&subaccount)))
+        .ok_or_else(|| format!("{} isn't a pool address", address))
+}
+
+fn pool_trades_bitcoin(pool_id: u128) -> bool {
+    read_pool_manager(|pools| pools.pool_mapping.get(&pool_id))
+        .is_some_and(|pool| pool.token0 == TokenType::Bitcoin || pool.token1 == TokenType::Bitcoin)
+}
+
+// gives up on a pending transaction the network dropped. its recorded change
+// goes away, its inputs go back to the utxo manager and whatever it charged
+// is handed back.
+pub fn abandon_transaction(txid: &str, submitted: &SubmittedTxn) -> Result<(), String> {
+    let txn: Transaction = bitcoin::consensus::deserialize(&submitted.raw)
+        .map_err(|err| format!("failed to decode transaction: {}", err))?;
+    let canister_outputs = canister_outputs(&txn);
+    let canister_outputs = canister_outputs.iter().collect::<Vec<_>>();
+    let recorded = own_outputs(&txn, &canister_outputs);
+    if read_utxo_manager(|manager| recorded.iter().any(|(_, utxo)| !manager.is_available(utxo))) {
+        return Err(String::from(
+            "the transaction's change is spent by a later transaction",
+        ));
+    }
+    // only a withdrawal hands runes back, they're all on canister inputs
+    let runes = match submitted.operation {
+        Operation::Withdrawal { .. } => replay(&txn, &submitted.inputs)?.0,
+        _ => Default::default(),
+    };
+
+    write_utxo_manager(|manager| {
+        for (address, utxo) in recorded.iter() {
+            manager.remove_utxo(&address.to_string(), utxo);
+        }
+        manager.unspend(submitted.inputs.clone());
+    });
+    match submitted.operation {
+        // the user was charged everything leaving the canister's addresses
+        Operation::Withdrawal { user } => {
+            let is_external =
+                |vout: u32| !recorded.iter().any(|(_, utxo)| utxo.outpoint.vout == vout);
+            let kept = recorded
+                .iter()
+                .fold(0, |total, (_, utxo)| total + utxo.value);
+            let spent = submitted
+                .inputs
+                .iter()
+                .fold(0, |total, input| total + input.utxo.value);
+            write_user_manager(|users| {
+                users.credit(
+                    &user,
+                    TokenType::Bitcoin,
+                    spent.saturating_sub(kept) as u128,
+                );
+                for ((vout, runeid), balance) in runes.iter() {
+                    if is_external(*vout) {
+                        users.credit(&user, TokenType::Runestone(runeid.clone()), *balance);
+                    }
+                }
+            });
+        }
+        // the payouts go out with the next batch, rune payouts get their
+        // postage and share of the fee back
+        Operation::Payouts { ref ids } => write_txn_manager(|txns| {
+            for id in ids.iter() {
+                let Some(payout) = txns.payout(*id) else {
+                    continue;
+                };
+                if matches!(payout.token, TokenType::Runestone(_)) {
+                    let charge = (DEFAULT_POSTAGE + payout.fee) as u128;
+                    write_user_manager(|users| {
+                        users.credit(&payout.user, TokenType::Bitcoin, charge)
+                    });
+                }
+                txns.set_payout_status(*id, PayoutStatus::Queued, 0);
+            }
+        }),
+        Operation::Consolidation { ref address } => refund_pool_fee(address, submitted.fee),
+        // the reserves never moved, they wait for the confirmation
+        Operation::Swap { .. } => {
+            write_txn_manager(|txns| txns.remove_psbt_swap(txid));
+        }
+    }
+    write_txn_manager(|txns| txns.set_status(txid, TxnStatus::Dropped));
+    Ok(())
+}
+
+// signs the canister's inputs of a swap whose wallet inputs `txn` already
+// carries the signatures of, and submits it. the wallet's signatures are
+// checked first.
+pub async fn submit_psbt_swap(
+    mut txn: Transaction,
+    swap: &PsbtSwap,
+) -> Result<SubmittedTxidType, String> {
+    let wallet = address_validation(&swap.wallet_address)?;
+    let canister_inputs = swap
+        .inputs
+        .iter()
+        .map(|input| {
+            let address = address_validation(&input.address)?;
+            let account = account_of_address(&input.address)
+                .ok_or_else(|| format!("{} isn't a canister address", input.address))?;
+            Ok((input, address, account))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let mut prevouts = vec![];
+    let mut signers = vec![];
+    for input in txn.input.iter() {
+        let outpoint = (
+            input.previous_output.txid.to_byte_array().to_vec(),
+            input.previous_output.vout,
+        );
+        let is_spent = |utxo: &Utxo| (utxo.outpoint.txid.clone(), utxo.outpoint.vout) == outpoint;
+        if let Some((spent, address, account)) = canister_inputs
+            .iter()
+            .find(|(spent, _, _)| is_spent(&spent.utxo))
+        {
+            prevouts.push(TxOut {
+                value: Amount::from_sat(spent.utxo.value),
+                script_pubkey: address.script_pubkey(),
+            });
+            signers.push(Some((address, account)));
+        } else if let Some(utxo) = swap.wallet_utxos.iter().find(|utxo| is_spent(utxo)) {
+            prevouts.push(TxOut {
+                value: Amount::from_sat(utxo.value),
+                script_pubkey: wallet.script_pubkey(),
+            });
+            signers.push(None);
+        } else {
+            return Err(String::from("transaction spends an unknown input"));
+        }
+    }
+    verify_wallet_inputs(&txn, &prevouts, &signers)?;
+    sign_canister_inputs(&mut txn, &prevouts, &signers).await;
+
+    let wallet_spent = swap
+        .wallet_utxos
+        .iter()
+        .fold(0, |total, utxo| total + utxo.value);
+    let change_addresses = canister_inputs
+        .iter()
+        .map(|(_, address, _)| address)
+        .collect::<Vec<_>>();
+    submit_bitcoin_transaction(
+        &txn,
+        &change_addresses,
+        swap.inputs.clone(),
+        wallet_spent,
+        Operation::Swap {
+            user: swap.user,
+            pool_id: swap.pool_id,
+        },
+    )
+    .await
+}
+
+// hands utxos of a transaction that won't confirm back to the utxo manager
+pub fn release_inputs(inputs: Vec<SpentInput>) {
+    write_utxo_manager(|manager| manager.release(inputs))
+}
@@ -2,8 +2,6 @@ use candid::{CandidType, Decode, Deserialize, Encode};
 use ic_cdk::api::management_canister::bitcoin::Utxo;
 use ic_stable_structures::{storable::Bound, Storable};
 
-use crate::{chains::Addresses, txn_handler::TransactionType};
-
 #[derive(CandidType, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct RuneId {
     pub block: u64,
@@ -11,7 +9,7 @@ pub struct RuneId {
 }
 
 impl Storable for RuneId {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         std::borrow::Cow::Owned(Encode!(self).expect("should encode"))
     }
 
@@ -45,7 +43,7 @@ impl Storable for RunicUtxo {
         Decode!(bytes.as_ref(), Self).expect("should decode")
     }
 
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         std::borrow::Cow::Owned(Encode!(self).expect("should encode"))
     }
 
@@ -61,7 +59,7 @@ pub enum TokenType {
 }
 
 impl Storable for TokenType {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         std::borrow::Cow::Owned(Encode!(self).expect("should encode"))
     }
 
@@ -72,7 +70,37 @@ impl Storable for TokenType {
     const BOUND: Bound = Bound::Unbounded;
 }
 
-#[derive(CandidType)]
+// output script of an address the canister holds keys for
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ScriptType {
+    P2pkh,
+    P2wpkh,
+    P2tr,
+}
+
+impl Storable for ScriptType {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(Encode!(self).expect("should encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("should decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// how urgently a caller wants its transaction mined, none follows the
+// canister's fee policy
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FeePreference {
+    Economy,
+    Normal,
+    Priority,
+    Explicit { sats_per_vbyte: u64 },
+}
+
+#[derive(CandidType, Debug)]
 pub enum SubmittedTxidType {
     Bitcoin { txid: String },
     Ic { txid: u64 },
@@ -1,93 +1,979 @@
+use std::collections::{HashMap, HashSet};
+
 use bitcoin::hashes::Hash;
-use ic_cdk::api::management_canister::bitcoin::{bitcoin_get_utxos, GetUtxosRequest, UtxoFilter};
+use candid::Principal;
+use ic_cdk::api::management_canister::bitcoin::{
+    bitcoin_get_utxos, bitcoin_send_transaction, BitcoinNetwork, GetUtxosRequest,
+    SendTransactionRequest, Utxo, UtxoFilter,
+};
+use icrc_ledger_types::icrc1::account::Account;
 
 use crate::{
-    ord_canister,
-    state::{read_config, read_utxo_manager, write_utxo_manager},
-    types::{RuneId, RunicUtxo},
+    chains::{
+        btc::{
+            address_validation,
+            coin_selection::Strategy,
+            get_fee_per_vbyte,
+            runestone::DEFAULT_POSTAGE,
+            transaction::{
+                batch::{self, BatchError, BatchPayout, PayoutGroup},
+                consolidate::{consolidate_bitcoin, consolidate_runes, ConsolidationArgs},
+            },
+        },
+        ic::icp,
+        principal_to_subaccount, Addresses,
+    },
+    ord_canister::{self, RuneBalance},
+    state::{
+        config::FeePurpose,
+        read_config, read_pool_manager, read_txn_manager, read_user_manager, read_utxo_manager,
+        txn_manager::{Operation, Payout, PayoutStatus, SubmittedTxn, TxnStatus},
+        utxo_manager::SyncState,
+        write_pool_manager, write_txn_manager, write_user_manager, write_utxo_manager,
+    },
+    txn_handler::{
+        abandon_transaction, bump_fee, charge_consolidation_fee, refund_consolidation_fee,
+        release_inputs,
+    },
+    types::{RuneId, RunicUtxo, SubmittedTxidType, TokenType},
 };
 
-fn txid_to_string(txid: &[u8]) -> String {
+pub fn txid_to_string(txid: &[u8]) -> String {
     bitcoin::Txid::from_raw_hash(Hash::from_slice(txid).unwrap()).to_string()
 }
 
-pub enum TargetType {
-    Bitcoin { target: u64 },
-    Runic { runeid: RuneId, target: u128 },
+// the ic's bitcoin canister doesn't see the mempool, every utxo it returns
+// has at least one confirmation
+fn confirmations(tip_height: u32, utxo: &Utxo) -> u32 {
+    tip_height.saturating_sub(utxo.height) + 1
 }
 
-pub async fn fetch_utxos_and_update_balances(addr: &str, target: TargetType) {
-    let network = read_config(|config| config.bitcoin_network());
+// the height up to which the ord canister has indexed, none if it can't be
+// reached
+async fn indexed_height() -> Option<u32> {
+    match ord_canister::get_height().await {
+        Ok((Ok((height, _)),)) => Some(height),
+        Ok((Err(err),)) => {
+            ic_cdk::println!("err while getting the indexed height: {:?}", err);
+            None
+        }
+        Err((code, msg)) => {
+            ic_cdk::println!("failed getting the indexed height: {:?}: {}", code, msg);
+            None
+        }
+    }
+}
+
+// the runes of `utxos` in the order given, a failed call to the ord canister
+// is an error like any the canister answers with
+async fn get_runes(utxos: &[Utxo]) -> Result<Vec<Vec<RuneBalance>>, String> {
+    ord_canister::get_runes_by_utxos(utxos)
+        .await
+        .map_err(|(code, msg)| format!("{:?}: {}", code, msg))?
+        .map_err(|err| format!("{:?}", err))
+}
+
+// the runes of `utxos` in the order given, asking the ord canister only
+// about the outpoints not cached yet. an outpoint's runes never change once
+// indexed, so `utxos` must be at or below the indexed height.
+async fn runes_of(utxos: &[Utxo]) -> Result<Vec<Vec<RuneBalance>>, String> {
+    let cached = read_utxo_manager(|manager| {
+        utxos
+            .iter()
+            .map(|utxo| manager.cached_runes(utxo))
+            .collect::<Vec<_>>()
+    });
+    let missing = utxos
+        .iter()
+        .zip(cached.iter())
+        .filter(|(_, cached)| cached.is_none())
+        .map(|(utxo, _)| utxo.clone())
+        .collect::<Vec<_>>();
+    let fetched = if missing.is_empty() {
+        vec![]
+    } else {
+        get_runes(&missing).await?
+    };
+    write_utxo_manager(|manager| {
+        for (utxo, runes) in missing.iter().zip(fetched.iter()) {
+            manager.cache_runes(utxo, runes.clone());
+        }
+    });
+    let mut fetched = fetched.into_iter();
+    Ok(cached
+        .into_iter()
+        .map(|cached| cached.unwrap_or_else(|| fetched.next().unwrap_or_default()))
+        .collect())
+}
+
+// syncs the utxos of `addr` with the bitcoin canister. every page is
+// fetched to find the utxos gone since the last sync, but only the ones
+// mined past the last processed height are classified with the ord canister.
+// nothing is done while the tip stays the same.
+pub async fn fetch_utxos_and_update_balances(addr: &str) {
+    let (network, btc_min_confirmations, rune_min_confirmations) = read_config(|config| {
+        (
+            config.bitcoin_network(),
+            config.btc_min_confirmations(),
+            config.rune_min_confirmations(),
+        )
+    });
+    let previous = read_utxo_manager(|manager| manager.sync_state(addr));
+    // utxos below the lower of both depths are left out by the bitcoin canister,
+    // the rest is checked against its own depth once classified.
     let mut arg = GetUtxosRequest {
         address: addr.to_string(),
         network,
-        filter: None,
+        filter: Some(UtxoFilter::MinConfirmations(
+            btc_min_confirmations.min(rune_min_confirmations),
+        )),
     };
+    let (mut tip, mut reported, mut fresh) = (None, HashSet::new(), vec![]);
     loop {
+        // the watermark stays where it was, the next round tries again
+        let utxo_response = match bitcoin_get_utxos(arg.clone()).await {
+            Ok((utxo_response,)) => utxo_response,
+            Err((code, msg)) => {
+                ic_cdk::println!(
+                    "err while getting the utxos of {}: {:?} {}",
+                    addr,
+                    code,
+                    msg
+                );
+                return;
+            }
+        };
+        let (tip_height, tip_hash) = tip
+            .get_or_insert_with(|| {
+                (
+                    utxo_response.tip_height,
+                    utxo_response.tip_block_hash.clone(),
+                )
+            })
+            .clone();
+        if previous
+            .as_ref()
+            .is_some_and(|previous| previous.tip_hash == tip_hash)
+        {
+            return;
+        }
+        // a tip no higher than before with another hash means a reorg,
+        // everything is looked at again
+        let processed_height = previous
+            .as_ref()
+            .filter(|previous| previous.tip_height < tip_height)
+            .map_or(0, |previous| previous.processed_height);
+        for utxo in utxo_response.utxos {
+            reported.insert(utxo.outpoint.clone());
+            if utxo.height > processed_height {
+                fresh.push(utxo);
+            }
+        }
+        match utxo_response.next_page {
+            Some(page) => arg.filter = Some(UtxoFilter::Page(page)),
+            None => break,
+        }
+    }
+    let Some((tip_height, tip_hash)) = tip else {
+        return;
+    };
+    let dropped = write_utxo_manager(|manager| manager.retain_reported(addr, &reported));
+    if dropped > 0 {
+        ic_cdk::println!("dropped {} spent utxos of {}", dropped, addr);
+    }
+
+    // reserved and spent utxos are still reported until the spend confirms,
+    // the change of the canister's own transactions is recorded already
+    let mut unclassified = vec![];
+    for utxo in fresh {
+        let (available, recorded) = read_utxo_manager(|manager| {
+            (
+                manager.is_available(&utxo),
+                manager.is_recorded(addr, &utxo),
+            )
+        });
+        if !available {
+            continue;
+        }
+        if recorded {
+            write_utxo_manager(|manager| manager.update_height(addr, &utxo));
+            continue;
+        }
+        unclassified.push(utxo);
+    }
+    // the ord canister answers no runes for blocks it hasn't indexed, those
+    // utxos are left for a later sync rather than taken for bitcoin
+    let indexed_height = indexed_height().await;
+    let (mut ready, mut deferred): (Vec<_>, Vec<_>) = unclassified
+        .into_iter()
+        .partition(|utxo| indexed_height.is_some_and(|height| utxo.height <= height));
+    let runes = match runes_of(&ready).await {
+        Ok(runes) => runes,
+        Err(err) => {
+            ic_cdk::println!("err while checking for runes: {}, deferring utxos", err);
+            deferred.append(&mut ready);
+            vec![]
+        }
+    };
+    if !deferred.is_empty() {
+        ic_cdk::println!(
+            "deferred {} utxos of {} the indexer hasn't reached",
+            deferred.len(),
+            addr
+        );
+    }
+
+    let mut btc_utxos = vec![];
+    for (utxo, runes) in ready.into_iter().zip(runes) {
+        let confirmations = confirmations(tip_height, &utxo);
+        if runes.is_empty() {
+            if confirmations >= btc_min_confirmations {
+                btc_utxos.push(utxo);
+            }
+            continue;
+        }
+        if confirmations < rune_min_confirmations {
+            continue;
+        }
+        for rune in runes {
+            write_utxo_manager(|manager| {
+                manager.remove_btc_utxo(addr, &utxo);
+                manager.record_runic_utxos(
+                    addr,
+                    rune.id,
+                    vec![RunicUtxo {
+                        utxo: utxo.clone(),
+                        balance: rune.balance,
+                    }],
+                )
+            });
+        }
+    }
+    // recording of bitcoin utxo
+    write_utxo_manager(|manager| {
+        manager.record_btc_utxos(addr, btc_utxos);
+        // whatever is this deep has the confirmations of either kind, the
+        // deferred utxos stay above the watermark. forgetting the tip has the
+        // next sync run even if no block comes in meanwhile.
+        let processed_height = deferred.iter().fold(
+            (tip_height + 1).saturating_sub(btc_min_confirmations.max(rune_min_confirmations)),
+            |processed, utxo| processed.min(utxo.height.saturating_sub(1)),
+        );
+        manager.set_sync_state(
+            addr,
+            SyncState {
+                tip_height,
+                tip_hash: if deferred.is_empty() {
+                    tip_hash
+                } else {
+                    vec![]
+                },
+                processed_height,
+            },
+        );
+    });
+}
+
+// the bitcoin canister's tip, read off the utxos of the canister's own
+// address
+pub async fn bitcoin_tip_height() -> Result<u32, String> {
+    let arg = GetUtxosRequest {
+        address: Addresses::from(&ic_cdk::id()).bitcoin,
+        network: read_config(|config| config.bitcoin_network()),
+        filter: None,
+    };
+    bitcoin_get_utxos(arg)
+        .await
+        .map(|(utxo_response,)| utxo_response.tip_height)
+        .map_err(|(code, msg)| format!("{:?}: {}", code, msg))
+}
+
+// a user's wallet is only looked at up to this many utxos
+const MAX_WALLET_UTXOS: usize = 50;
+
+// the confirmed utxos of an address the canister doesn't hold: the ones
+// without runes and, if `runeid` is given, the ones carrying only that rune.
+// utxos the ord canister can't tell about are left out.
+pub async fn fetch_wallet_utxos(
+    addr: &str,
+    runeid: Option<&RuneId>,
+) -> Result<(Vec<Utxo>, Vec<RunicUtxo>), String> {
+    let (network, btc_min_confirmations, rune_min_confirmations) = read_config(|config| {
+        (
+            config.bitcoin_network(),
+            config.btc_min_confirmations(),
+            config.rune_min_confirmations(),
+        )
+    });
+    let mut arg = GetUtxosRequest {
+        address: addr.to_string(),
+        network,
+        filter: Some(UtxoFilter::MinConfirmations(
+            btc_min_confirmations.min(rune_min_confirmations),
+        )),
+    };
+    // utxos of blocks the indexer hasn't reached could carry runes it can't
+    // tell about yet
+    let indexed_height = indexed_height()
+        .await
+        .ok_or_else(|| String::from("the rune indexer can't be reached"))?;
+    let mut wallet = vec![];
+    'pages: loop {
         let utxo_response = bitcoin_get_utxos(arg.clone())
             .await
-            .expect("failed getting the utxo response")
+            .map_err(|(code, msg)| format!("{:?}: {}", code, msg))?
             .0;
-        let mut btc_utxos = vec![];
         for utxo in utxo_response.utxos {
-            if read_utxo_manager(|manager| manager.is_recorded_as_runic(addr, &utxo)) {
+            if wallet.len() == MAX_WALLET_UTXOS {
+                break 'pages;
+            }
+            if utxo.height > indexed_height {
                 continue;
             }
-            let txid = txid_to_string(&utxo.outpoint.txid);
-            match ord_canister::get_runes_by_utxo(txid, utxo.outpoint.vout)
-                .await
-                .unwrap()
-                .0
+            wallet.push((confirmations(utxo_response.tip_height, &utxo), utxo));
+        }
+        match utxo_response.next_page {
+            Some(page) => arg.filter = Some(UtxoFilter::Page(page)),
+            None => break,
+        }
+    }
+    // wallet utxos aren't cached, nothing evicts them once spent
+    let utxos = wallet
+        .iter()
+        .map(|(_, utxo)| utxo.clone())
+        .collect::<Vec<_>>();
+    let runes = get_runes(&utxos).await?;
+    let (mut btc_utxos, mut runic_utxos) = (vec![], vec![]);
+    for ((confirmations, utxo), runes) in wallet.into_iter().zip(runes) {
+        match runes.as_slice() {
+            [] if confirmations >= btc_min_confirmations => btc_utxos.push(utxo),
+            [rune] if Some(&rune.id) == runeid && confirmations >= rune_min_confirmations => {
+                runic_utxos.push(RunicUtxo {
+                    balance: rune.balance,
+                    utxo,
+                })
+            }
+            _ => {}
+        }
+    }
+    Ok((btc_utxos, runic_utxos))
+}
+
+// hands the canister's utxos of swaps never signed back to the utxo manager
+pub fn release_expired_psbt_swaps() {
+    let now = ic_cdk::api::time();
+    for swap in write_txn_manager(|txns| txns.take_expired_psbt_swaps(now)) {
+        release_inputs(swap.inputs);
+    }
+}
+
+// hands back the utxos of builds which never made it to a submit, like the
+// ones interrupted by a trap after an await
+pub fn release_expired_reservations() {
+    let now = ic_cdk::api::time();
+    let released = write_utxo_manager(|manager| manager.release_expired(now));
+    if released > 0 {
+        ic_cdk::println!("released {} expired utxo reservations", released);
+    }
+}
+
+// sums up the deposits to `addr` which the bitcoin canister reports but which
+// are still below their confirmation depth.
+pub async fn fetch_pending_deposits(addr: &str) -> HashMap<TokenType, u128> {
+    let (network, btc_min_confirmations, rune_min_confirmations) = read_config(|config| {
+        (
+            config.bitcoin_network(),
+            config.btc_min_confirmations(),
+            config.rune_min_confirmations(),
+        )
+    });
+    let mut arg = GetUtxosRequest {
+        address: addr.to_string(),
+        network,
+        filter: None,
+    };
+    let mut shallow = vec![];
+    let tip_height = loop {
+        let utxo_response = match bitcoin_get_utxos(arg.clone()).await {
+            Ok((utxo_response,)) => utxo_response,
+            Err((code, msg)) => {
+                ic_cdk::println!(
+                    "err while getting the utxos of {}: {:?} {}",
+                    addr,
+                    code,
+                    msg
+                );
+                return HashMap::new();
+            }
+        };
+        let tip_height = utxo_response.tip_height;
+        for utxo in utxo_response.utxos {
+            if confirmations(tip_height, &utxo) < btc_min_confirmations.max(rune_min_confirmations)
             {
-                Err(_) => {
-                    ic_cdk::println!("err while checking for runes, recording as non runic utxo");
-                    btc_utxos.push(utxo);
+                shallow.push(utxo);
+            }
+        }
+        match utxo_response.next_page {
+            Some(page) => arg.filter = Some(UtxoFilter::Page(page)),
+            None => break tip_height,
+        }
+    };
+    // deposits the indexer hasn't reached yet show up once it has
+    let indexed_height = indexed_height().await.unwrap_or_default();
+    shallow.retain(|utxo| utxo.height <= indexed_height);
+    // nothing is told apart while the indexer can't be asked, rather than
+    // taking every utxo for bitcoin
+    let runes = runes_of(&shallow).await.unwrap_or_default();
+    let mut pending = HashMap::new();
+    for (utxo, runes) in shallow.into_iter().zip(runes) {
+        let confirmations = confirmations(tip_height, &utxo);
+        if runes.is_empty() {
+            if confirmations < btc_min_confirmations {
+                *pending.entry(TokenType::Bitcoin).or_insert(0) += utxo.value as u128;
+            }
+        } else if confirmations < rune_min_confirmations {
+            for rune in runes {
+                *pending.entry(TokenType::Runestone(rune.id)).or_insert(0) += rune.balance;
+            }
+        }
+    }
+    pending
+}
+
+// credits every new deposit made to `user`'s addresses to its internal balance.
+// bitcoin and runes stay on the deposit address until withdrawn, icp is swept
+// into the canister's default account.
+pub async fn credit_deposits(user: &Principal) {
+    let addresses = Addresses::from(user);
+    let subaccount = principal_to_subaccount(user);
+
+    for addr in addresses.bitcoin_addresses() {
+        fetch_utxos_and_update_balances(addr).await;
+
+        let deposits = read_utxo_manager(|manager| manager.deposits(addr));
+        write_user_manager(|users| {
+            users.register_address(addr, subaccount);
+            for (utxo, amounts) in deposits {
+                if users.credit_deposit(user, &utxo, amounts) {
+                    ic_cdk::println!("credited deposit {:?} to {}", utxo.outpoint, user);
+                }
+            }
+        });
+    }
+
+    match icp::sweep(subaccount, addresses.account_identifier).await {
+        Ok(0) => {}
+        Ok(amount) => {
+            write_user_manager(|users| users.credit(user, TokenType::Icp, amount as u128))
+        }
+        Err(err) => ic_cdk::println!("failed to sweep icp deposit: {}", err),
+    }
+}
+
+// refreshes the deposits of `user` still waiting for confirmations
+pub async fn update_pending_deposits(user: &Principal) {
+    let addresses = Addresses::from(user);
+    let mut pending = HashMap::new();
+    for addr in addresses.bitcoin_addresses() {
+        for (token, amount) in fetch_pending_deposits(addr).await {
+            *pending.entry(token).or_insert(0) += amount;
+        }
+    }
+    write_user_manager(|users| users.set_pending(user, pending));
+}
+
+// how long a submitted transaction may go unconfirmed before it's broadcast again
+const REBROADCAST_AFTER_NANOS: u64 = 30 * 60 * 1_000_000_000;
+
+// looks for `txid:vout` among the utxos of `addr`, returns the height it
+// was mined at if it's unspent, along with the current tip.
+async fn find_utxo(
+    network: BitcoinNetwork,
+    addr: &str,
+    txid: &str,
+    vout: u32,
+) -> Result<(Option<u32>, u32), String> {
+    let mut arg = GetUtxosRequest {
+        address: addr.to_string(),
+        network,
+        filter: None,
+    };
+    loop {
+        let utxo_response = bitcoin_get_utxos(arg.clone())
+            .await
+            .map_err(|(code, msg)| format!("{:?}: {}", code, msg))?
+            .0;
+        let found = utxo_response
+            .utxos
+            .iter()
+            .find(|utxo| utxo.outpoint.vout == vout && txid_to_string(&utxo.outpoint.txid) == txid);
+        if let Some(utxo) = found {
+            return Ok((Some(utxo.height), utxo_response.tip_height));
+        }
+        match utxo_response.next_page {
+            Some(page) => arg.filter = Some(UtxoFilter::Page(page)),
+            None => return Ok((None, utxo_response.tip_height)),
+        }
+    }
+}
+
+// a transaction still unconfirmed this long after it was submitted is
+// checked for having been dropped by the network
+const DROP_AFTER_NANOS: u64 = 3 * 24 * 60 * 60 * 1_000_000_000;
+
+// whether none of the inputs of a submitted transaction were spent on chain.
+// change of a transaction of the canister which isn't mined either counts
+// as unspent.
+async fn inputs_unspent(network: BitcoinNetwork, txn: &SubmittedTxn) -> Result<bool, String> {
+    for input in txn.inputs.iter() {
+        let input_txid = txid_to_string(&input.utxo.outpoint.txid);
+        let (unspent, _) = find_utxo(
+            network,
+            &input.address,
+            &input_txid,
+            input.utxo.outpoint.vout,
+        )
+        .await?;
+        if unspent.is_some() {
+            continue;
+        }
+        let parent_unmined = read_txn_manager(|txns| txns.get(&input_txid))
+            .is_some_and(|parent| !matches!(parent.status, TxnStatus::Confirmed { .. }));
+        if !parent_unmined {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// whether the bitcoin canister stopped reporting one of the wallet utxos of
+// the psbt swap submitted as `txid`
+async fn wallet_spent_elsewhere(network: BitcoinNetwork, txid: &str) -> Result<bool, String> {
+    let Some(swap) = read_txn_manager(|txns| txns.psbt_swap(txid)) else {
+        return Ok(false);
+    };
+    for utxo in swap.wallet_utxos.iter() {
+        let (unspent, _) = find_utxo(
+            network,
+            &swap.wallet_address,
+            &txid_to_string(&utxo.outpoint.txid),
+            utxo.outpoint.vout,
+        )
+        .await?;
+        if unspent.is_none() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// the pool's reserves take in a psbt swap once its transaction confirmed
+fn settle_psbt_swap(txid: &str) {
+    let Some(swap) = write_txn_manager(|txns| txns.remove_psbt_swap(txid)) else {
+        return;
+    };
+    write_pool_manager(|pools| {
+        let Some(mut pool) = pools.pool_mapping.get(&swap.pool_id) else {
+            return;
+        };
+        let (amount0in, amount0out, amount1in, amount1out) =
+            pool.sort_tokens(&swap.token_in, swap.amount_in, swap.amount_out);
+        pool.settle_swap(amount0in, amount1in, amount0out, amount1out);
+        pools.pool_mapping.insert(swap.pool_id, pool);
+    });
+}
+
+// confirmation status of a submitted transaction, judged by its watched
+// output or, once that's spent, by its first input being gone.
+async fn check_confirmation(
+    network: BitcoinNetwork,
+    txid: &str,
+    txn: &SubmittedTxn,
+) -> Result<TxnStatus, String> {
+    if !txn.watch_address.is_empty() {
+        let (height, _) = find_utxo(network, &txn.watch_address, txid, txn.watch_vout).await?;
+        if let Some(height) = height {
+            return Ok(TxnStatus::Confirmed { height });
+        }
+    }
+    let Some(input) = txn.inputs.first() else {
+        return Ok(TxnStatus::Pending);
+    };
+    let input_txid = txid_to_string(&input.utxo.outpoint.txid);
+    let (unspent, tip_height) = find_utxo(
+        network,
+        &input.address,
+        &input_txid,
+        input.utxo.outpoint.vout,
+    )
+    .await?;
+    match unspent {
+        Some(_) => Ok(TxnStatus::Pending),
+        // the output was spent already, the tip is the closest height known
+        None => Ok(TxnStatus::Confirmed { height: tip_height }),
+    }
+}
+
+// polls every pending transaction, rebroadcasting the ones that stay
+// unconfirmed for too long.
+pub async fn poll_submitted_transactions() {
+    let network = read_config(|config| config.bitcoin_network());
+    let pending = read_txn_manager(|txns| txns.pending());
+    for (txid, txn) in pending {
+        let status = match check_confirmation(network, &txid, &txn).await {
+            Ok(status) => status,
+            Err(err) => {
+                ic_cdk::println!("failed to check {}: {}", txid, err);
+                continue;
+            }
+        };
+        if status != TxnStatus::Pending {
+            if matches!(status, TxnStatus::Confirmed { .. }) {
+                write_utxo_manager(|manager| manager.forget_spent(&txn.inputs));
+                if matches!(txn.operation, Operation::Swap { .. }) {
+                    settle_psbt_swap(&txid);
+                }
+            }
+            write_txn_manager(|txns| txns.set_status(&txid, status));
+            continue;
+        }
+
+        // a swap whose wallet inputs got spent elsewhere won't ever confirm,
+        // everything else is given until the deadline
+        let now = ic_cdk::api::time();
+        let expired = now - txn.submitted_at >= DROP_AFTER_NANOS;
+        let give_up = match txn.operation {
+            Operation::Swap { .. } if !expired => wallet_spent_elsewhere(network, &txid).await,
+            _ => Ok(expired),
+        };
+        let dropped = match give_up {
+            Ok(true) => inputs_unspent(network, &txn).await,
+            other => other,
+        };
+        match dropped {
+            Ok(true) => match abandon_transaction(&txid, &txn) {
+                Ok(()) => {
+                    ic_cdk::println!("dropped {}", txid);
                     continue;
                 }
-                Ok(runes) => {
-                    if runes.is_empty() {
-                        btc_utxos.push(utxo);
-                        continue;
-                    }
-                    for rune in runes {
-                        write_utxo_manager(|manager| {
-                            manager.remove_btc_utxo(addr, &utxo);
-                            manager.record_runic_utxos(
-                                addr,
-                                rune.id,
-                                vec![RunicUtxo {
-                                    utxo: utxo.clone(),
-                                    balance: rune.balance,
-                                }],
-                            )
-                        });
+                Err(err) => ic_cdk::println!("failed to drop {}: {}", txid, err),
+            },
+            Ok(false) => {}
+            Err(err) => ic_cdk::println!("failed to check the inputs of {}: {}", txid, err),
+        }
+        if now - txn.last_broadcast_at < REBROADCAST_AFTER_NANOS {
+            continue;
+        }
+        match bitcoin_send_transaction(SendTransactionRequest {
+            network,
+            transaction: txn.raw,
+        })
+        .await
+        {
+            Ok(()) => write_txn_manager(|txns| txns.mark_broadcasted(&txid, now)),
+            Err((code, msg)) => {
+                ic_cdk::println!("failed to rebroadcast {}: {:?} {}", txid, code, msg)
+            }
+        }
+    }
+}
+
+// a pending transaction is only bumped once it has been waiting for a while
+// and the market rate has moved well past what it pays
+const BUMP_AFTER_NANOS: u64 = 60 * 60 * 1_000_000_000;
+const BUMP_THRESHOLD_PERCENT: u64 = 125;
+
+pub async fn bump_stuck_transactions() {
+    let now = ic_cdk::api::time();
+    let stuck = read_txn_manager(|txns| txns.pending())
+        .into_iter()
+        .filter(|(_, txn)| now - txn.submitted_at >= BUMP_AFTER_NANOS)
+        // consolidations were sent out at a low rate on purpose, swaps carry
+        // the user's signatures
+        .filter(|(_, txn)| {
+            !matches!(
+                txn.operation,
+                Operation::Consolidation { .. } | Operation::Swap { .. }
+            )
+        })
+        .collect::<Vec<_>>();
+    if stuck.is_empty() {
+        return;
+    }
+    let fee_per_vbytes = get_fee_per_vbyte(FeePurpose::FeeBump, None).await;
+    for (txid, txn) in stuck {
+        if fee_per_vbytes * 100 < txn.fee_per_vbytes * BUMP_THRESHOLD_PERCENT {
+            continue;
+        }
+        match bump_fee(&txid, fee_per_vbytes).await {
+            Ok(replacement) => ic_cdk::println!("bumped {} to {:?}", txid, replacement),
+            Err(err) => ic_cdk::println!("failed to bump {}: {}", txid, err),
+        }
+    }
+}
+
+// merging utxos only pays off while fees are low, millisats per vbyte
+pub const CONSOLIDATION_MAX_FEE_RATE: u64 = 3_000;
+
+// the most a single round of consolidation takes from one pool's reserves,
+// in sats
+pub const CONSOLIDATION_MAX_POOL_FEE: u64 = 20_000;
+
+// the most a single round spends merging user deposit addresses, in sats
+pub const CONSOLIDATION_MAX_DEPOSIT_FEE: u64 = 50_000;
+
+// merges the utxos piling up on every address the canister holds, pools'
+// and users' deposit addresses alike, each rune separately and then bitcoin,
+// as long as the consolidation fee rate is at most `max_fee_per_vbytes`.
+// each pool pays for its own consolidations, deposit addresses are paid by
+// the commission receiver. consolidations past the round's fee cap wait for
+// the next round.
+pub async fn consolidate_held_utxos(
+    max_fee_per_vbytes: u64,
+) -> Result<Vec<SubmittedTxidType>, String> {
+    let fee_per_vbytes = get_fee_per_vbyte(FeePurpose::Consolidation, None).await;
+    if fee_per_vbytes > max_fee_per_vbytes {
+        return Err(format!(
+            "fee rate of {} is above {}",
+            fee_per_vbytes, max_fee_per_vbytes
+        ));
+    }
+    let pool_subaccounts = read_pool_manager(|pools| {
+        pools
+            .pool_mapping
+            .iter()
+            .map(|(_, pool)| pool.allocated_raw_subaccount)
+            .collect::<HashSet<_>>()
+    });
+    let held = read_user_manager(|users| users.address_book.iter().collect::<Vec<_>>());
+
+    // fees charged this round, per pool or none for the deposit addresses
+    let mut charged: HashMap<Option<[u8; 32]>, u64> = HashMap::new();
+    let mut txids = vec![];
+    for (addr, subaccount) in held {
+        let Ok(address) = address_validation(&addr) else {
+            continue;
+        };
+        let (payer, cap) = if pool_subaccounts.contains(&subaccount) {
+            (Some(subaccount), CONSOLIDATION_MAX_POOL_FEE)
+        } else {
+            (None, CONSOLIDATION_MAX_DEPOSIT_FEE)
+        };
+        let args = || ConsolidationArgs {
+            addr: address.clone(),
+            account: Account {
+                owner: ic_cdk::id(),
+                subaccount: Some(subaccount),
+            },
+            fee_per_vbytes,
+        };
+        let runes = read_utxo_manager(|manager| manager.all_rune_with_balances(&addr));
+        let mut txns = runes
+            .into_keys()
+            .filter_map(|runeid| consolidate_runes(args(), runeid))
+            .collect::<Vec<_>>();
+        txns.extend(consolidate_bitcoin(args()));
+
+        for txn in txns {
+            let fee = txn.mining_fee();
+            let spent = charged.entry(payer).or_default();
+            if *spent + fee > cap {
+                txn.release_utxos();
+                continue;
+            }
+            if let Err(err) = charge_consolidation_fee(&addr, fee) {
+                ic_cdk::println!("failed to consolidate {}: {}", addr, err);
+                txn.release_utxos();
+                continue;
+            }
+            *spent += fee;
+            let operation = Operation::Consolidation {
+                address: addr.clone(),
+            };
+            match txn.build_and_submit(operation).await {
+                Ok(txid) => txids.push(txid),
+                Err(err) => {
+                    ic_cdk::println!("failed to consolidate {}: {}", addr, err);
+                    txn.release_utxos();
+                    refund_consolidation_fee(&addr, fee);
+                    if let Some(spent) = charged.get_mut(&payer) {
+                        *spent -= fee;
                     }
                 }
             }
         }
-        // recording of bitcoin utxo
-        write_utxo_manager(|manager| manager.record_btc_utxos(addr, btc_utxos));
+    }
+    Ok(txids)
+}
 
-        match target {
-            TargetType::Runic { ref runeid, target } => {
-                let balance =
-                    read_utxo_manager(|manager| manager.get_runestone_balance(addr, runeid));
-                if balance < target && utxo_response.next_page.is_some() {
-                    arg.filter = Some(UtxoFilter::Page(utxo_response.next_page.unwrap()));
+// gives the amount of a payout which won't be sent back to its user
+fn cancel_payout(id: u64, payout: &Payout, reason: &str) {
+    write_user_manager(|users| users.credit(&payout.user, payout.token.clone(), payout.amount));
+    write_txn_manager(|txns| {
+        txns.set_payout_status(
+            id,
+            PayoutStatus::Cancelled {
+                reason: String::from(reason),
+            },
+            0,
+        )
+    });
+}
+
+// payouts grouped by the pool they come from, in the order they were queued
+fn group_payouts(queued: &[(u64, Payout)]) -> Vec<PayoutGroup> {
+    let mut groups: Vec<(u128, PayoutGroup)> = vec![];
+    for (id, payout) in queued {
+        // addresses were validated when the payout got queued
+        let Ok(to) = address_validation(&payout.to) else {
+            continue;
+        };
+        let batch_payout = BatchPayout {
+            id: *id,
+            to,
+            rune: match &payout.token {
+                TokenType::Runestone(runeid) => Some(runeid.clone()),
+                _ => None,
+            },
+            amount: payout.amount,
+        };
+        match groups
+            .iter_mut()
+            .find(|(pool_id, _)| *pool_id == payout.pool_id)
+        {
+            Some((_, group)) => group.payouts.push(batch_payout),
+            None => {
+                let change = read_pool_manager(|pools| pools.pool_mapping.get(&payout.pool_id))
+                    .and_then(|pool| {
+                        address_validation(&pool.deposit_addresses().bitcoin_segwit).ok()
+                    });
+                let Some(change) = change else {
                     continue;
-                } else {
-                    break;
+                };
+                let payouts = vec![batch_payout];
+                groups.push((payout.pool_id, PayoutGroup { change, payouts }));
+            }
+        }
+    }
+    groups.into_iter().map(|(_, group)| group).collect()
+}
+
+// sends every queued swap payout out in a single transaction. payouts of a
+// pool that can't be funded wait for the next flush, the ones which can't
+// pay for their share of the fee are cancelled.
+pub async fn flush_payouts() -> Result<Option<SubmittedTxidType>, String> {
+    let queued = read_txn_manager(|txns| txns.queued_payouts());
+    if queued.is_empty() {
+        return Ok(None);
+    }
+    // the batch goes out at the highest rate one of its payouts asks for,
+    // none asking for the policy's swap rate
+    let mut preferences = vec![];
+    for (_, payout) in queued {
+        if !preferences.contains(&payout.fee_preference) {
+            preferences.push(payout.fee_preference);
+        }
+    }
+    let mut fee_per_vbytes = 0;
+    for preference in preferences {
+        fee_per_vbytes = fee_per_vbytes.max(get_fee_per_vbyte(FeePurpose::Swap, preference).await);
+    }
+    let strategy = Strategy::for_fee_rate(fee_per_vbytes);
+
+    // nothing awaits from here until the payouts are marked as batched
+    let mut queued = read_txn_manager(|txns| txns.queued_payouts());
+    let (txn, share, charged) = loop {
+        let groups = group_payouts(&queued);
+        if groups.is_empty() {
+            return Ok(None);
+        }
+        let (txn, share) = match batch::build(&groups, fee_per_vbytes, strategy) {
+            Ok(built) => built,
+            Err(BatchError::TooSmall(id)) => {
+                if let Some((_, payout)) = queued.iter().find(|(queued_id, _)| *queued_id == id) {
+                    cancel_payout(id, payout, "too small to pay for its share of the fee");
                 }
+                queued.retain(|(queued_id, _)| *queued_id != id);
+                continue;
             }
-            TargetType::Bitcoin { target } => {
-                let balance = read_utxo_manager(|manager| manager.get_bitcoin_balance(addr));
-                if balance < target && utxo_response.next_page.is_some() {
-                    arg.filter = Some(UtxoFilter::Page(utxo_response.next_page.unwrap()));
-                    continue;
-                } else {
+            Err(BatchError::Unfunded(index)) => {
+                let skipped = groups[index]
+                    .payouts
+                    .iter()
+                    .map(|payout| payout.id)
+                    .collect::<Vec<_>>();
+                ic_cdk::println!("not enough funds for payouts {:?}", skipped);
+                queued.retain(|(id, _)| !skipped.contains(id));
+                continue;
+            }
+        };
+        // payouts left out of every group stay queued
+        let batched = groups
+            .iter()
+            .flat_map(|group| group.payouts.iter().map(|payout| payout.id))
+            .collect::<Vec<_>>();
+        queued.retain(|(id, _)| batched.contains(id));
+
+        // rune payouts pay their postage and share of the fee out of the
+        // user's bitcoin balance
+        let charge = (DEFAULT_POSTAGE + share) as u128;
+        let mut charged = vec![];
+        let mut unpaid = None;
+        for (id, payout) in queued.iter() {
+            if !matches!(payout.token, TokenType::Runestone(_)) {
+                continue;
+            }
+            match write_user_manager(|users| users.debit(&payout.user, TokenType::Bitcoin, charge))
+            {
+                Ok(()) => charged.push((payout.user, charge)),
+                Err(_) => {
+                    unpaid = Some(*id);
                     break;
                 }
             }
         }
+        let Some(id) = unpaid else {
+            break (txn, share, charged);
+        };
+        txn.release_utxos();
+        refund(&charged);
+        if let Some((_, payout)) = queued.iter().find(|(queued_id, _)| *queued_id == id) {
+            cancel_payout(id, payout, "not enough bitcoin for postage and fee");
+        }
+        queued.retain(|(queued_id, _)| *queued_id != id);
+    };
+
+    let ids = queued.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+    write_txn_manager(|txns| {
+        for id in ids.iter() {
+            txns.set_payout_status(*id, PayoutStatus::Batched, share);
+        }
+    });
+    let result = txn
+        .build_and_submit(Operation::Payouts { ids: ids.clone() })
+        .await;
+    write_txn_manager(|txns| {
+        let (status, fee) = match result {
+            Ok(SubmittedTxidType::Bitcoin { ref txid }) => {
+                (PayoutStatus::Submitted { txid: txid.clone() }, share)
+            }
+            _ => (PayoutStatus::Queued, 0),
+        };
+        for id in ids.iter() {
+            txns.set_payout_status(*id, status.clone(), fee);
+        }
+    });
+    if result.is_err() {
+        txn.release_utxos();
+        refund(&charged);
     }
+    result.map(Some)
+}
+
+fn refund(charged: &[(Principal, u128)]) {
+    write_user_manager(|users| {
+        for (user, amount) in charged {
+            users.credit(user, TokenType::Bitcoin, *amount);
+        }
+    });
 }